@@ -0,0 +1,36 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// A region from a BED file, with 0-based half-open coordinates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub chrom: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+/// Reads the first three columns of a BED file, skipping header and comment lines
+pub fn read_regions(path: &str) -> Result<Vec<Region>, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut regions = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() < 3 {
+            return Err(format!("Line {} of {} has fewer than 3 columns", index + 1, path).into());
+        }
+        regions.push(Region {
+            chrom: fields[0].to_string(),
+            start: fields[1].parse()?,
+            end: fields[2].parse()?,
+        });
+    }
+    Ok(regions)
+}
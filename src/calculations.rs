@@ -1,20 +1,43 @@
+use crate::metrics;
 use std::collections::HashMap;
 
 pub fn get_n(lengths: &[u128], nb_bases_total: u128, percentile: f64) -> u128 {
-    // Handle empty array case
+    get_nl(lengths, nb_bases_total, percentile).0
+}
+
+/// Returns the Nx length and the Lx count (number of reads needed to reach that fraction of the yield)
+/// lengths have to be sorted in descending order
+pub fn get_nl(lengths: &[u128], nb_bases_total: u128, fraction: f64) -> (u128, usize) {
     if lengths.is_empty() {
-        return 0; // Return 0 for N50/N75 when no reads match the criteria
+        return (0, 0);
     }
 
     let mut acc = 0;
-    for val in lengths.iter() {
+    for (index, val) in lengths.iter().enumerate() {
         acc += *val;
-        if acc as f64 > nb_bases_total as f64 * percentile {
-            return *val;
+        if acc as f64 > nb_bases_total as f64 * fraction {
+            return (*val, index + 1);
         }
     }
 
-    lengths[lengths.len() - 1]
+    (lengths[lengths.len() - 1], lengths.len())
+}
+
+/// Area under the Nx curve: the expected length of the read a random base belongs to
+pub fn get_aun(lengths: &[u128], nb_bases_total: u128) -> f64 {
+    if nb_bases_total == 0 {
+        return 0.0;
+    }
+    lengths.iter().map(|l| (l * l) as f64).sum::<f64>() / nb_bases_total as f64
+}
+
+/// Nearest-rank percentile of lengths sorted in descending order
+pub fn length_percentile(lengths: &[u128], fraction: f64) -> u128 {
+    if lengths.is_empty() {
+        return 0;
+    }
+    let rank = (fraction * lengths.len() as f64).ceil() as usize;
+    lengths[lengths.len() - rank.clamp(1, lengths.len())]
 }
 
 pub fn median<T: Into<f64> + Copy>(array: &[T]) -> f64 {
@@ -62,6 +85,90 @@ pub fn median_splice(array: &[usize]) -> usize {
     }
 }
 
+/// Nearest-rank percentile of an array sorted in ascending order
+/// the fraction is expected between 0 and 1
+pub fn percentile<T: Into<f64> + Copy>(sorted: &[T], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (fraction * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].into()
+}
+
+/// Summarizes a distribution of lengths sorted in ascending order
+pub fn length_distribution(sorted: &[u32]) -> Option<metrics::LengthDistribution> {
+    if sorted.is_empty() {
+        return None;
+    }
+    Some(metrics::LengthDistribution {
+        count: sorted.len(),
+        median: median(sorted),
+        mean: sorted.iter().map(|x| *x as u64).sum::<u64>() as f64 / sorted.len() as f64,
+        p5: percentile(sorted, 0.05),
+        p25: percentile(sorted, 0.25),
+        p75: percentile(sorted, 0.75),
+        p95: percentile(sorted, 0.95),
+        max: *sorted.last().unwrap() as u64,
+    })
+}
+
+/// Nx, Lx, auN and percentiles of read lengths sorted in descending order
+pub fn read_length_stats(
+    lengths: &[u128],
+    data_yield: u128,
+    nx: &[u8],
+) -> (
+    Vec<metrics::NxStat>,
+    Vec<metrics::LxStat>,
+    f64,
+    metrics::LengthPercentiles,
+) {
+    let (nx_stats, lx_stats) = nx
+        .iter()
+        .map(|x| {
+            let (length, num_reads) = get_nl(lengths, data_yield, *x as f64 / 100.0);
+            (
+                metrics::NxStat { x: *x, length },
+                metrics::LxStat { x: *x, num_reads },
+            )
+        })
+        .unzip();
+    let percentiles = metrics::LengthPercentiles {
+        p5: length_percentile(lengths, 0.05),
+        p10: length_percentile(lengths, 0.10),
+        p25: length_percentile(lengths, 0.25),
+        p50: length_percentile(lengths, 0.50),
+        p75: length_percentile(lengths, 0.75),
+        p90: length_percentile(lengths, 0.90),
+        p95: length_percentile(lengths, 0.95),
+    };
+    (
+        nx_stats,
+        lx_stats,
+        get_aun(lengths, data_yield),
+        percentiles,
+    )
+}
+
+/// Total, N50, median and mean of lengths sorted in descending order
+pub fn length_summary(lengths: &[u128]) -> metrics::LengthSummary {
+    let total = lengths.iter().sum::<u128>();
+    metrics::LengthSummary {
+        total_gb: total as f64 / 1e9,
+        n50: get_n(lengths, total, 0.50),
+        median: if lengths.is_empty() {
+            0.0
+        } else {
+            median_length(lengths)
+        },
+        mean: if lengths.is_empty() {
+            0.0
+        } else {
+            total as f64 / lengths.len() as f64
+        },
+    }
+}
+
 pub fn modal_accuracy(array: &[f64]) -> f64 {
     // this doesn't work for f64s, so first I multiply by 10 and then divide by 10 at the end to get the original value again
     // it gets converted to an int, so some resolution is lost, but the floating point differences don't really matter anyway
@@ -109,6 +216,61 @@ mod tests {
         assert_eq!(median_phaseblocks(v3), 0.0);
     }
 
+    #[test]
+    fn test_nx_lx_and_aun() {
+        let lengths = vec![100u128, 50, 30, 20];
+        assert_eq!(get_nl(&lengths, 200, 0.5), (50, 2));
+        assert_eq!(get_nl(&lengths, 200, 0.1), (100, 1));
+        assert_eq!(get_nl(&lengths, 200, 0.9), (20, 4));
+        assert_eq!(get_n(&lengths, 200, 0.5), 50);
+        assert_eq!(get_nl(&[], 0, 0.5), (0, 0));
+        assert_eq!(
+            get_aun(&lengths, 200),
+            (10000.0 + 2500.0 + 900.0 + 400.0) / 200.0
+        );
+        assert_eq!(get_aun(&[], 0), 0.0);
+    }
+
+    #[test]
+    fn test_length_percentile_descending() {
+        let lengths: Vec<u128> = (1..=100).rev().collect();
+        assert_eq!(length_percentile(&lengths, 0.05), 5);
+        assert_eq!(length_percentile(&lengths, 0.5), 50);
+        assert_eq!(length_percentile(&lengths, 0.95), 95);
+        assert_eq!(length_percentile(&[], 0.5), 0);
+    }
+
+    #[test]
+    fn test_length_summary() {
+        let summary = length_summary(&[400, 300, 200, 100]);
+        assert_eq!(summary.n50, 300);
+        assert_eq!(summary.median, 250.0);
+        assert_eq!(summary.mean, 250.0);
+        assert_eq!(summary.total_gb, 1e-6);
+        assert_eq!(length_summary(&[]).n50, 0);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let array: Vec<u32> = (1..=100).collect();
+        assert_eq!(percentile(&array, 0.05), 5.0);
+        assert_eq!(percentile(&array, 0.5), 50.0);
+        assert_eq!(percentile(&array, 0.95), 95.0);
+        assert_eq!(percentile(&array, 1.0), 100.0);
+        assert_eq!(percentile(&array, 0.0), 1.0);
+        assert_eq!(percentile::<u32>(&[], 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_length_distribution() {
+        let dist = length_distribution(&[100, 200, 300, 400]).unwrap();
+        assert_eq!(dist.count, 4);
+        assert_eq!(dist.median, 250.0);
+        assert_eq!(dist.mean, 250.0);
+        assert_eq!(dist.max, 400);
+        assert!(length_distribution(&[]).is_none());
+    }
+
     #[test]
     fn test_modal_accuracy() {
         let array = [1.1, 2.2, 2.2, 3.3, 4.4];
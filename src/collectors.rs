@@ -0,0 +1,486 @@
+use crate::QcOptions;
+use crate::extract_from_bam::{
+    Data, QScoreHistogramData, get_exon_number, get_haplotype, get_phaseset, get_splice_blocks,
+    hardclipped_bases,
+};
+use crate::phased::{self, ChromosomePhaseCounts, PhaseBlock, PhaseBlockBuilder};
+use crate::sketch::{IdentitySketch, LengthSketch, SpliceSketch};
+use rayon::prelude::*;
+use rust_htslib::bam;
+use rust_htslib::bam::ext::BamRecordExtensions;
+use serde::{Serialize, de::DeserializeOwned};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Values of a read that are used by several collectors
+pub struct ReadInfo {
+    /// aligned length, without soft clipped bases
+    pub length: u128,
+    /// gap-compressed identity, or estimated from the base qualities for unaligned reads
+    pub identity: f64,
+}
+
+/// Collects another metric from the reads that pass the filters, registered with QcOptions::collector
+pub trait MetricCollector: Any {
+    /// Observes a (primary or supplementary) read that passed the filters
+    fn observe(&mut self, record: &bam::Record, read: &ReadInfo);
+
+    /// Adds the reads observed by another collector of the same type, e.g. of another shard of
+    /// the input, which can be turned into its type with collectors::downcast
+    fn merge(&mut self, other: Box<dyn MetricCollector>) -> Result<(), String>;
+
+    /// The name and value of the section that is added as-is to the report, if any
+    fn finalize(&self) -> Option<(String, serde_json::Value)>;
+}
+
+/// A collector as its own type, e.g. the other collector in MetricCollector::merge
+pub fn downcast<C: MetricCollector>(collector: Box<dyn MetricCollector>) -> Result<Box<C>, String> {
+    let collector: Box<dyn Any> = collector;
+    collector
+        .downcast()
+        .map_err(|_| format!("Not a collector of type {}", std::any::type_name::<C>()))
+}
+
+/// Collects a built-in metric, and fills the fields of the extracted data with it
+pub(crate) trait BuiltinCollector {
+    /// Observes a (primary or supplementary) read that passed the filters
+    fn observe(&mut self, record: &bam::Record, read: &ReadInfo);
+
+    /// Adds the collected values to the fields of the metrics and histograms
+    fn finalize(self: Box<Self>, data: &mut Data);
+}
+
+/// Creates a collector for every input, see QcOptions::collector
+/// The partial state of the collectors is saved as json, to write it to a state file and
+/// merge it with that of other inputs
+#[derive(Clone)]
+pub struct CollectorFactory {
+    new: Arc<dyn Fn() -> Box<dyn MetricCollector> + Send + Sync>,
+    save: fn(&dyn MetricCollector) -> Result<serde_json::Value, String>,
+    load: fn(serde_json::Value) -> Result<Box<dyn MetricCollector>, String>,
+}
+
+impl fmt::Debug for CollectorFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CollectorFactory")
+    }
+}
+
+impl CollectorFactory {
+    pub(crate) fn new<C, F>(new: F) -> Self
+    where
+        C: MetricCollector + Serialize + DeserializeOwned,
+        F: Fn() -> C + Send + Sync + 'static,
+    {
+        CollectorFactory {
+            new: Arc::new(move || Box::new(new())),
+            save: save_collector::<C>,
+            load: load_collector::<C>,
+        }
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn MetricCollector> {
+        (self.new)()
+    }
+
+    /// The partial state of a collector created by this factory
+    pub(crate) fn save(
+        &self,
+        collector: &dyn MetricCollector,
+    ) -> Result<serde_json::Value, String> {
+        (self.save)(collector)
+    }
+
+    /// A collector from its partial state
+    pub(crate) fn load(
+        &self,
+        state: serde_json::Value,
+    ) -> Result<Box<dyn MetricCollector>, String> {
+        (self.load)(state)
+    }
+}
+
+fn save_collector<C: MetricCollector + Serialize>(
+    collector: &dyn MetricCollector,
+) -> Result<serde_json::Value, String> {
+    let collector: &dyn Any = collector;
+    let collector = collector
+        .downcast_ref::<C>()
+        .ok_or_else(|| format!("Not a collector of type {}", std::any::type_name::<C>()))?;
+    serde_json::to_value(collector).map_err(|err| format!("Error saving a collector: {err}"))
+}
+
+fn load_collector<C: MetricCollector + DeserializeOwned>(
+    state: serde_json::Value,
+) -> Result<Box<dyn MetricCollector>, String> {
+    let collector = serde_json::from_value::<C>(state)
+        .map_err(|err| format!("Error loading a collector: {err}"))?;
+    Ok(Box::new(collector))
+}
+
+/// The collectors of the enabled built-in metrics
+pub(crate) fn collectors(
+    options: &QcOptions,
+    header: &bam::HeaderView,
+) -> Vec<Box<dyn BuiltinCollector>> {
+    let mut collectors: Vec<Box<dyn BuiltinCollector>> = vec![
+        Box::new(LengthCollector::new(options)),
+        Box::new(IdentityCollector::new(options)),
+    ];
+    if options.length_stats {
+        collectors.push(Box::<LengthStatsCollector>::default());
+    }
+    if options.karyotype {
+        collectors.push(Box::<KaryotypeCollector>::default());
+    }
+    if options.phased {
+        // for coordinate-sorted input, phase blocks are built while reading
+        let sorted = crate::utils::is_coordinate_sorted(&bam::Header::from_template(header));
+        collectors.push(Box::new(PhaseCollector::new(sorted)));
+    }
+    if options.spliced {
+        collectors.push(Box::new(SpliceCollector::new(options)));
+    }
+    collectors
+}
+
+/// Read lengths, in a vector or with --sketch in a sketch
+/// with --read-level, lengths are per read and the aligned lengths per alignment are kept separately
+pub(crate) struct LengthCollector {
+    lengths: Vec<u128>,
+    sketch: Option<LengthSketch>,
+    read_level: bool,
+    read_lengths: Vec<u128>,
+    // reads with supplementary alignments are grouped by name
+    split_reads: HashMap<Vec<u8>, u128>,
+}
+
+impl LengthCollector {
+    pub(crate) fn new(options: &QcOptions) -> Self {
+        LengthCollector {
+            lengths: Vec::new(),
+            sketch: options.sketch.then(|| {
+                LengthSketch::new(&crate::utils::yield_thresholds(&options.long_thresholds))
+            }),
+            read_level: options.read_level,
+            read_lengths: Vec::new(),
+            split_reads: HashMap::new(),
+        }
+    }
+}
+
+impl BuiltinCollector for LengthCollector {
+    fn observe(&mut self, record: &bam::Record, read: &ReadInfo) {
+        match &mut self.sketch {
+            Some(sketch) => sketch.add(read.length),
+            None => self.lengths.push(read.length),
+        }
+        if self.read_level {
+            // the full length of a read is its sequence length including hard clipped bases,
+            // which is the same for every alignment of the read
+            let full_length = record.seq_len() as u128 + hardclipped_bases(record);
+            if record.aux(b"SA").is_ok() {
+                let entry = self
+                    .split_reads
+                    .entry(record.qname().to_vec())
+                    .or_insert(full_length);
+                if !record.is_supplementary() {
+                    *entry = full_length;
+                }
+            } else if !record.is_supplementary() {
+                self.read_lengths.push(full_length);
+            }
+        }
+    }
+
+    fn finalize(self: Box<Self>, data: &mut Data) {
+        let mut lengths = self.lengths;
+        // with --read-level, the read stats are calculated on the read lengths
+        // and the aligned lengths of the individual alignments are kept separately
+        if self.read_level {
+            let mut read_lengths = self.read_lengths;
+            data.num_split_reads = Some(self.split_reads.len());
+            read_lengths.extend(self.split_reads.into_values());
+            data.num_reads = read_lengths.len();
+            let mut alignment_lengths = std::mem::replace(&mut lengths, read_lengths);
+            alignment_lengths.par_sort_unstable_by(|a, b| b.cmp(a));
+            data.alignment_lengths = Some(alignment_lengths);
+        }
+        // sort in descending order (required for N50/N75)
+        lengths.par_sort_unstable_by(|a, b| b.cmp(a));
+        match self.sketch {
+            Some(sketch) => data.length_sketch = Some(sketch),
+            None => data.lengths = Some(lengths),
+        }
+    }
+}
+
+/// Read identities, in a vector or with --sketch in a sketch, and the histogram of their Phred scores
+pub(crate) struct IdentityCollector {
+    identities: Vec<f64>,
+    sketch: Option<IdentitySketch>,
+    q_score_hist: Option<QScoreHistogramData>,
+}
+
+impl IdentityCollector {
+    pub(crate) fn new(options: &QcOptions) -> Self {
+        IdentityCollector {
+            identities: Vec::new(),
+            sketch: options.sketch.then(IdentitySketch::default),
+            q_score_hist: options.histograms.then(|| QScoreHistogramData {
+                counts: vec![0u64; 41],
+                bases: vec![0u128; 41],
+            }),
+        }
+    }
+}
+
+impl BuiltinCollector for IdentityCollector {
+    fn observe(&mut self, _record: &bam::Record, read: &ReadInfo) {
+        match &mut self.sketch {
+            Some(sketch) => sketch.add(read.identity),
+            None => self.identities.push(read.identity),
+        }
+        if let Some(hist) = &mut self.q_score_hist {
+            let phred = crate::utils::accuracy_to_phred(read.identity);
+            let index = if phred < 40 { phred } else { 40 };
+            hist.counts[index] += 1;
+            hist.bases[index] += read.length;
+        }
+    }
+
+    fn finalize(self: Box<Self>, data: &mut Data) {
+        let mut identities = self.identities;
+        identities.par_sort_unstable_by(|a, b| b.partial_cmp(a).unwrap());
+        match self.sketch {
+            Some(sketch) => data.identity_sketch = Some(sketch),
+            None => data.identities = Some(identities),
+        }
+        data.q_score_hist = self.q_score_hist;
+    }
+}
+
+/// Query length (incl. clipped bases) and reference span, for --length-stats
+#[derive(Default)]
+pub(crate) struct LengthStatsCollector {
+    query_lengths: Vec<u128>,
+    reference_spans: Vec<u128>,
+}
+
+impl BuiltinCollector for LengthStatsCollector {
+    fn observe(&mut self, record: &bam::Record, _read: &ReadInfo) {
+        self.query_lengths
+            .push(record.seq_len() as u128 + hardclipped_bases(record));
+        self.reference_spans
+            .push((record.reference_end() - record.pos()) as u128);
+    }
+
+    fn finalize(self: Box<Self>, data: &mut Data) {
+        let (mut query_lengths, mut reference_spans) = (self.query_lengths, self.reference_spans);
+        query_lengths.par_sort_unstable_by(|a, b| b.cmp(a));
+        reference_spans.par_sort_unstable_by(|a, b| b.cmp(a));
+        data.query_lengths = Some(query_lengths);
+        data.reference_spans = Some(reference_spans);
+    }
+}
+
+/// Number of reads per tid, for --karyotype
+#[derive(Default)]
+pub(crate) struct KaryotypeCollector {
+    tid_counts: HashMap<i32, usize>,
+}
+
+impl BuiltinCollector for KaryotypeCollector {
+    fn observe(&mut self, record: &bam::Record, _read: &ReadInfo) {
+        *self.tid_counts.entry(record.tid()).or_default() += 1;
+    }
+
+    fn finalize(self: Box<Self>, data: &mut Data) {
+        data.tid_counts = Some(self.tid_counts);
+    }
+}
+
+/// Phase blocks and the balance of the haplotypes per chromosome, for --phased
+pub(crate) struct PhaseCollector {
+    phase_counts: HashMap<i32, ChromosomePhaseCounts>,
+    // for coordinate-sorted input, phase blocks are built while reading
+    builder: Option<PhaseBlockBuilder>,
+    // (tid, start, end, phaseset) of phased reads, only used if the input is not coordinate-sorted
+    phased_reads: Vec<(i32, i64, i64, u32)>,
+    // the first and last (tid, start) of the phased reads
+    phased_range: Option<((i32, i64), (i32, i64))>,
+}
+
+impl PhaseCollector {
+    pub(crate) fn new(coordinate_sorted: bool) -> Self {
+        PhaseCollector {
+            phase_counts: HashMap::new(),
+            builder: coordinate_sorted.then(PhaseBlockBuilder::default),
+            phased_reads: Vec::new(),
+            phased_range: None,
+        }
+    }
+
+    fn phaseblocks(self) -> Vec<PhaseBlock> {
+        match self.builder {
+            Some(builder) => builder.finish(),
+            None => phased::phase_metrics(self.phased_reads),
+        }
+    }
+}
+
+impl BuiltinCollector for PhaseCollector {
+    fn observe(&mut self, record: &bam::Record, read: &ReadInfo) {
+        // reads with an invalid PS tag are rejected before they are observed
+        let phaseset = get_phaseset(record).ok().flatten();
+        self.phase_counts.entry(record.tid()).or_default().add(
+            phaseset.is_some(),
+            get_haplotype(record),
+            read.length,
+        );
+        if let Some(phaseset) = phaseset {
+            let position = (record.tid(), record.pos());
+            self.phased_range = Some(match self.phased_range {
+                Some((first, last)) => (first.min(position), last.max(position)),
+                None => (position, position),
+            });
+            match &mut self.builder {
+                Some(builder) => {
+                    builder.add(record.tid(), record.pos(), record.reference_end(), phaseset)
+                }
+                None => self.phased_reads.push((
+                    record.tid(),
+                    record.pos(),
+                    record.reference_end(),
+                    phaseset,
+                )),
+            }
+        }
+    }
+
+    fn finalize(mut self: Box<Self>, data: &mut Data) {
+        data.phase_counts = Some(std::mem::take(&mut self.phase_counts));
+        data.phased_range = self.phased_range;
+        data.phaseblocks = Some(self.phaseblocks());
+    }
+}
+
+/// Number of exons, intron lengths and exon block lengths, for --spliced
+/// in vectors or with --sketch in a sketch
+pub(crate) struct SpliceCollector {
+    exons: Vec<usize>,
+    intron_lengths: Vec<u32>,
+    exon_lengths: Vec<u32>,
+    sketch: Option<SpliceSketch>,
+}
+
+impl SpliceCollector {
+    pub(crate) fn new(options: &QcOptions) -> Self {
+        SpliceCollector {
+            exons: Vec::new(),
+            intron_lengths: Vec::new(),
+            exon_lengths: Vec::new(),
+            sketch: options.sketch.then(SpliceSketch::default),
+        }
+    }
+}
+
+impl BuiltinCollector for SpliceCollector {
+    fn observe(&mut self, record: &bam::Record, _read: &ReadInfo) {
+        match &mut self.sketch {
+            Some(sketch) => {
+                // the vectors only hold the introns and exon blocks of this read
+                self.intron_lengths.clear();
+                self.exon_lengths.clear();
+                get_splice_blocks(record, &mut self.intron_lengths, &mut self.exon_lengths);
+                sketch.add(
+                    get_exon_number(record),
+                    &self.intron_lengths,
+                    &self.exon_lengths,
+                );
+            }
+            None => {
+                self.exons.push(get_exon_number(record));
+                get_splice_blocks(record, &mut self.intron_lengths, &mut self.exon_lengths);
+            }
+        }
+    }
+
+    fn finalize(self: Box<Self>, data: &mut Data) {
+        if let Some(sketch) = self.sketch {
+            data.splice_sketch = Some(sketch);
+            return;
+        }
+        let (mut intron_lengths, mut exon_lengths) = (self.intron_lengths, self.exon_lengths);
+        // intron and exon block lengths are sorted in ascending order (for percentiles)
+        intron_lengths.par_sort_unstable();
+        exon_lengths.par_sort_unstable();
+        data.exons = Some(self.exons);
+        data.intron_lengths = Some(intron_lengths);
+        data.exon_lengths = Some(exon_lengths);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    fn record(length: usize, tid: i32, pos: i64, phaseset: Option<u32>) -> bam::Record {
+        let mut record = bam::Record::new();
+        let cigar = CigarString(vec![Cigar::Match(length as u32)]);
+        record.set(
+            b"read",
+            Some(&cigar),
+            &vec![b'A'; length],
+            &vec![30u8; length],
+        );
+        record.set_tid(tid);
+        record.set_pos(pos);
+        record.unset_unmapped();
+        if let Some(phaseset) = phaseset {
+            record.push_aux(b"PS", Aux::U32(phaseset)).unwrap();
+            record.push_aux(b"HP", Aux::U8(1)).unwrap();
+        }
+        record
+    }
+
+    fn observe(collector: &mut impl BuiltinCollector, record: &bam::Record) {
+        let read = ReadInfo {
+            length: record.seq_len() as u128,
+            identity: 99.0,
+        };
+        collector.observe(record, &read);
+    }
+
+    #[test]
+    fn test_lengths() {
+        let mut collector = LengthCollector::new(&QcOptions::new());
+        observe(&mut collector, &record(100, 0, 0, None));
+        observe(&mut collector, &record(300, 0, 0, None));
+        observe(&mut collector, &record(200, 0, 0, None));
+        let mut data = Data::default();
+        Box::new(collector).finalize(&mut data);
+        assert_eq!(data.lengths, Some(vec![300, 200, 100]));
+        assert!(data.length_sketch.is_none());
+    }
+
+    #[test]
+    fn test_phaseblocks() {
+        let mut collector = PhaseCollector::new(true);
+        observe(&mut collector, &record(100, 0, 1000, Some(7)));
+        observe(&mut collector, &record(100, 0, 1500, Some(7)));
+        observe(&mut collector, &record(100, 0, 5000, None));
+        observe(&mut collector, &record(100, 1, 0, Some(3)));
+        let mut data = Data::default();
+        Box::new(collector).finalize(&mut data);
+        let blocks = data.phaseblocks.unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!((blocks[0].start, blocks[0].end), (1000, 1600));
+        assert_eq!(blocks[0].num_reads, 2);
+        let counts = &data.phase_counts.unwrap()[&0];
+        assert_eq!((counts.reads, counts.phased_reads), (3, 2));
+    }
+}
@@ -0,0 +1,383 @@
+use crate::metrics_processor::OutputFormat;
+use crate::{QcOptions, metrics};
+use clap::Args;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, IsTerminal};
+use std::path::Path;
+
+#[derive(Args, Debug)]
+pub struct CompareArgs {
+    /// Reports (json output of cramino) or bam/cram files to compare, the first one is the reference
+    #[clap(value_parser, num_args = 2.., required = true)]
+    inputs: Vec<String>,
+
+    /// Relative difference (in percent) with the reference above which a metric is highlighted
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    rel_tolerance: f64,
+
+    /// Absolute difference with the reference up to which a metric is never highlighted
+    #[clap(long, value_parser, default_value_t = 0.0)]
+    abs_tolerance: f64,
+
+    /// Relative tolerance (in percent) of specific metrics or sections, as comma-separated metric=tolerance (e.g. read_stats.n50=5,karyotype_stats=10)
+    #[clap(long, value_parser = parse_tolerance, value_delimiter = ',')]
+    tolerance: Vec<(String, f64)>,
+
+    /// Only show the metrics with a difference beyond the tolerance
+    #[clap(long, value_parser)]
+    changed_only: bool,
+
+    /// Number of parallel decompression threads to use for bam/cram inputs
+    #[clap(short, long, value_parser, default_value_t = 4)]
+    threads: usize,
+
+    /// reference for decompressing cram inputs
+    #[clap(long, value_parser)]
+    reference: Option<String>,
+
+    /// Minimal length of read to be considered for bam/cram inputs
+    #[clap(short, long, value_parser, default_value_t = 0)]
+    min_read_len: usize,
+
+    /// Output format (text, json, or tsv)
+    #[clap(long, value_parser, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+fn parse_tolerance(s: &str) -> Result<(String, f64), String> {
+    let (metric, tolerance) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected metric=tolerance, got {s}"))?;
+    let tolerance = tolerance
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid tolerance for {metric}: {tolerance}"))?;
+    Ok((metric.trim().to_string(), tolerance))
+}
+
+/// Difference of a metric with the reference, relative in percent
+/// Missing if the metric is absent from either report, or relative to a reference of 0
+#[derive(Serialize, Debug, PartialEq)]
+struct Difference {
+    absolute: Option<f64>,
+    relative: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+struct Comparison {
+    metric: String,
+    values: Vec<Option<f64>>,
+    /// differences of the other reports with the first
+    differences: Vec<Difference>,
+    exceeds_tolerance: bool,
+}
+
+struct Tolerances {
+    relative: f64,
+    absolute: f64,
+    per_metric: HashMap<String, f64>,
+}
+
+impl Tolerances {
+    /// The relative tolerance of a metric, from the most specific --tolerance that applies
+    fn relative(&self, metric: &str) -> f64 {
+        self.per_metric
+            .iter()
+            .filter(|(name, _)| {
+                metric == name.as_str()
+                    || metric
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with('.'))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.relative, |(_, tolerance)| *tolerance)
+    }
+
+    fn exceeds(&self, metric: &str, difference: &Difference) -> bool {
+        match difference.absolute {
+            // a metric that is only in some of the reports is always highlighted
+            None => true,
+            Some(absolute) if absolute.abs() <= self.absolute => false,
+            Some(_) => difference
+                .relative
+                .is_none_or(|relative| relative.abs() > self.relative(metric)),
+        }
+    }
+}
+
+/// Compares the reports (or the reports of the bam/cram files) with the first one
+pub fn compare(compare_args: &CompareArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let reports = compare_args
+        .inputs
+        .iter()
+        .map(|input| load_report(input, compare_args).map(|report| report.flatten()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let tolerances = Tolerances {
+        relative: compare_args.rel_tolerance,
+        absolute: compare_args.abs_tolerance,
+        per_metric: compare_args.tolerance.iter().cloned().collect(),
+    };
+    let mut comparisons = compare_reports(&reports, &tolerances);
+    let num_changed = comparisons.iter().filter(|c| c.exceeds_tolerance).count();
+    if compare_args.changed_only {
+        comparisons.retain(|c| c.exceeds_tolerance);
+    }
+    let names = compare_args
+        .inputs
+        .iter()
+        .map(|input| {
+            Path::new(input)
+                .file_name()
+                .map_or(input.clone(), |name| name.to_string_lossy().into_owned())
+        })
+        .collect::<Vec<_>>();
+
+    match compare_args.format {
+        OutputFormat::Text => {
+            print_text_table(&names, &comparisons);
+            println!(
+                "\n{num_changed} of {} metrics differ beyond the tolerance",
+                reports.iter().map(|r| r.len()).max().unwrap_or(0)
+            );
+        }
+        OutputFormat::Json => {
+            let output = serde_json::json!({
+                "inputs": names,
+                "num_changed": num_changed,
+                "metrics": comparisons,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        OutputFormat::Tsv => {
+            let mut header = table_header(&names);
+            header.push("exceeds_tolerance".to_string());
+            println!("{}", header.join("\t"));
+            for comparison in &comparisons {
+                let mut row = table_row(comparison);
+                row.push(comparison.exceeds_tolerance.to_string());
+                println!("{}", row.join("\t"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a json report, or calculates the metrics of a bam/cram file with the default options
+fn load_report(
+    input: &str,
+    compare_args: &CompareArgs,
+) -> Result<metrics::Metrics, Box<dyn std::error::Error>> {
+    if input.ends_with(".json") {
+        let reader = BufReader::new(File::open(input)?);
+        // the report can be followed by histogram counts if these were written to stdout
+        return serde_json::Deserializer::from_reader(reader)
+            .into_iter::<metrics::Metrics>()
+            .next()
+            .ok_or_else(|| format!("Report {input} is empty"))?
+            .map_err(|err| format!("Error reading report {input}: {err}").into());
+    }
+    let mut options = QcOptions::new()
+        .threads(compare_args.threads)
+        .min_read_len(compare_args.min_read_len);
+    if let Some(reference) = &compare_args.reference {
+        options = options.reference(reference);
+    }
+    crate::metrics_from_path(input, &options)
+}
+
+/// Aligns the metrics of the reports by name, in the order they first appear
+fn compare_reports(reports: &[Vec<(String, f64)>], tolerances: &Tolerances) -> Vec<Comparison> {
+    let mut metrics: Vec<&str> = Vec::new();
+    let mut values: HashMap<&str, Vec<Option<f64>>> = HashMap::new();
+    for (index, report) in reports.iter().enumerate() {
+        for (metric, value) in report {
+            values.entry(metric).or_insert_with(|| {
+                metrics.push(metric);
+                vec![None; reports.len()]
+            })[index] = Some(*value);
+        }
+    }
+    metrics
+        .into_iter()
+        .map(|metric| {
+            let values = values.remove(metric).unwrap_or_default();
+            let differences = values[1..]
+                .iter()
+                .map(|value| difference(values[0], *value))
+                .collect::<Vec<_>>();
+            let exceeds_tolerance = differences
+                .iter()
+                .any(|difference| tolerances.exceeds(metric, difference));
+            Comparison {
+                metric: metric.to_string(),
+                values,
+                differences,
+                exceeds_tolerance,
+            }
+        })
+        .collect()
+}
+
+fn difference(reference: Option<f64>, value: Option<f64>) -> Difference {
+    match (reference, value) {
+        (Some(reference), Some(value)) => Difference {
+            absolute: Some(value - reference),
+            relative: if value == reference {
+                Some(0.0)
+            } else if reference == 0.0 {
+                None
+            } else {
+                Some((value - reference) / reference.abs() * 100.0)
+            },
+        },
+        _ => Difference {
+            absolute: None,
+            relative: None,
+        },
+    }
+}
+
+fn table_header(names: &[String]) -> Vec<String> {
+    let mut header = vec!["metric".to_string()];
+    header.extend(names.iter().cloned());
+    for name in &names[1..] {
+        header.push(format!("diff_{name}"));
+        header.push(format!("rel_diff_{name}"));
+    }
+    header
+}
+
+fn table_row(comparison: &Comparison) -> Vec<String> {
+    let mut row = vec![comparison.metric.clone()];
+    row.extend(comparison.values.iter().map(|value| format_value(*value)));
+    for difference in &comparison.differences {
+        row.push(match difference.absolute {
+            Some(absolute) if absolute >= 0.0 => format!("+{}", format_value(Some(absolute))),
+            absolute => format_value(absolute),
+        });
+        row.push(
+            difference
+                .relative
+                .map_or("NA".to_string(), |relative| format!("{relative:+.2}%")),
+        );
+    }
+    row
+}
+
+fn format_value(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.fract() == 0.0 => format!("{value:.0}"),
+        // e.g. the normalized counts of the karyotype
+        Some(value) if value.abs() < 1e-3 => format!("{value:.3e}"),
+        Some(value) => format!("{value:.4}"),
+        None => "NA".to_string(),
+    }
+}
+
+/// Prints an aligned table, in which metrics beyond the tolerance are marked with *
+/// and shown in red if the output is a terminal
+fn print_text_table(names: &[String], comparisons: &[Comparison]) {
+    let color = std::io::stdout().is_terminal();
+    let header = table_header(names);
+    let rows = comparisons.iter().map(table_row).collect::<Vec<_>>();
+    let widths = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].len())
+                .chain(std::iter::once(header[column].len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    let format_row = |row: &[String]| {
+        row.iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(column, (cell, width))| {
+                // the metric names are left-aligned, numbers right-aligned
+                if column == 0 {
+                    format!("{cell:<width$}")
+                } else {
+                    format!("{cell:>width$}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("  ")
+    };
+    println!("  {}", format_row(&header));
+    for (row, comparison) in rows.iter().zip(comparisons) {
+        let line = format_row(row);
+        match (comparison.exceeds_tolerance, color) {
+            (true, true) => println!("* \x1b[1;31m{line}\x1b[0m"),
+            (true, false) => println!("* {line}"),
+            (false, _) => println!("  {line}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tolerances(per_metric: &[(&str, f64)]) -> Tolerances {
+        Tolerances {
+            relative: 1.0,
+            absolute: 0.0,
+            per_metric: per_metric
+                .iter()
+                .map(|(metric, tolerance)| (metric.to_string(), *tolerance))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_compare_reports() {
+        let reference = vec![
+            ("read_stats.n50".to_string(), 10_000.0),
+            ("read_stats.yield_gb".to_string(), 100.0),
+            ("karyotype_stats.chr1.count".to_string(), 0.0),
+        ];
+        let other = vec![
+            ("read_stats.n50".to_string(), 10_050.0),
+            ("read_stats.yield_gb".to_string(), 90.0),
+            ("karyotype_stats.chr1.count".to_string(), 0.0),
+            ("karyotype_stats.chr2.count".to_string(), 5.0),
+        ];
+        let comparisons = compare_reports(&[reference, other], &tolerances(&[]));
+        assert_eq!(comparisons.len(), 4);
+        assert_eq!(
+            comparisons[0].differences[0],
+            Difference {
+                absolute: Some(50.0),
+                relative: Some(0.5)
+            }
+        );
+        assert!(!comparisons[0].exceeds_tolerance);
+        assert!(comparisons[1].exceeds_tolerance);
+        assert!(!comparisons[2].exceeds_tolerance);
+        // only in the second report
+        assert_eq!(comparisons[3].values, vec![None, Some(5.0)]);
+        assert!(comparisons[3].exceeds_tolerance);
+    }
+
+    #[test]
+    fn test_tolerances() {
+        let tolerances = tolerances(&[("read_stats", 20.0), ("read_stats.n50", 0.1)]);
+        assert_eq!(tolerances.relative("read_stats.yield_gb"), 20.0);
+        assert_eq!(tolerances.relative("read_stats.n50"), 0.1);
+        assert_eq!(tolerances.relative("read_stats_long.n50"), 1.0);
+        assert!(tolerances.exceeds("read_stats.n50", &difference(Some(100.0), Some(100.5))));
+        assert!(!tolerances.exceeds("read_stats.n75", &difference(Some(100.0), Some(110.0))));
+        // a change from 0 has no relative difference
+        assert!(tolerances.exceeds(
+            "phase_stats.num_phaseblocks",
+            &difference(Some(0.0), Some(1.0))
+        ));
+        assert_eq!(
+            parse_tolerance("read_stats.n50=5"),
+            Ok(("read_stats.n50".to_string(), 5.0))
+        );
+        assert!(parse_tolerance("read_stats.n50").is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use crate::{Cli, qc};
+use clap::builder::Resettable;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, ValueEnum};
+use std::ffi::OsString;
+
+/// Environment variable with the path of the config file, if --config is not given
+pub const CONFIG_ENV: &str = "CRAMINO_CONFIG";
+
+/// Options that require another option, e.g. --state requires --sketch, which can also be
+/// given in the config file or preset
+const REQUIREMENTS: [(&str, &str); 5] = [
+    ("state", "sketch"),
+    ("read_table_tags", "read_table"),
+    ("phaseblocks_bed", "phased"),
+    ("phaseblocks_regions", "phased"),
+    ("plot_format", "plot_dir"),
+];
+
+/// Built-in combinations of options for common data types, in the format of a config file
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    /// Nanopore whole genome sequencing
+    OntWgs,
+    /// PacBio HiFi whole genome sequencing
+    HifiWgs,
+    /// Nanopore (direct or cDNA) RNA sequencing
+    OntRna,
+    /// Unaligned reads
+    Ubam,
+}
+
+impl Preset {
+    fn config(&self) -> &'static str {
+        match self {
+            Preset::OntWgs => {
+                r#"
+                karyotype = true
+                phased = true
+                scaled = true
+                long_thresholds = [25000, 50000, 100000]
+
+                [qc_thresholds]
+                warn = ["read_stats.n50 >= 15000", "identity_stats.median_identity >= 95"]
+                "#
+            }
+            Preset::HifiWgs => {
+                r#"
+                karyotype = true
+                phased = true
+                long_thresholds = [15000, 25000]
+
+                [qc_thresholds]
+                warn = ["read_stats.n50 >= 12000", "identity_stats.median_identity >= 99"]
+                "#
+            }
+            Preset::OntRna => {
+                r#"
+                spliced = true
+                long_thresholds = [1000, 5000]
+
+                [qc_thresholds]
+                warn = ["identity_stats.median_identity >= 90"]
+                "#
+            }
+            Preset::Ubam => {
+                r#"
+                ubam = true
+                scaled = true
+                long_thresholds = [25000, 50000, 100000]
+
+                [qc_thresholds]
+                warn = ["read_stats.n50 >= 15000"]
+                "#
+            }
+        }
+    }
+}
+
+/// Parses the command line of the process, see parse_args_from
+pub fn parse_args() -> Cli {
+    parse_args_from(std::env::args_os().collect()).unwrap_or_else(|e| e.exit())
+}
+
+/// Parses the command line, with the defaults from the preset and the config file:
+/// options given on the command line override those of the config file, which override those of the preset
+pub fn parse_args_from(argv: Vec<OsString>) -> Result<Cli, clap::Error> {
+    // errors are ignored until the settings are known, which can e.g. satisfy a requirement
+    let matches = command().ignore_errors(true).try_get_matches_from(&argv)?;
+    let config = matches.get_one::<String>("config");
+    let cli_preset = matches.get_one::<Preset>("preset").copied();
+    // the subcommands have their own options
+    if matches.subcommand().is_some() || (config.is_none() && cli_preset.is_none()) {
+        return Cli::from_arg_matches(&command().try_get_matches_from(argv)?);
+    }
+    let mut settings = match config {
+        Some(path) => read_config(path)?,
+        None => toml::Table::new(),
+    };
+    let preset = match settings.remove("preset") {
+        Some(_) if from_command_line(&matches, "preset") => cli_preset,
+        Some(toml::Value::String(name)) => Some(
+            Preset::from_str(&name, false)
+                .map_err(|_| config_error(format!("Unknown preset in config file: {name}")))?,
+        ),
+        Some(value) => {
+            return Err(config_error(format!(
+                "Invalid preset in config file: {value}"
+            )));
+        }
+        None => cli_preset,
+    };
+    if let Some(preset) = preset {
+        let mut preset_settings = toml::from_str::<toml::Table>(preset.config())
+            .expect("Invalid config of built-in preset");
+        preset_settings.extend(settings);
+        settings = preset_settings;
+    }
+
+    // rules can be given inline, instead of as the path of a file
+    let mut qc_thresholds = None;
+    if let Some(toml::Value::Table(rules)) = settings.get("qc_thresholds") {
+        if !from_command_line(&matches, "qc_thresholds") {
+            qc_thresholds = Some(
+                toml::Value::Table(rules.clone())
+                    .try_into::<qc::QcThresholds>()
+                    .map_err(|e| config_error(format!("Invalid qc_thresholds: {e}")))?,
+            );
+        }
+        settings.remove("qc_thresholds");
+    }
+
+    // the settings are the defaults of the options, so that the command line is parsed as given
+    let mut command = command();
+    let defaults = settings_to_defaults(&settings, &matches)?;
+    for (option, required) in REQUIREMENTS {
+        // clap only accepts requirements on the command line
+        if defaults
+            .iter()
+            .any(|(id, values)| id == required && values != &["false"])
+        {
+            command = command.mut_arg(option, |arg| arg.requires(Resettable::Reset));
+        }
+    }
+    for (id, values) in defaults {
+        command = command.mut_arg(id, |arg| arg.default_values(values));
+    }
+    let matches = command.try_get_matches_from(argv)?;
+    let mut args = Cli::from_arg_matches(&matches)?;
+    if qc_thresholds.is_some() {
+        args.qc_thresholds = qc_thresholds;
+    }
+    Ok(args)
+}
+
+/// The options of Cli, with a --no-<flag> for every flag to switch off that of the config file or preset
+fn command() -> Command {
+    let mut command = Cli::command();
+    let flags = command
+        .get_arguments()
+        .filter(|arg| matches!(arg.get_action(), ArgAction::SetTrue))
+        .filter_map(|arg| Some((arg.get_id().to_string(), arg.get_long()?.to_string())))
+        .collect::<Vec<_>>();
+    for (id, long) in flags {
+        command = command.arg(
+            Arg::new(format!("no_{id}"))
+                .long(format!("no-{long}"))
+                .action(ArgAction::SetTrue)
+                .conflicts_with(id)
+                .hide(true),
+        );
+    }
+    command
+}
+
+fn read_config(path: &str) -> Result<toml::Table, clap::Error> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| config_error(format!("Could not read config file {path}: {e}")))?;
+    toml::from_str(&content).map_err(|e| config_error(format!("Invalid config file {path}: {e}")))
+}
+
+fn from_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// Translates the settings to the default values of the options, e.g. karyotype = true to
+/// true for karyotype and nx = [50, 90] to 50 and 90 for nx
+/// Settings of options on the command line, or that conflict with one on the command line
+/// (e.g. lenient with --strict, or phased with --no-phased), are skipped
+fn settings_to_defaults(
+    settings: &toml::Table,
+    matches: &ArgMatches,
+) -> Result<Vec<(String, Vec<String>)>, clap::Error> {
+    let command = command();
+    let on_command_line = command
+        .get_arguments()
+        .filter(|arg| from_command_line(matches, arg.get_id().as_str()))
+        .collect::<Vec<_>>();
+    let mut defaults = Vec::new();
+    for (key, value) in settings {
+        // options can be written as in the help (min-read-len) or as in the json (min_read_len)
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .filter(|arg| !["config", "preset"].contains(&arg.get_id().as_str()))
+            .filter(|arg| !arg.is_hide_set())
+            .find(|arg| arg.get_id() == id.as_str())
+            .ok_or_else(|| config_error(format!("Unknown option in config file: {key}")))?;
+        if on_command_line.iter().any(|other| {
+            other.get_id() == arg.get_id()
+                || command.get_arg_conflicts_with(other).contains(&arg)
+                || command.get_arg_conflicts_with(arg).contains(other)
+        }) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Boolean(_) | toml::Value::Integer(_) | toml::Value::Float(_) => {
+                vec![value.to_string()]
+            }
+            toml::Value::String(text) => vec![text.clone()],
+            toml::Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                })
+                .collect(),
+            _ => {
+                return Err(config_error(format!(
+                    "Invalid value for {key} in config file: {value}"
+                )));
+            }
+        };
+        defaults.push((id, values));
+    }
+    Ok(defaults)
+}
+
+fn config_error(message: String) -> clap::Error {
+    Cli::command().error(clap::error::ErrorKind::InvalidValue, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        parse_args_from(args.iter().map(OsString::from).collect())
+    }
+
+    #[test]
+    fn test_presets() {
+        for preset in Preset::value_variants() {
+            let name = preset.to_possible_value().unwrap().get_name().to_string();
+            let args = parse(&["cramino", "--preset", &name, "sample.bam"]).unwrap();
+            assert_eq!(args.input, "sample.bam");
+            assert!(args.qc_thresholds.is_some());
+        }
+        let args = parse(&["cramino", "--preset", "ont-wgs", "sample.bam"]).unwrap();
+        assert!(args.metrics.karyotype && args.phased && args.scaled);
+        assert_eq!(args.metrics.long_thresholds, [25_000, 50_000, 100_000]);
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let path = std::env::temp_dir().join("cramino-config-test.toml");
+        std::fs::write(
+            &path,
+            "preset = \"ont-rna\"\nmin-read-len = 500\nnx = [50, 90]\nlong_thresholds = [2000]\nformat = \"json\"\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+        let args = parse(&["cramino", "--config", config, "--nx", "10", "sample.bam"]).unwrap();
+        // the preset is applied, with the options of the config file and the command line on top
+        assert!(args.spliced);
+        assert_eq!(args.metrics.min_read_len, 500);
+        assert_eq!(args.metrics.long_thresholds, [2000]);
+        assert_eq!(args.metrics.nx, [10]);
+        assert_eq!(args.format.to_string(), "json");
+        // the preset on the command line takes precedence over that of the config file
+        let args = parse(&["cramino", "--config", config, "--preset", "ubam"]).unwrap();
+        assert!(args.metrics.ubam && !args.spliced);
+
+        // --strict on the command line overrides lenient in the config file
+        std::fs::write(&path, "lenient = true\n").unwrap();
+        assert!(parse(&["cramino", "--config", config]).unwrap().lenient);
+        assert!(
+            !parse(&["cramino", "--config", config, "--strict"])
+                .unwrap()
+                .lenient
+        );
+
+        // flags of the config file or preset are switched off with --no-<flag>
+        std::fs::write(&path, "preset = \"ont-wgs\"\nsketch = true\n").unwrap();
+        let args = parse(&["cramino", "--config", config, "--no-phased", "sample.bam"]).unwrap();
+        assert!(!args.phased && args.metrics.karyotype);
+        assert!(parse(&["cramino", "--phased", "--no-phased", "sample.bam"]).is_err());
+        // settings are defaults, which satisfy requirements and don't conflict with the command line
+        let args = parse(&[
+            "cramino",
+            "--config",
+            config,
+            "--state",
+            "s.json",
+            "sample.bam",
+        ])
+        .unwrap();
+        assert!(args.sketch && args.state.is_some());
+        assert!(parse(&["cramino", "--state", "s.json", "sample.bam"]).is_err());
+
+        std::fs::write(&path, "karyotpe = true\n").unwrap();
+        assert!(parse(&["cramino", "--config", config]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// Autosomes and sex chromosomes of GRCh38 and CHM13, with or without the chr prefix
+/// This excludes chrM, unplaced and unlocalized contigs, alts, decoys, HLA and EBV
+const PRIMARY_ASSEMBLY: &str = "(chr)?([1-9]|1[0-9]|2[0-2]|X|Y)";
+
+/// Selection of contigs used for the genome size (coverage) and karyotype
+/// Specified as "primary" (preset), or a comma-separated list of contig names or regular expressions
+#[derive(Debug, Clone)]
+pub struct ContigFilter {
+    spec: String,
+    patterns: Vec<Regex>,
+}
+
+impl ContigFilter {
+    /// Checks if a contig name matches the full name of one of the patterns
+    pub fn is_match(&self, contig: &str) -> bool {
+        self.patterns.iter().any(|pattern| pattern.is_match(contig))
+    }
+}
+
+impl FromStr for ContigFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let patterns = if s == "primary" {
+            vec![PRIMARY_ASSEMBLY]
+        } else {
+            s.split(',')
+                .map(|pattern| pattern.trim())
+                .filter(|pattern| !pattern.is_empty())
+                .collect()
+        };
+        if patterns.is_empty() {
+            return Err("No contigs specified".to_string());
+        }
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| {
+                // anchor the pattern so that chr1 doesn't also select chr10 or chr1_KI270706v1_random
+                Regex::new(&format!("^(?:{pattern})$"))
+                    .map_err(|e| format!("Invalid contig pattern {pattern}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ContigFilter {
+            spec: s.to_string(),
+            patterns,
+        })
+    }
+}
+
+impl fmt::Display for ContigFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
+
+/// Parses a genome size, optionally with a k, m or g suffix (e.g. 3.1g)
+pub fn parse_genome_size(s: &str) -> Result<u64, String> {
+    let s = s.trim().to_lowercase();
+    let (number, multiplier) = match s.chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1e3),
+        Some('m') => (&s[..s.len() - 1], 1e6),
+        Some('g') => (&s[..s.len() - 1], 1e9),
+        _ => (&s[..], 1.0),
+    };
+    let size = number
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid genome size: {s}"))?
+        * multiplier;
+    if size < 1.0 {
+        return Err(format!("Genome size should be positive: {s}"));
+    }
+    Ok(size.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_primary_preset() {
+        let filter = ContigFilter::from_str("primary").unwrap();
+        for contig in ["chr1", "chr22", "chrX", "chrY", "1", "X"] {
+            assert!(filter.is_match(contig), "{contig}");
+        }
+        for contig in [
+            "chr23",
+            "chrM",
+            "chrEBV",
+            "chr1_KI270706v1_random",
+            "chrUn_KI270302v1",
+            "HLA-A*01:01:01:01",
+            "chr6_GL000250v2_alt",
+        ] {
+            assert!(!filter.is_match(contig), "{contig}");
+        }
+    }
+
+    #[test]
+    fn test_contig_list_and_regex() {
+        let filter = ContigFilter::from_str("chr1, chr2,chr[XY]").unwrap();
+        assert!(filter.is_match("chr1"));
+        assert!(filter.is_match("chrX"));
+        assert!(!filter.is_match("chr10"));
+        assert!(!filter.is_match("chr3"));
+        assert!(ContigFilter::from_str("chr(").is_err());
+        assert!(ContigFilter::from_str(",").is_err());
+    }
+
+    #[test]
+    fn test_parse_genome_size() {
+        assert_eq!(parse_genome_size("3100000000"), Ok(3_100_000_000));
+        assert_eq!(parse_genome_size("3.1g"), Ok(3_100_000_000));
+        assert_eq!(parse_genome_size("3.1G"), Ok(3_100_000_000));
+        assert_eq!(parse_genome_size("12m"), Ok(12_000_000));
+        assert!(parse_genome_size("huge").is_err());
+        assert!(parse_genome_size("0").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+
+/// Exit code when the input can't be parsed, also after writing the report of a truncated file
+pub const EXIT_INCOMPLETE: i32 = 6;
+
+/// Errors of reading the input and writing the output, with the exit code of their class
+#[derive(Debug)]
+pub enum CraminoError {
+    /// The input can't be opened, e.g. a missing file, an unparsable url or a wrong reference
+    Input { input: String, message: String },
+    /// A record with an invalid value, e.g. a negative NM tag, skipped with --lenient
+    InvalidRecord {
+        read_name: String,
+        /// byte offset of the bgzf block of the record, only known for bam files
+        offset: Option<u64>,
+        message: String,
+    },
+    /// The input can't be parsed, e.g. a malformed or truncated file, which ends the reading
+    /// with the metrics of the records before, flagged as incomplete
+    Parse {
+        /// number of records read before the error
+        records: usize,
+        offset: Option<u64>,
+        message: String,
+    },
+    /// An output file can't be written
+    Output { path: String, message: String },
+}
+
+impl CraminoError {
+    /// Exit codes per class of error, next to 1 for other errors, 2 for invalid options
+    /// and 3 (qc::EXIT_FAILURE) for failed QC rules
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CraminoError::Input { .. } => 4,
+            CraminoError::InvalidRecord { .. } => 5,
+            CraminoError::Parse { .. } => EXIT_INCOMPLETE,
+            CraminoError::Output { .. } => 7,
+        }
+    }
+
+    pub(crate) fn output(path: &str, err: impl fmt::Display) -> Self {
+        CraminoError::Output {
+            path: path.to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CraminoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CraminoError::Input { input, message } => {
+                write!(f, "Error opening {input}: {message}")
+            }
+            CraminoError::InvalidRecord {
+                read_name,
+                offset,
+                message,
+            } => {
+                write!(f, "Invalid record {read_name}")?;
+                if let Some(offset) = offset {
+                    write!(f, " at byte offset {offset}")?;
+                }
+                write!(f, ": {message}")
+            }
+            CraminoError::Parse {
+                records,
+                offset,
+                message,
+            } => {
+                write!(f, "Error parsing the input after {records} records")?;
+                if let Some(offset) = offset {
+                    write!(f, " at byte offset {offset}")?;
+                }
+                write!(f, ": {message}")
+            }
+            CraminoError::Output { path, message } => {
+                write!(f, "Error writing {path}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CraminoError {}
+
+/// The exit code of an error of run(), 1 if it is not a CraminoError
+pub fn exit_code(err: &(dyn std::error::Error + 'static)) -> i32 {
+    err.downcast_ref::<CraminoError>()
+        .map_or(1, CraminoError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let err: Box<dyn std::error::Error> = Box::new(CraminoError::InvalidRecord {
+            read_name: "read1".to_string(),
+            offset: Some(1024),
+            message: "negative NM tag".to_string(),
+        });
+        assert_eq!(exit_code(err.as_ref()), 5);
+        assert_eq!(
+            err.to_string(),
+            "Invalid record read1 at byte offset 1024: negative NM tag"
+        );
+        let err: Box<dyn std::error::Error> = "other".into();
+        assert_eq!(exit_code(err.as_ref()), 1);
+    }
+}
@@ -1,30 +1,78 @@
-use bam::ext::BamRecordExtensions;
+use crate::QcOptions;
+use crate::collectors::{self, MetricCollector, ReadInfo};
+use crate::error::CraminoError;
+use crate::feather::{self, FeatherWriter};
+use crate::metrics::Incomplete;
+use crate::phased::{ChromosomePhaseCounts, PhaseBlock};
+use crate::progress::Progress;
+use crate::read_table::{ReadRow, ReadTableWriter};
+use crate::sketch::{IdentitySketch, LengthSketch, SpliceSketch};
 use log::warn;
-use rayon::prelude::*;
 use rust_htslib::bam::record::{Aux, Cigar};
 use rust_htslib::{bam, bam::Read, htslib};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::env;
+use std::rc::Rc;
 use url::Url;
 
+#[derive(Default)]
 pub struct Data {
     pub lengths: Option<Vec<u128>>,
+    pub alignment_lengths: Option<Vec<u128>>,
+    pub num_split_reads: Option<usize>,
+    pub coverage_yield: Option<u128>,
+    pub query_lengths: Option<Vec<u128>>,
+    pub reference_spans: Option<Vec<u128>>,
     pub num_reads: usize,
     pub all_counts: usize,
     pub identities: Option<Vec<f64>>,
+    pub length_sketch: Option<LengthSketch>,
+    pub identity_sketch: Option<IdentitySketch>,
     pub q_score_hist: Option<QScoreHistogramData>,
-    pub tids: Option<Vec<i32>>,
-    pub starts: Option<Vec<i64>>,
-    pub ends: Option<Vec<i64>>,
-    pub phasesets: Option<Vec<Option<u32>>>,
+    /// number of reads per tid, for --karyotype
+    pub tid_counts: Option<HashMap<i32, usize>>,
+    pub phaseblocks: Option<Vec<PhaseBlock>>,
+    /// the first and last (tid, start) of the phased reads, to check that shards don't overlap
+    pub phased_range: Option<((i32, i64), (i32, i64))>,
+    pub phase_counts: Option<HashMap<i32, ChromosomePhaseCounts>>,
     pub exons: Option<Vec<usize>>,
+    pub intron_lengths: Option<Vec<u32>>,
+    pub exon_lengths: Option<Vec<u32>>,
+    pub splice_sketch: Option<SpliceSketch>,
     pub is_ubam: bool,
+    /// number of invalid records skipped with --lenient
+    pub skipped_records: usize,
+    /// the errors of the first skipped records
+    pub skipped_errors: Vec<String>,
+    /// set if the input could not be read completely, e.g. a truncated file
+    pub incomplete: Option<Incomplete>,
+    /// the collectors registered with QcOptions::collector, which add their section to the report
+    pub(crate) collectors: Vec<Box<dyn MetricCollector>>,
 }
 
+/// Number of errors of skipped records that are kept for the report
+pub(crate) const MAX_SKIPPED_ERRORS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QScoreHistogramData {
     pub counts: Vec<u64>,
     pub bases: Vec<u128>,
 }
 
+impl QScoreHistogramData {
+    /// Adds the reads of another histogram
+    pub fn merge(&mut self, other: &QScoreHistogramData) {
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        for (bases, other_bases) in self.bases.iter_mut().zip(&other.bases) {
+            *bases += other_bases;
+        }
+    }
+}
+
 /// Sets up the CURL_CA_BUNDLE environment variable for HTTPS/S3 access
 /// Tries to use a CA bundle from standard locations, with appropriate fallbacks
 fn setup_ssl_certificates() {
@@ -60,43 +108,42 @@ fn setup_ssl_certificates() {
     );
 }
 
-pub fn extract(args: &crate::Cli) -> (Data, rust_htslib::bam::Header) {
-    let mut lengths = vec![];
-    let mut num_reads = 0;
-    let mut identities = vec![];
-    let hist_requested = args.hist.is_some() || args.hist_count.is_some();
-    let mut q_score_counts = Vec::new();
-    let mut q_score_bases = Vec::new();
-    if hist_requested {
-        q_score_counts = vec![0u64; 41];
-        q_score_bases = vec![0u128; 41];
-    }
-    let mut tids = vec![];
-    let mut starts = vec![];
-    let mut ends = vec![];
-    let mut phasesets = vec![];
-    let mut exons = vec![];
-    let mut bam = if args.input == "-" {
-        bam::Reader::from_stdin().expect("\n\nError reading alignments from stdin.\nDid you include the file header with -h?\n\n\n\n")
-    } else if args.input.starts_with("s3") || args.input.starts_with("https://") {
+/// Extracts the data of the reads in args.input, for the command line
+pub fn extract(args: &crate::Cli) -> Result<(Data, rust_htslib::bam::Header), CraminoError> {
+    let options = QcOptions::from(args);
+    let mut bam = open(&args.input, &options)?;
+    extract_reader(&mut bam, &args.input, &options)
+}
+
+/// Opens a bam or cram file, a url (s3:// or https://) or stdin (-) with the reference and threads of the options
+pub fn open(input: &str, options: &QcOptions) -> Result<bam::Reader, CraminoError> {
+    let error = |message: String| CraminoError::Input {
+        input: input.to_string(),
+        message,
+    };
+    let mut bam = if input == "-" {
+        bam::Reader::from_stdin().map_err(|_| {
+            error(
+                "could not read alignments from stdin, did you include the file header with -h?"
+                    .to_string(),
+            )
+        })?
+    } else if input.starts_with("s3") || input.starts_with("https://") {
         setup_ssl_certificates();
-        bam::Reader::from_url(&Url::parse(&args.input).expect("Failed to parse URL"))
-            .unwrap_or_else(|err| panic!("Error opening remote BAM: {err}"))
+        let url = Url::parse(input).map_err(|err| error(format!("invalid url: {err}")))?;
+        bam::Reader::from_url(&url)
+            .map_err(|err| error(format!("could not open remote BAM: {err}")))?
     } else {
-        bam::Reader::from_path(&args.input)
-            .expect("Error opening BAM/CRAM file.\nIs the input file correct?\n\n\n\n")
+        bam::Reader::from_path(input)
+            .map_err(|err| error(format!("{err}, is the input file correct?")))?
     };
-    if args.input.ends_with(".cram") & args.reference.is_some() {
-        // bam.set_cram_option(htslib::CFR_REQUIRED_FIELDS, htslib::sam_fields_SAM_AUX as i32)
-        //     .expect("Failed setting cram options");
-        bam.set_reference(
-            args.reference
-                .as_ref()
-                .expect("Failed setting reference for CRAM file"),
-        )
-        .expect("Failed setting reference for CRAM file");
-    }
-    if args.input.ends_with(".cram") {
+    if input.ends_with(".cram") {
+        if let Some(reference) = &options.reference {
+            // bam.set_cram_option(htslib::CFR_REQUIRED_FIELDS, htslib::sam_fields_SAM_AUX as i32)
+            //     .expect("Failed setting cram options");
+            bam.set_reference(reference)
+                .map_err(|err| error(format!("failed setting reference {reference}: {err}")))?;
+        }
         bam.set_cram_options(
             hts_sys::hts_fmt_option_CRAM_OPT_REQUIRED_FIELDS,
             hts_sys::sam_fields_SAM_AUX
@@ -104,13 +151,144 @@ pub fn extract(args: &crate::Cli) -> (Data, rust_htslib::bam::Header) {
                 | hts_sys::sam_fields_SAM_CIGAR
                 | hts_sys::sam_fields_SAM_SEQ,
         )
-        .expect("Failed setting cram options");
+        .map_err(|err| error(format!("failed setting cram options: {err}")))?;
     }
+    bam.set_threads(options.threads)
+        .map_err(|err| error(format!("failed setting decompression threads: {err}")))?;
+    Ok(bam)
+}
+
+/// Extracts the data of the reads of a reader, e.g. an indexed reader fetching a region
+pub fn extract_reader<R: bam::Read>(
+    bam: &mut R,
+    input: &str,
+    options: &QcOptions,
+) -> Result<(Data, rust_htslib::bam::Header), CraminoError> {
     let header = bam.header().clone();
-    let header = rust_htslib::bam::Header::from_template(&header);
-    bam.set_threads(args.threads)
-        .expect("Failure setting decompression threads");
+    // the offset of a record, for the context of errors, can only be told in bam files
+    let is_bam = unsafe { (*bam.htsfile()).format.format } == htslib::htsExactFormat_bam;
+    // a file without EOF marker is truncated, even if all its blocks can be read
+    let missing_eof = has_eof_marker(bam) == Some(false);
+    if missing_eof {
+        warn!("The EOF marker of {input} is absent, the file may be truncated");
+    }
+    let mut record = Rc::new(bam::Record::new());
+    let mut records_read = 0;
+    let records = std::iter::from_fn(|| {
+        let offset = is_bam.then(|| (bam.tell() >> 16) as u64);
+        // as in rc_records(), the record is reused once the previous one is dropped
+        if Rc::get_mut(&mut record).is_none() {
+            record = Rc::new(bam::Record::new());
+        }
+        match bam.read(Rc::get_mut(&mut record).expect("Record is still in use"))? {
+            Ok(()) => {
+                records_read += 1;
+                Some(Ok((Rc::clone(&record), offset)))
+            }
+            Err(err) => Some(Err(CraminoError::Parse {
+                records: records_read,
+                offset,
+                message: err.to_string(),
+            })),
+        }
+    });
+    let data = extract_with_offsets(records, missing_eof, &header, input, options)?;
+    Ok((data, rust_htslib::bam::Header::from_template(&header)))
+}
+
+/// Whether a bam or cram file ends with an EOF marker, None if that can't be checked (e.g. for stdin)
+pub(crate) fn has_eof_marker<R: bam::Read>(bam: &R) -> Option<bool> {
+    let htsfile = unsafe { &*bam.htsfile() };
+    let eof = match htsfile.format.format {
+        htslib::htsExactFormat_bam => unsafe { htslib::bgzf_check_EOF(htsfile.fp.bgzf) },
+        htslib::htsExactFormat_cram => unsafe { htslib::cram_check_EOF(htsfile.fp.cram) },
+        _ => return None,
+    };
+    match eof {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
 
+/// Extracts the data of the reads, with the header they were read with
+/// The input is only used in the metadata of the arrow file and read table
+pub fn extract_records<R: Borrow<bam::Record>>(
+    records: impl Iterator<Item = R>,
+    header: &bam::HeaderView,
+    input: &str,
+    args: &QcOptions,
+) -> Result<Data, CraminoError> {
+    let records = records.map(|record| Ok((record, None)));
+    extract_with_offsets(records, false, header, input, args)
+}
+
+/// Extracts the data of the reads, with the file offset of each read if it is known
+/// After an error reading the records, the data of the records before is returned,
+/// flagged as incomplete
+fn extract_with_offsets<R: Borrow<bam::Record>>(
+    records: impl Iterator<Item = Result<(R, Option<u64>), CraminoError>>,
+    missing_eof: bool,
+    header: &bam::HeaderView,
+    input: &str,
+    args: &QcOptions,
+) -> Result<Data, CraminoError> {
+    let mut num_reads = 0;
+    // the phase blocks of unsorted input are built from all phased reads, which takes memory per read
+    if args.sketch
+        && args.phased
+        && !crate::utils::is_coordinate_sorted(&bam::Header::from_template(header))
+    {
+        return Err(CraminoError::Input {
+            input: input.to_string(),
+            message: "--phased with --sketch requires coordinate-sorted input".to_string(),
+        });
+    }
+    let mut collectors = collectors::collectors(args, header);
+    let mut registered = args
+        .collectors
+        .iter()
+        .map(|factory| factory.create())
+        .collect::<Vec<_>>();
+    // target_names() can't be used on a header without targets (e.g. unaligned reads)
+    let target_names = if header.target_count() == 0 {
+        vec![]
+    } else {
+        header
+            .target_names()
+            .iter()
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect::<Vec<String>>()
+    };
+    // with --contigs, only the aligned bases on the selected contigs are used for the coverage
+    let selected_contigs = args.contigs.as_ref().map(|contigs| {
+        target_names
+            .iter()
+            .map(|name| contigs.is_match(name))
+            .collect::<Vec<bool>>()
+    });
+    let mut coverage_yield = 0;
+    // the arrow file and read table are written while reading, in batches
+    let arrow_path = args.arrow.as_deref().unwrap_or_default();
+    let mut feather = args
+        .arrow
+        .as_ref()
+        .map(|path| FeatherWriter::new(path, feather::schema_metadata(input, args)))
+        .transpose()
+        .map_err(|err| CraminoError::output(arrow_path, err))?;
+    let read_table_path = args.read_table.as_deref().unwrap_or_default();
+    let mut read_table = args
+        .read_table
+        .as_ref()
+        .map(|path| {
+            ReadTableWriter::new(
+                path,
+                &args.read_table_tags,
+                feather::schema_metadata(input, args),
+            )
+        })
+        .transpose()
+        .map_err(|err| CraminoError::output(read_table_path, err))?;
     let min_read_len = args.min_read_len;
     // the match statement below is a bit ugly, but it is the only way to get a closure
     // that closure is used for filtering the reads
@@ -134,104 +312,241 @@ pub fn extract(args: &crate::Cli) -> (Data, rust_htslib::bam::Header) {
         (false, _) | (true, _) => unreachable!(),
     };
     let mut all_counts = 0;
-    for read in bam
-        .rc_records()
-        .map(|r| r.expect("Failure parsing Bam file"))
-        .filter(|record| record.flags() & (htslib::BAM_FSECONDARY) as u16 == 0)
-        .inspect(|_| all_counts += 1)
-        .filter(|read| filter_closure(read))
-    {
-        let read_length = read.seq_len() as u128 - softclipped_bases(&read);
-        lengths.push(read_length);
-        if !read.is_supplementary() {
-            num_reads += 1;
+    let mut skipped_records = 0;
+    let mut skipped_errors = Vec::new();
+    let mut records_processed = 0;
+    // (tid, pos) of the last aligned record, to tell how far a truncated file was read
+    let mut last_position = None;
+    let mut read_error = None;
+    let mut progress = args.progress.then(|| Progress::new(input, header));
+    for record in records {
+        let (record, offset) = match record {
+            Ok(record) => record,
+            Err(err) => {
+                read_error = Some(err);
+                break;
+            }
+        };
+        let read: &bam::Record = record.borrow();
+        records_processed += 1;
+        if read.tid() >= 0 {
+            last_position = Some((read.tid(), read.pos()));
         }
-        if args.karyotype || args.phased {
-            tids.push(read.tid());
+        if let Some(progress) = &mut progress {
+            progress.update(read, offset);
         }
-        if args.phased {
-            starts.push(read.pos());
-            ends.push(read.reference_end());
-            phasesets.push(get_phaseset(&read));
+        if read.flags() & (htslib::BAM_FSECONDARY) as u16 != 0 {
+            continue;
         }
-        if args.spliced {
-            exons.push(get_exon_number(&read));
+        all_counts += 1;
+        if !filter_closure(read) {
+            continue;
         }
-        if args.ubam {
+        let identity = if args.ubam {
             // For unmapped reads, estimate accuracy from per-base Q-scores
-            let accuracy = qscore_to_accuracy(&read);
-            identities.push(accuracy);
-            if hist_requested {
-                let phred = crate::utils::accuracy_to_phred(accuracy);
-                let index = if phred < 40 { phred } else { 40 };
-                q_score_counts[index] += 1;
-                q_score_bases[index] += read_length;
-            }
+            Ok(qscore_to_accuracy(read))
         } else {
-            let identity = gap_compressed_identity(read);
-            identities.push(identity);
-            if hist_requested {
-                let phred = crate::utils::accuracy_to_phred(identity);
-                let index = if phred < 40 { phred } else { 40 };
-                q_score_counts[index] += 1;
-                q_score_bases[index] += read_length;
+            gap_compressed_identity(read)
+        };
+        // the PS tag is only used for --phased and the read table
+        let phaseset = if args.phased || read_table.is_some() {
+            get_phaseset(read)
+        } else {
+            Ok(None)
+        };
+        let (identity, phaseset) = match identity.and_then(|identity| Ok((identity, phaseset?))) {
+            Ok(values) => values,
+            Err(message) => {
+                let err = CraminoError::InvalidRecord {
+                    read_name: String::from_utf8_lossy(read.qname()).to_string(),
+                    offset,
+                    message,
+                };
+                // with --lenient, invalid records are skipped and reported in the warnings
+                if !args.lenient {
+                    return Err(err);
+                }
+                warn!("Skipping {err}");
+                skipped_records += 1;
+                if skipped_errors.len() < MAX_SKIPPED_ERRORS {
+                    skipped_errors.push(err.to_string());
+                }
+                continue;
             }
+        };
+        let read_length = read.seq_len() as u128 - softclipped_bases(read);
+        if let Some(selected_contigs) = &selected_contigs
+            && read.tid() >= 0
+            && selected_contigs[read.tid() as usize]
+        {
+            coverage_yield += read_length;
+        }
+        if !read.is_supplementary() {
+            num_reads += 1;
+        }
+        let read_info = ReadInfo {
+            length: read_length,
+            identity,
+        };
+        for collector in &mut collectors {
+            collector.observe(read, &read_info);
         }
+        for collector in &mut registered {
+            collector.observe(read, &read_info);
+        }
+        if let Some(feather) = &mut feather {
+            feather
+                .push(read_length as u64, identity)
+                .map_err(|err| CraminoError::output(arrow_path, err))?;
+        }
+        if let Some(read_table) = &mut read_table {
+            // for unaligned reads the identity is only estimated from the base qualities (mean_base_q)
+            read_table
+                .push(read_table_row(
+                    read,
+                    read_length,
+                    if args.ubam { None } else { Some(identity) },
+                    phaseset,
+                    &target_names,
+                    &args.read_table_tags,
+                ))
+                .map_err(|err| CraminoError::output(read_table_path, err))?;
+        }
+    }
+    if let Some(read_table) = read_table {
+        read_table
+            .finish()
+            .map_err(|err| CraminoError::output(read_table_path, err))?;
+    }
+    if let Some(feather) = feather {
+        feather
+            .finish()
+            .map_err(|err| CraminoError::output(arrow_path, err))?;
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
     }
-    if let Some(s) = &args.arrow {
-        match args.ubam {
-            true => crate::feather::save_as_arrow_ubam(
-                s.to_string(),
-                lengths.iter().map(|x| *x as u64).collect(),
-                identities.clone(),
-            ),
-            false => crate::feather::save_as_arrow(
-                s.to_string(),
-                lengths.iter().map(|x| *x as u64).collect(),
-                identities.clone(),
-            ),
+    let reason = match read_error {
+        Some(err) => Some(err.to_string()),
+        None => {
+            missing_eof.then(|| "the EOF marker is absent, the file may be truncated".to_string())
         }
+    };
+    let incomplete = reason.map(|reason| Incomplete {
+        reason,
+        records_processed,
+        last_position: last_position
+            .map(|(tid, pos)| format!("{}:{}", target_names[tid as usize], pos + 1)),
+    });
+
+    let mut data = Data {
+        coverage_yield: args.contigs.as_ref().map(|_| coverage_yield),
+        num_reads,
+        all_counts,
+        is_ubam: args.ubam,
+        skipped_records,
+        skipped_errors,
+        incomplete,
+        collectors: registered,
+        ..Default::default()
+    };
+    for collector in collectors {
+        collector.finalize(&mut data);
     }
+    Ok(data)
+}
 
-    // sort vectors in descending order (required for N50/N75)
-    lengths.par_sort_unstable_by(|a, b| b.cmp(a));
-    identities.par_sort_unstable_by(|a, b| b.partial_cmp(a).unwrap());
-    (
-        Data {
-            lengths: Some(lengths),
-            num_reads,
-            all_counts,
-            identities: Some(identities),
-            q_score_hist: if hist_requested {
-                Some(QScoreHistogramData {
-                    counts: q_score_counts,
-                    bases: q_score_bases,
-                })
-            } else {
-                None
-            },
-            tids: if args.karyotype || args.phased {
-                Some(tids)
-            } else {
-                None
-            },
-            starts: if args.phased { Some(starts) } else { None },
-            ends: if args.phased { Some(ends) } else { None },
-            phasesets: if args.phased { Some(phasesets) } else { None },
-            exons: if args.spliced { Some(exons) } else { None },
-            is_ubam: args.ubam,
+/// Collects the columns of the per-read table (--read-table) for a record
+fn read_table_row(
+    record: &bam::Record,
+    aligned_length: u128,
+    identity: Option<f64>,
+    phaseset: Option<u32>,
+    target_names: &[String],
+    tags: &[String],
+) -> ReadRow {
+    let mapped = record.tid() >= 0 && !record.is_unmapped();
+    let accuracy = qscore_to_accuracy(record);
+    ReadRow {
+        name: String::from_utf8_lossy(record.qname()).to_string(),
+        flag: record.flags(),
+        contig: if mapped {
+            target_names.get(record.tid() as usize).cloned()
+        } else {
+            None
         },
-        header,
-    )
+        position: if mapped { Some(record.pos() + 1) } else { None },
+        strand: if !mapped {
+            None
+        } else if record.is_reverse() {
+            Some('-')
+        } else {
+            Some('+')
+        },
+        mapq: record.mapq(),
+        query_length: (record.seq_len() as u128 + hardclipped_bases(record)) as u64,
+        aligned_length: aligned_length as u64,
+        identity,
+        mean_base_q: if accuracy > 0.0 {
+            Some(-10.0 * (1.0 - accuracy / 100.0).log10())
+        } else {
+            None
+        },
+        read_group: match record.aux(b"RG") {
+            Ok(Aux::String(rg)) => Some(rg.to_string()),
+            _ => None,
+        },
+        phaseset,
+        haplotype: match get_haplotype(record) {
+            0 => None,
+            hp => Some(hp),
+        },
+        exons: if mapped {
+            Some(get_exon_number(record) as u32)
+        } else {
+            None
+        },
+        tags: tags
+            .iter()
+            .map(|tag| record.aux(tag.as_bytes()).ok().map(aux_to_string))
+            .collect(),
+    }
+}
+
+/// Formats the value of an aux tag as in SAM, with array values separated by commas
+fn aux_to_string(aux: Aux) -> String {
+    fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+        values.map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+    }
+    match aux {
+        Aux::Char(v) => (v as char).to_string(),
+        Aux::I8(v) => v.to_string(),
+        Aux::U8(v) => v.to_string(),
+        Aux::I16(v) => v.to_string(),
+        Aux::U16(v) => v.to_string(),
+        Aux::I32(v) => v.to_string(),
+        Aux::U32(v) => v.to_string(),
+        Aux::Float(v) => v.to_string(),
+        Aux::Double(v) => v.to_string(),
+        Aux::String(v) | Aux::HexByteArray(v) => v.to_string(),
+        Aux::ArrayI8(a) => join(a.iter()),
+        Aux::ArrayU8(a) => join(a.iter()),
+        Aux::ArrayI16(a) => join(a.iter()),
+        Aux::ArrayU16(a) => join(a.iter()),
+        Aux::ArrayI32(a) => join(a.iter()),
+        Aux::ArrayU32(a) => join(a.iter()),
+        Aux::ArrayFloat(a) => join(a.iter()),
+    }
 }
 
 /// Calculates the gap-compressed identity
 /// based on https://lh3.github.io/2018/11/25/on-the-definition-of-sequence-identity
 /// recent minimap2 version have that as the de tag
 /// if that is not present it is calculated from CIGAR and NM
-fn gap_compressed_identity(record: std::rc::Rc<rust_htslib::bam::Record>) -> f64 {
-    match get_de_tag(&record) {
-        Some(v) => v as f64,
+fn gap_compressed_identity(record: &bam::Record) -> Result<f64, String> {
+    match get_de_tag(record)? {
+        Some(v) => Ok(v as f64),
         None => {
             let mut matches = 0;
             let mut gap_size = 0;
@@ -248,10 +563,10 @@ fn gap_compressed_identity(record: std::rc::Rc<rust_htslib::bam::Record>) -> f64
                     _ => (),
                 }
             }
-            100.0
-                * (1.0
-                    - ((get_nm_tag(&record) - gap_size + gap_count) as f64
-                        / (matches + gap_count) as f64))
+            let mismatches = (get_nm_tag(record)? + gap_count)
+                .checked_sub(gap_size)
+                .ok_or("NM tag is smaller than the number of inserted and deleted bases")?;
+            Ok(100.0 * (1.0 - (mismatches as f64 / (matches + gap_count) as f64)))
         }
     }
 }
@@ -278,68 +593,62 @@ fn qscore_to_accuracy(record: &bam::Record) -> f64 {
     100.0 * sum_accuracy / quals.len() as f64
 }
 
-fn get_nm_tag(record: &bam::Record) -> u32 {
-    match record.aux(b"NM") {
-        Ok(value) => match value {
-            Aux::U8(v) => u32::from(v),
-            Aux::U16(v) => u32::from(v),
-            Aux::U32(v) => v,
-            Aux::I8(v) => u32::try_from(v).expect("Identified a negative NM tag"),
-            Aux::I16(v) => u32::try_from(v).expect("Identified a negative NM tag"),
-            Aux::I32(v) => u32::try_from(v).expect("Identified a negative NM tag"),
-            _ => panic!("Unexpected type of Aux for NM tag: {:?}", value),
-        },
-        Err(_e) => panic!("Unexpected result while trying to access the NM tag"),
-    }
+fn get_nm_tag(record: &bam::Record) -> Result<u32, String> {
+    let nm = match record.aux(b"NM") {
+        Ok(Aux::U8(v)) => i64::from(v),
+        Ok(Aux::U16(v)) => i64::from(v),
+        Ok(Aux::U32(v)) => i64::from(v),
+        Ok(Aux::I8(v)) => i64::from(v),
+        Ok(Aux::I16(v)) => i64::from(v),
+        Ok(Aux::I32(v)) => i64::from(v),
+        Ok(value) => return Err(format!("unexpected type of NM tag: {value:?}")),
+        Err(_) => return Err("no NM or de tag to calculate the identity".to_string()),
+    };
+    u32::try_from(nm).map_err(|_| format!("negative NM tag: {nm}"))
 }
 
 /// Get the de:f tag from minimap2, which is the gap compressed sequence divergence
 /// Which is converted into percent identity with 100 * (1 - de)
 /// This tag can be absent if the aligner version is not quite recent
-fn get_de_tag(record: &bam::Record) -> Option<f32> {
+fn get_de_tag(record: &bam::Record) -> Result<Option<f32>, String> {
     match record.aux(b"de") {
-        Ok(value) => match value {
-            Aux::Float(v) => Some(100.0 * (1.0 - v)),
-            _ => panic!("Unexpected type of Aux for de tag: {:?}", value),
-        },
-        Err(_e) => None,
+        Ok(Aux::Float(v)) => Ok(Some(100.0 * (1.0 - v))),
+        Ok(value) => Err(format!("unexpected type of de tag: {value:?}")),
+        Err(_e) => Ok(None),
     }
 }
 
-fn get_phaseset(record: &bam::Record) -> Option<u32> {
-    match record.aux(b"PS") {
-        Ok(value) => match value {
-            Aux::U8(v) => Some(u32::from(v)),
-            Aux::U16(v) => Some(u32::from(v)),
-            Aux::U32(v) => Some(v),
-            Aux::I8(v) => Some(u32::try_from(v).unwrap_or_else(|_| {
-                panic!(
-                    "Invalid: Identified a negative PS tag at {}",
-                    std::str::from_utf8(record.qname())
-                        .expect("Failed to convert read name to string")
-                )
-            })),
-            Aux::I16(v) => Some(u32::try_from(v).unwrap_or_else(|_| {
-                panic!(
-                    "Invalid: Identified a negative PS tag at {}",
-                    std::str::from_utf8(record.qname())
-                        .expect("Failed to convert read name to string")
-                )
-            })),
-            Aux::I32(v) => Some(u32::try_from(v).unwrap_or_else(|_| {
-                panic!(
-                    "Invalid: Identified a negative PS tag at {}",
-                    std::str::from_utf8(record.qname())
-                        .expect("Failed to convert read name to string")
-                )
-            })),
-            _ => panic!("Unexpected type of Aux for phaseset: {:?}", value),
-        },
-        Err(_e) => None,
+pub(crate) fn get_phaseset(record: &bam::Record) -> Result<Option<u32>, String> {
+    let phaseset = match record.aux(b"PS") {
+        Ok(Aux::U8(v)) => i64::from(v),
+        Ok(Aux::U16(v)) => i64::from(v),
+        Ok(Aux::U32(v)) => i64::from(v),
+        Ok(Aux::I8(v)) => i64::from(v),
+        Ok(Aux::I16(v)) => i64::from(v),
+        Ok(Aux::I32(v)) => i64::from(v),
+        Ok(value) => return Err(format!("unexpected type of PS tag: {value:?}")),
+        Err(_e) => return Ok(None),
+    };
+    u32::try_from(phaseset)
+        .map(Some)
+        .map_err(|_| format!("negative PS tag: {phaseset}"))
+}
+
+/// Get the HP tag (haplotype), as set by e.g. whatshap haplotag
+/// Returns 0 if the tag is absent or not an integer
+pub(crate) fn get_haplotype(record: &bam::Record) -> u8 {
+    match record.aux(b"HP") {
+        Ok(Aux::U8(v)) => v,
+        Ok(Aux::U16(v)) => u8::try_from(v).unwrap_or(0),
+        Ok(Aux::U32(v)) => u8::try_from(v).unwrap_or(0),
+        Ok(Aux::I8(v)) => u8::try_from(v).unwrap_or(0),
+        Ok(Aux::I16(v)) => u8::try_from(v).unwrap_or(0),
+        Ok(Aux::I32(v)) => u8::try_from(v).unwrap_or(0),
+        _ => 0,
     }
 }
 
-fn get_exon_number(record: &bam::Record) -> usize {
+pub(crate) fn get_exon_number(record: &bam::Record) -> usize {
     let mut exon_count = 1;
 
     for op in record.cigar().iter() {
@@ -351,10 +660,48 @@ fn get_exon_number(record: &bam::Record) -> usize {
     exon_count
 }
 
+/// Collects the length of each intron (RefSkip operation) and of each aligned exon block
+/// An exon block is the reference span (M, =, X and D operations) between two introns
+/// or between an intron and the end of the alignment
+/// Unspliced alignments are skipped, as their single block is just the aligned part of the read
+pub(crate) fn get_splice_blocks(
+    record: &bam::Record,
+    introns: &mut Vec<u32>,
+    exon_blocks: &mut Vec<u32>,
+) {
+    let cigar = record.cigar();
+    if !cigar.iter().any(|op| matches!(op, Cigar::RefSkip(_))) {
+        return;
+    }
+    let mut block = 0;
+    for op in cigar.iter() {
+        match op {
+            Cigar::Match(len) | Cigar::Equal(len) | Cigar::Diff(len) | Cigar::Del(len) => {
+                block += *len;
+            }
+            Cigar::RefSkip(len) => {
+                introns.push(*len);
+                if block > 0 {
+                    exon_blocks.push(block);
+                }
+                block = 0;
+            }
+            _ => (),
+        }
+    }
+    if block > 0 {
+        exon_blocks.push(block);
+    }
+}
+
 fn softclipped_bases(read: &bam::Record) -> u128 {
     (read.cigar().leading_softclips() + read.cigar().trailing_softclips()) as u128
 }
 
+pub(crate) fn hardclipped_bases(read: &bam::Record) -> u128 {
+    (read.cigar().leading_hardclips() + read.cigar().trailing_hardclips()) as u128
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -412,6 +759,56 @@ mod tests {
         assert!((accuracy - expected).abs() < 0.01);
     }
 
+    #[test]
+    fn test_splice_blocks() {
+        let mut record = bam::Record::new();
+        let cigar = bam::record::CigarString(vec![
+            Cigar::SoftClip(5),
+            Cigar::Match(100),
+            Cigar::Del(2),
+            Cigar::Match(20),
+            Cigar::RefSkip(1500),
+            Cigar::Match(80),
+            Cigar::Ins(3),
+            Cigar::Match(50),
+            Cigar::RefSkip(300),
+            Cigar::Match(40),
+        ]);
+        let seq = vec![b'A'; 298];
+        let qual = vec![30u8; 298];
+        record.set(b"test_read", Some(&cigar), &seq, &qual);
+
+        let mut introns = vec![];
+        let mut exon_blocks = vec![];
+        get_splice_blocks(&record, &mut introns, &mut exon_blocks);
+        assert_eq!(introns, vec![1500, 300]);
+        assert_eq!(exon_blocks, vec![122, 130, 40]);
+        assert_eq!(get_exon_number(&record), 3);
+
+        // unspliced alignments don't contribute exon blocks
+        let mut unspliced = bam::Record::new();
+        let cigar = bam::record::CigarString(vec![Cigar::Match(50)]);
+        unspliced.set(b"unspliced", Some(&cigar), &[b'A'; 50], &[30u8; 50]);
+        get_splice_blocks(&unspliced, &mut introns, &mut exon_blocks);
+        assert_eq!(introns.len(), 2);
+        assert_eq!(exon_blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_clipped_bases() {
+        let mut record = bam::Record::new();
+        let cigar = bam::record::CigarString(vec![
+            Cigar::HardClip(100),
+            Cigar::SoftClip(5),
+            Cigar::Match(40),
+            Cigar::SoftClip(10),
+            Cigar::HardClip(20),
+        ]);
+        record.set(b"clipped", Some(&cigar), &[b'A'; 55], &[30u8; 55]);
+        assert_eq!(softclipped_bases(&record), 15);
+        assert_eq!(hardclipped_bases(&record), 120);
+    }
+
     #[test]
     fn test_qscore_to_accuracy_missing_quality() {
         // Create a test record with missing quality (all 255)
@@ -1,4 +1,5 @@
-use arrow::datatypes::{DataType, Field, Schema};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use std::collections::HashMap;
 use std::fs::File;
 use std::sync::Arc;
 
@@ -9,40 +10,121 @@ use arrow::{
     record_batch::RecordBatch,
 };
 
-pub fn save_as_arrow(filename: String, lengths: Vec<u64>, identities: Vec<f64>) {
-    let identities_array = Arc::new(Float64Array::from(identities)) as _;
-    let lengths_array = Arc::new(UInt64Array::from(lengths)) as _;
-    let batch =
-        RecordBatch::try_from_iter([("identities", identities_array), ("lengths", lengths_array)])
-            .unwrap();
+/// Number of reads per record batch, to avoid holding a copy of all data in memory
+const BATCH_SIZE: usize = 65_536;
 
-    let schema = Schema::new(vec![
-        Field::new("identities", DataType::Float64, false),
-        Field::new("lengths", DataType::UInt64, false),
-    ]);
-    let buffer = File::create(filename).expect("create file error");
-
-    let mut writer = FileWriter::try_new(buffer, &schema).expect("create arrow file writer error");
+/// Metadata embedded in the schema of the arrow output,
+/// to know how a file was created when it is used downstream (e.g. NanoPlot)
+pub fn schema_metadata(input: &str, args: &crate::QcOptions) -> HashMap<String, String> {
+    HashMap::from([
+        (
+            "cramino_version".to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+        ("input".to_string(), input.to_string()),
+        ("min_read_len".to_string(), args.min_read_len.to_string()),
+        // secondary alignments are always skipped, unmapped reads unless --ubam is used
+        (
+            "filters".to_string(),
+            if args.ubam {
+                "secondary".to_string()
+            } else {
+                "secondary,unmapped".to_string()
+            },
+        ),
+        // for unaligned reads the identities are estimated from the base qualities
+        (
+            "identities".to_string(),
+            if args.ubam {
+                "estimated".to_string()
+            } else {
+                "gap-compressed".to_string()
+            },
+        ),
+    ])
+}
 
-    writer.write(&batch).expect("write arrow batch error");
-    writer.finish().expect("finish write arrow error");
+/// Writes the lengths and identities of the reads to an arrow (feather) file,
+/// in record batches while the reads are processed
+pub struct FeatherWriter {
+    writer: FileWriter<File>,
+    schema: SchemaRef,
+    lengths: Vec<u64>,
+    identities: Vec<f64>,
 }
 
-pub fn save_as_arrow_ubam(filename: String, lengths: Vec<u64>, qualities: Vec<f64>) {
-    let qualities_array = Arc::new(Float64Array::from(qualities)) as _;
-    let lengths_array = Arc::new(UInt64Array::from(lengths)) as _;
-    let batch =
-        RecordBatch::try_from_iter([("identities", qualities_array), ("lengths", lengths_array)])
-            .unwrap();
+impl FeatherWriter {
+    pub fn new(
+        filename: &str,
+        metadata: HashMap<String, String>,
+    ) -> Result<Self, arrow::error::ArrowError> {
+        let schema = Arc::new(
+            Schema::new(vec![
+                Field::new("identities", DataType::Float64, false),
+                Field::new("lengths", DataType::UInt64, false),
+            ])
+            .with_metadata(metadata),
+        );
+        let buffer = File::create(filename)?;
+        let writer = FileWriter::try_new(buffer, &schema)?;
+        Ok(FeatherWriter {
+            writer,
+            schema,
+            lengths: Vec::with_capacity(BATCH_SIZE),
+            identities: Vec::with_capacity(BATCH_SIZE),
+        })
+    }
+
+    pub fn push(&mut self, length: u64, identity: f64) -> Result<(), arrow::error::ArrowError> {
+        self.lengths.push(length);
+        self.identities.push(identity);
+        if self.lengths.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), arrow::error::ArrowError> {
+        if self.lengths.is_empty() {
+            return Ok(());
+        }
+        let identities_array = Arc::new(Float64Array::from(std::mem::take(&mut self.identities)));
+        let lengths_array = Arc::new(UInt64Array::from(std::mem::take(&mut self.lengths)));
+        let batch =
+            RecordBatch::try_new(self.schema.clone(), vec![identities_array, lengths_array])?;
+        self.writer.write(&batch)
+    }
+
+    pub fn finish(mut self) -> Result<(), arrow::error::ArrowError> {
+        self.flush()?;
+        self.writer.finish()
+    }
+}
 
-    let schema = Schema::new(vec![
-        Field::new("identities", DataType::Float64, false),
-        Field::new("lengths", DataType::UInt64, false),
-    ]);
-    let buffer = File::create(filename).expect("create file error");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::ipc::reader::FileReader;
 
-    let mut writer = FileWriter::try_new(buffer, &schema).expect("create arrow file writer error");
+    #[test]
+    fn test_feather_batches_and_metadata() {
+        let path = std::env::temp_dir().join("cramino-feather-test.arrow");
+        let metadata = HashMap::from([("input".to_string(), "test.bam".to_string())]);
+        let mut writer = FeatherWriter::new(path.to_str().unwrap(), metadata).unwrap();
+        let num_reads = 2 * BATCH_SIZE + 1;
+        for index in 0..num_reads {
+            writer.push(index as u64, 99.0).unwrap();
+        }
+        writer.finish().unwrap();
 
-    writer.write(&batch).expect("write arrow batch error");
-    writer.finish().expect("finish write arrow error");
+        let reader = FileReader::try_new(File::open(&path).unwrap(), None).unwrap();
+        assert_eq!(reader.schema().metadata()["input"], "test.bam");
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+            num_reads
+        );
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -1,11 +1,17 @@
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::cmp::max;
 use std::fs::File;
 use std::io::{self, Write};
 
+use crate::sketch::LengthSketch;
 use crate::{extract_from_bam, metrics, utils};
 
-struct LengthHistogramData {
+/// Number of values and their sum in bins of a fixed step up to max_value, and of the values
+/// above it in an overflow bin
+/// Unlike the values themselves these have a bounded size, so with --sketch they are kept in the sketches
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LengthHistogramData {
     step: u64,
     max_value: u64,
     counts: Vec<u64>,
@@ -14,58 +20,194 @@ struct LengthHistogramData {
     overflow_bases: u128,
 }
 
+impl LengthHistogramData {
+    pub fn new(step: u64, max_value: u64) -> Self {
+        let step_count = (max_value / step) as usize;
+        LengthHistogramData {
+            step,
+            max_value,
+            counts: vec![0; step_count],
+            bases: vec![0; step_count],
+            overflow_count: 0,
+            overflow_bases: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: u128) {
+        self.add_count(value, 1);
+    }
+
+    /// Adds a number of values that are the same
+    pub fn add_count(&mut self, value: u128, count: u64) {
+        if value >= self.max_value as u128 {
+            self.overflow_count += count;
+            self.overflow_bases += value * count as u128;
+        } else {
+            let index = (value / self.step as u128) as usize;
+            self.counts[index] += count;
+            self.bases[index] += value * count as u128;
+        }
+    }
+
+    /// Adds the values of another histogram, which has to have the same bins
+    pub fn merge(&mut self, other: &LengthHistogramData) -> Result<(), String> {
+        if (self.step, self.max_value) != (other.step, other.max_value) {
+            return Err(format!(
+                "Can't merge histograms with different bins ({} up to {} and {} up to {})",
+                self.step, self.max_value, other.step, other.max_value
+            ));
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        for (bases, other_bases) in self.bases.iter_mut().zip(&other.bases) {
+            *bases += other_bases;
+        }
+        self.overflow_count += other.overflow_count;
+        self.overflow_bases += other.overflow_bases;
+        Ok(())
+    }
+
+    /// Lowers max_value to a multiple of the step, the bins from the new max_value on are added to the overflow bin
+    fn truncate(&mut self, max_value: u64) {
+        let step_count = (max_value / self.step) as usize;
+        if step_count >= self.counts.len() {
+            return;
+        }
+        self.overflow_count += self.counts.drain(step_count..).sum::<u64>();
+        self.overflow_bases += self.bases.drain(step_count..).sum::<u128>();
+        self.max_value = max_value;
+    }
+
+    fn total_count(&self) -> u64 {
+        self.counts.iter().sum::<u64>() + self.overflow_count
+    }
+}
+
+// read lengths are counted in bins of 2 kb up to 60 kb, the histogram ends at
+// the maximum read length rounded up to 10 kb
+pub const LENGTH_STEP: u64 = 2000;
+pub const LENGTH_MAX: u64 = 60_000;
+
 fn compute_length_histogram_data(array: &[u128]) -> Option<LengthHistogramData> {
-    if array.is_empty() {
+    let max_read_length = array.iter().copied().max()?;
+    let mut hist = compute_fixed_histogram_data(array.iter().copied(), LENGTH_STEP, LENGTH_MAX);
+    hist.truncate(length_histogram_max(max_read_length));
+    Some(hist)
+}
+
+/// The read length histogram of a sketch, which is the same as that of all lengths
+fn sketch_length_histogram_data(sketch: &LengthSketch) -> Option<LengthHistogramData> {
+    if sketch.is_empty() {
         return None;
     }
+    let mut hist = sketch.histogram().clone();
+    hist.truncate(length_histogram_max(sketch.max()));
+    Some(hist)
+}
 
-    // dynamically set the maximum value based on the maximum read length, capped at 60k
-    let max_read_length = array
-        .iter()
-        .copied()
-        .max()
-        .expect("Array is empty, cannot find max");
-    let max_value = std::cmp::min(
-        60_000u64,
+// dynamically set the maximum value based on the maximum read length, capped at 60k
+fn length_histogram_max(max_read_length: u128) -> u64 {
+    std::cmp::min(
+        LENGTH_MAX,
         ((max_read_length as f64 / 10_000.0).ceil() as u64) * 10_000,
-    );
-    let step = 2000u64;
-    let step_count = (max_value / step) as usize;
-    let mut counts = vec![0u64; step_count];
-    let mut bases = vec![0u128; step_count];
-    let mut overflow_count = 0u64;
-    let mut overflow_bases = 0u128;
-
-    for &value in array {
-        if value >= max_value as u128 {
-            overflow_count += 1;
-            overflow_bases += value;
-        } else {
-            let index = (value / step as u128) as usize;
-            counts[index] += 1;
-            bases[index] += value;
+    )
+}
+
+/// The read length histogram, of all lengths or with --sketch of the length sketch
+fn read_length_histogram_data(
+    metrics_data: &extract_from_bam::Data,
+) -> Option<LengthHistogramData> {
+    match (&metrics_data.lengths, &metrics_data.length_sketch) {
+        (Some(lengths), _) => compute_length_histogram_data(lengths),
+        (None, Some(sketch)) => sketch_length_histogram_data(sketch),
+        (None, None) => None,
+    }
+}
+
+// intron and exon block lengths use fixed bins, with an overflow bin for everything above max_value
+// spuriously long introns (e.g. from a misconfigured aligner) end up in the overflow bin
+pub const INTRON_STEP: u64 = 1000;
+pub const INTRON_MAX: u64 = 100_000;
+pub const EXON_BLOCK_STEP: u64 = 50;
+pub const EXON_BLOCK_MAX: u64 = 2000;
+// the scale of the length of phase blocks is hard to predict, this leaves room for long phase blocks
+const PHASEBLOCK_STEP: u64 = 10_000;
+const PHASEBLOCK_MAX: u64 = 1_000_000;
+const EXON_COUNT_MAX: u64 = 15;
+
+fn compute_fixed_histogram_data(
+    values: impl IntoIterator<Item = impl Into<u128>>,
+    step: u64,
+    max_value: u64,
+) -> LengthHistogramData {
+    let mut hist = LengthHistogramData::new(step, max_value);
+    for value in values {
+        hist.add(value.into());
+    }
+    hist
+}
+
+/// Number of exons per read, of all reads or with --sketch of the splice sketch
+fn exon_count_histogram_data(metrics_data: &extract_from_bam::Data) -> Option<LengthHistogramData> {
+    match (&metrics_data.exons, &metrics_data.splice_sketch) {
+        (Some(exons), _) => Some(compute_fixed_histogram_data(
+            exons.iter().map(|&count| count as u64),
+            1,
+            EXON_COUNT_MAX,
+        )),
+        (None, Some(sketch)) => {
+            let mut hist = LengthHistogramData::new(1, EXON_COUNT_MAX);
+            for (exons, count) in sketch.exon_counts() {
+                hist.add_count(exons as u128, count);
+            }
+            Some(hist)
         }
+        (None, None) => None,
     }
+}
 
-    Some(LengthHistogramData {
-        step,
-        max_value,
-        counts,
-        bases,
-        overflow_count,
-        overflow_bases,
-    })
+/// Intron lengths, of all introns or with --sketch of the splice sketch
+fn intron_length_histogram_data(
+    metrics_data: &extract_from_bam::Data,
+) -> Option<LengthHistogramData> {
+    match (&metrics_data.intron_lengths, &metrics_data.splice_sketch) {
+        (Some(introns), _) => Some(compute_fixed_histogram_data(
+            introns.iter().copied(),
+            INTRON_STEP,
+            INTRON_MAX,
+        )),
+        (None, Some(sketch)) => Some(sketch.intron_lengths().histogram().clone()),
+        (None, None) => None,
+    }
 }
 
-fn build_length_histogram(array: &[u128]) -> metrics::Histogram {
-    let Some(hist) = compute_length_histogram_data(array) else {
+/// Exon block lengths, of all exon blocks or with --sketch of the splice sketch
+fn exon_length_histogram_data(
+    metrics_data: &extract_from_bam::Data,
+) -> Option<LengthHistogramData> {
+    match (&metrics_data.exon_lengths, &metrics_data.splice_sketch) {
+        (Some(exons), _) => Some(compute_fixed_histogram_data(
+            exons.iter().copied(),
+            EXON_BLOCK_STEP,
+            EXON_BLOCK_MAX,
+        )),
+        (None, Some(sketch)) => Some(sketch.exon_lengths().histogram().clone()),
+        (None, None) => None,
+    }
+}
+
+fn build_length_histogram(hist: Option<LengthHistogramData>) -> metrics::Histogram {
+    let Some(hist) = hist else {
         return metrics::Histogram {
-            step: 2000,
+            step: LENGTH_STEP,
             max_value: 0,
             bins: Vec::new(),
         };
     };
-
+    histogram_from_data(hist)
+}
+fn histogram_from_data(hist: LengthHistogramData) -> metrics::Histogram {
     let mut bins = Vec::with_capacity(hist.counts.len() + 1);
     for (index, (count, bases)) in hist.counts.iter().zip(hist.bases.iter()).enumerate() {
         let start = index as u64 * hist.step;
@@ -124,32 +266,44 @@ fn build_qscore_histogram(hist: &extract_from_bam::QScoreHistogramData) -> metri
     }
 }
 
+/// Phase block lengths in 10 kb bins, with an overflow bin for phase blocks longer than 1 Mb
+pub fn build_phaseblock_histogram(phaseblock_lengths: &[i64]) -> metrics::Histogram {
+    histogram_from_data(compute_fixed_histogram_data(
+        phaseblock_lengths
+            .iter()
+            .map(|&length| length.max(0) as u64),
+        PHASEBLOCK_STEP,
+        PHASEBLOCK_MAX,
+    ))
+}
+
+/// Number of exons per read, with an overflow bin for reads with 15 or more exons
+/// The bases of the bins are the total number of exons
+pub fn build_exon_count_histogram(
+    metrics_data: &extract_from_bam::Data,
+) -> Option<metrics::Histogram> {
+    exon_count_histogram_data(metrics_data).map(histogram_from_data)
+}
+
 pub fn build_histograms(metrics_data: &extract_from_bam::Data) -> metrics::Histograms {
-    let read_length = metrics_data
-        .lengths
-        .as_ref()
-        .map(|lengths| build_length_histogram(lengths))
-        .unwrap_or(metrics::Histogram {
-            step: 2000,
-            max_value: 0,
-            bins: Vec::new(),
-        });
+    let read_length = build_length_histogram(read_length_histogram_data(metrics_data));
     let q_score = metrics_data
         .q_score_hist
         .as_ref()
         .map(build_qscore_histogram);
 
+    let intron_length = intron_length_histogram_data(metrics_data).map(histogram_from_data);
+    let exon_length = exon_length_histogram_data(metrics_data).map(histogram_from_data);
+
     metrics::Histograms {
         read_length,
         q_score,
+        intron_length,
+        exon_length,
     }
 }
 
-fn output_histogram_counts_tsv<W: Write>(array: &[u128], writer: &mut W, scaled: bool) {
-    let Some(hist) = compute_length_histogram_data(array) else {
-        return;
-    };
-
+fn output_histogram_counts_tsv<W: Write>(hist: &LengthHistogramData, writer: &mut W, scaled: bool) {
     let value_label = if scaled { "bases" } else { "count" };
     // Write TSV header with leading newline for formatting
     writeln!(writer, "\nbin_start\tbin_end\t{}", value_label)
@@ -189,17 +343,14 @@ fn output_histogram_counts_tsv<W: Write>(array: &[u128], writer: &mut W, scaled:
 // as well as for future customizations
 // in principle it would be possible to enable the user to change the step size or max value, but I don't want to add too many options to the CLI
 
-fn make_histogram_lengths<W: Write>(array: &[u128], writer: &mut W, scaled: bool) {
-    let Some(hist) = compute_length_histogram_data(array) else {
-        return;
-    };
+fn make_histogram_lengths<W: Write>(hist: &LengthHistogramData, writer: &mut W, scaled: bool) {
     let overflow = hist.overflow_count as usize;
     let overflow_bp = hist.overflow_bases;
     let dotsize = if scaled {
         let total_bp: u128 = hist.bases.iter().sum::<u128>() + overflow_bp;
         std::cmp::max((total_bp / 500) as usize, 1)
     } else {
-        std::cmp::max(array.len() / 500, 1)
+        std::cmp::max(hist.total_count() as usize / 500, 1)
     };
     writeln!(
         writer,
@@ -213,9 +364,9 @@ fn make_histogram_lengths<W: Write>(array: &[u128], writer: &mut W, scaled: bool
     .expect("Unable to write histogram");
     for (index, (entry, bp)) in hist.counts.iter().zip(hist.bases.iter()).enumerate() {
         let bar = if scaled {
-            "∎".repeat(((*bp as usize) / dotsize).max(0))
+            "∎".repeat((*bp as usize) / dotsize)
         } else {
-            "∎".repeat(((*entry as usize) / dotsize).max(0))
+            "∎".repeat((*entry as usize) / dotsize)
         };
         writeln!(
             writer,
@@ -231,9 +382,9 @@ fn make_histogram_lengths<W: Write>(array: &[u128], writer: &mut W, scaled: bool
     }
     if overflow > 0 {
         let bar = if scaled {
-            "∎".repeat(((overflow_bp as usize) / dotsize).max(0))
+            "∎".repeat((overflow_bp as usize) / dotsize)
         } else {
-            "∎".repeat((overflow / dotsize).max(0))
+            "∎".repeat(overflow / dotsize)
         };
         writeln!(writer, "{: >11} {}", format!("{}+", hist.max_value), bar)
             .expect("Unable to write histogram");
@@ -330,8 +481,8 @@ pub fn make_histogram_phaseblocks<W: Write>(array: &[i64], writer: &mut W) {
     // I may have to increase its max value in the future
     // this configuration seemed sufficient for a randomly picked test file phased with LongShot
     // but presumably other tools can do better, especially with longer reads, and therefore I left some room for longer phase blocks
-    let stepsize: i64 = 10000;
-    let max_value = 1_000_000;
+    let stepsize = PHASEBLOCK_STEP as i64;
+    let max_value = PHASEBLOCK_MAX as usize;
     let step_count = max_value / stepsize as usize;
     let mut counts = vec![0; step_count + 1];
     for value in array.iter() {
@@ -369,20 +520,17 @@ pub fn make_histogram_phaseblocks<W: Write>(array: &[i64], writer: &mut W) {
     .expect("Unable to write histogram");
 }
 
-fn make_histogram_exons<W: Write>(array: &[usize], writer: &mut W) {
-    let max_value = 15;
-    let step_count = max_value;
-    let mut counts = vec![0; step_count + 1];
-    for value in array.iter() {
-        if *value < max_value {
-            counts[*value] += 1;
-        }
-    }
+fn make_histogram_exons<W: Write>(hist: &LengthHistogramData, writer: &mut W) {
     // the last bin is for all values above the last step
-    counts[step_count] = array.len() - counts.iter().sum::<usize>();
+    let counts = hist
+        .counts
+        .iter()
+        .chain([&hist.overflow_count])
+        .map(|&count| count as usize)
+        .collect::<Vec<usize>>();
     // the dotsize variable determines how many reads are represented by a single dot
     // I either have to set this dynamically or experiment with it further
-    let dotsize = max(array.len() / 500, 1);
+    let dotsize = max(hist.total_count() as usize / 500, 1);
     writeln!(writer, "\n\n# Histogram for number of exons:").expect("Unable to write histogram");
     // print the second entry in the vector. The first entry is 0 exons, which is not used (empty)
     // 1 exon is renamed to unspliced
@@ -412,6 +560,33 @@ fn make_histogram_exons<W: Write>(array: &[usize], writer: &mut W) {
     .expect("Unable to write histogram");
 }
 
+fn make_histogram_splice_lengths<W: Write>(
+    title: &str,
+    hist: &LengthHistogramData,
+    writer: &mut W,
+) {
+    let (step, max_value) = (hist.step, hist.max_value);
+    // the dotsize variable determines how many introns/exon blocks are represented by a single dot
+    let dotsize = max(hist.total_count() as usize / 500, 1);
+    writeln!(writer, "\n\n# Histogram for {}:", title).expect("Unable to write histogram");
+    for (index, entry) in hist.counts.iter().enumerate() {
+        writeln!(
+            writer,
+            "{: >13} {}",
+            format!("{}-{}", index as u64 * step, (index as u64 + 1) * step),
+            "∎".repeat(*entry as usize / dotsize)
+        )
+        .expect("Unable to write histogram");
+    }
+    writeln!(
+        writer,
+        "{: >13} {}",
+        format!("{}+", max_value),
+        "∎".repeat(hist.overflow_count as usize / dotsize)
+    )
+    .expect("Unable to write histogram");
+}
+
 pub fn create_histograms(
     metrics_data: &extract_from_bam::Data,
     hist_file: &Option<String>,
@@ -423,12 +598,13 @@ pub fn create_histograms(
     } else {
         Box::new(io::stdout())
     };
-    if let Some(lengths) = &metrics_data.lengths {
-        make_histogram_lengths(lengths, &mut writer, scaled);
+    if let Some(hist) = read_length_histogram_data(metrics_data) {
+        make_histogram_lengths(&hist, &mut writer, scaled);
     }
-    if let Some(identities) = &metrics_data.identities {
+    // with --sketch, the identities are not kept and the histogram uses the Phred scores
+    if metrics_data.identities.is_some() || metrics_data.q_score_hist.is_some() {
         make_histogram_identities(
-            identities,
+            metrics_data.identities.as_deref().unwrap_or_default(),
             metrics_data.q_score_hist.as_ref(),
             &mut writer,
             scaled,
@@ -437,8 +613,18 @@ pub fn create_histograms(
     if let Some(phaseblocks) = phaseblocks {
         make_histogram_phaseblocks(&phaseblocks, &mut writer);
     }
-    if let Some(exons) = &metrics_data.exons {
-        make_histogram_exons(exons, &mut writer);
+    if let Some(hist) = exon_count_histogram_data(metrics_data) {
+        make_histogram_exons(&hist, &mut writer);
+    }
+    if let Some(hist) = intron_length_histogram_data(metrics_data)
+        && hist.total_count() > 0
+    {
+        make_histogram_splice_lengths("intron lengths", &hist, &mut writer);
+    }
+    if let Some(hist) = exon_length_histogram_data(metrics_data)
+        && hist.total_count() > 0
+    {
+        make_histogram_splice_lengths("exon block lengths", &hist, &mut writer);
     }
     Ok(())
 }
@@ -453,8 +639,8 @@ pub fn output_histogram_counts(
     } else {
         Box::new(io::stdout())
     };
-    if let Some(lengths) = &metrics_data.lengths {
-        output_histogram_counts_tsv(lengths, &mut writer, scaled);
+    if let Some(hist) = read_length_histogram_data(metrics_data) {
+        output_histogram_counts_tsv(&hist, &mut writer, scaled);
     }
     Ok(())
 }
@@ -479,19 +665,33 @@ mod tests {
 
         let data = extract_from_bam::Data {
             lengths: Some(lengths),
+            alignment_lengths: None,
+            num_split_reads: None,
+            coverage_yield: None,
+            query_lengths: None,
+            reference_spans: None,
             num_reads: 3,
             all_counts: 3,
             identities: Some(identities),
+            length_sketch: None,
+            identity_sketch: None,
             q_score_hist: Some(extract_from_bam::QScoreHistogramData {
                 counts: q_score_counts,
                 bases: q_score_bases,
             }),
-            tids: None,
-            starts: None,
-            ends: None,
-            phasesets: None,
+            tid_counts: None,
+            phaseblocks: None,
+            phased_range: None,
+            phase_counts: None,
             exons: None,
+            intron_lengths: None,
+            exon_lengths: None,
+            splice_sketch: None,
             is_ubam: false,
+            skipped_records: 0,
+            skipped_errors: Vec::new(),
+            incomplete: None,
+            collectors: Vec::new(),
         };
 
         let histograms = build_histograms(&data);
@@ -523,13 +723,47 @@ mod tests {
         assert!(json_value.get("histograms").is_some());
         assert!(json_value["histograms"]["read_length"]["bins"].is_array());
         assert!(json_value["histograms"]["q_score"]["bins"].is_array());
+        assert!(json_value["histograms"].get("intron_length").is_none());
+    }
+
+    #[test]
+    fn splice_length_histograms_use_fixed_bins_with_overflow() {
+        let introns = [80u32, 900, 1500, 250_000];
+        let hist = histogram_from_data(compute_fixed_histogram_data(
+            introns.iter().map(|&length| u64::from(length)),
+            INTRON_STEP,
+            INTRON_MAX,
+        ));
+        assert_eq!(hist.bins.len(), 101);
+        assert_eq!(hist.bins[0].count, 2);
+        assert_eq!(hist.bins[0].bases, 980);
+        assert_eq!(hist.bins[1].count, 1);
+        let overflow = hist.bins.last().unwrap();
+        assert_eq!(overflow.start, INTRON_MAX);
+        assert_eq!(overflow.end, None);
+        assert_eq!(overflow.count, 1);
+
+        let mut output = Vec::new();
+        make_histogram_splice_lengths(
+            "intron lengths",
+            &compute_fixed_histogram_data(introns.iter().copied(), INTRON_STEP, INTRON_MAX),
+            &mut output,
+        );
+        let output = String::from_utf8(output).expect("Output is valid UTF-8");
+        assert!(output.contains("# Histogram for intron lengths:"));
+        let overflow_line = output
+            .lines()
+            .find(|line| line.trim_start().starts_with("100000+"))
+            .expect("Missing overflow line");
+        assert_eq!(overflow_line.chars().filter(|&c| c == '∎').count(), 1);
     }
 
     #[test]
     fn histogram_counts_tsv_scaled_uses_bases() {
         let lengths = vec![1000u128, 3000, 5000];
         let mut output = Vec::new();
-        output_histogram_counts_tsv(&lengths, &mut output, true);
+        let hist = compute_length_histogram_data(&lengths).unwrap();
+        output_histogram_counts_tsv(&hist, &mut output, true);
 
         let output = String::from_utf8(output).expect("TSV output is valid UTF-8");
         let lines: Vec<&str> = output.trim().lines().collect();
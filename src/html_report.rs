@@ -0,0 +1,207 @@
+use crate::metrics;
+use crate::plots::{BarChart, escape};
+use serde_json::Value;
+use std::fmt::Write;
+
+const STYLE: &str =
+    "body { font-family: sans-serif; margin: 2em auto; max-width: 960px; color: #222; }
+h1 { border-bottom: 2px solid #4c72b0; padding-bottom: 0.2em; }
+h2 { margin-top: 1.5em; color: #4c72b0; }
+table { border-collapse: collapse; margin: 0.5em 0 1em 0; }
+th, td { border: 1px solid #ccc; padding: 0.25em 0.75em; text-align: left; }
+th { background: #f0f3f8; }
+td.number { text-align: right; font-variant-numeric: tabular-nums; }
+figure { margin: 1em 0; }";
+
+/// Writes a single HTML file with the metrics as tables and the plots as inline SVG,
+/// which can be viewed offline and attached to e.g. a ticket
+pub fn write_html(
+    path: &str,
+    metrics: &metrics::Metrics,
+    charts: &[BarChart],
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::write(path, render(metrics, charts)?)?;
+    Ok(())
+}
+
+fn render(
+    metrics: &metrics::Metrics,
+    charts: &[BarChart],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut html = String::new();
+    let name = escape(&metrics.file_info.name);
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>cramino report: {name}</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n<h1>cramino report: {name}</h1>"
+    )?;
+    writeln!(html, "<table>")?;
+    for (key, value) in [
+        ("File", metrics.file_info.name.as_str()),
+        ("Path", metrics.file_info.path.as_str()),
+        ("Creation time", metrics.file_info.creation_time.as_str()),
+        ("cramino version", env!("CARGO_PKG_VERSION")),
+    ] {
+        writeln!(html, "<tr><th>{key}</th><td>{}</td></tr>", escape(value))?;
+    }
+    writeln!(
+        html,
+        "<tr><th>Report created</th><td>{}</td></tr>\n</table>",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+    )?;
+
+    if let Value::Object(sections) = serde_json::to_value(metrics)? {
+        for (section, value) in &sections {
+            // the histograms are shown as plots
+            if section != "file_info" && section != "histograms" {
+                render_section(&mut html, &title(section), value, 2)?;
+            }
+        }
+    }
+
+    if !charts.is_empty() {
+        writeln!(html, "<h2>Plots</h2>")?;
+        for chart in charts {
+            writeln!(html, "<figure>\n{}</figure>", chart.to_svg())?;
+        }
+    }
+    writeln!(html, "</body>\n</html>")?;
+    Ok(html)
+}
+
+/// A table of the values of a section, followed by the nested sections and lists (e.g. per chromosome)
+fn render_section(
+    html: &mut String,
+    heading: &str,
+    value: &Value,
+    level: usize,
+) -> Result<(), std::fmt::Error> {
+    writeln!(html, "<h{level}>{}</h{level}>", escape(heading))?;
+    match value {
+        Value::Object(fields) => {
+            let (values, nested): (Vec<_>, Vec<_>) = fields
+                .iter()
+                .partition(|(_, value)| !value.is_object() && !value.is_array());
+            if !values.is_empty() {
+                writeln!(html, "<table>")?;
+                for (key, value) in values {
+                    writeln!(
+                        html,
+                        "<tr><th>{}</th>{}</tr>",
+                        escape(key),
+                        render_value(value)
+                    )?;
+                }
+                writeln!(html, "</table>")?;
+            }
+            for (key, value) in nested {
+                render_section(html, &title(key), value, (level + 1).min(6))?;
+            }
+        }
+        Value::Array(items) => render_list(html, items)?,
+        _ => writeln!(html, "<p>{}</p>", render_value(value))?,
+    }
+    Ok(())
+}
+
+/// A table with a row per item, and the fields of the items as columns
+fn render_list(html: &mut String, items: &[Value]) -> Result<(), std::fmt::Error> {
+    let Some(Value::Object(first)) = items.first() else {
+        return Ok(());
+    };
+    // nested values such as the haplotypes per chromosome are flattened into columns
+    let columns = first
+        .iter()
+        .flat_map(|(key, value)| match value {
+            Value::Object(fields) => fields
+                .keys()
+                .map(|field| (key.clone(), Some(field.clone())))
+                .collect(),
+            _ => vec![(key.clone(), None)],
+        })
+        .collect::<Vec<_>>();
+    writeln!(html, "<table>\n<tr>")?;
+    for (key, field) in &columns {
+        let header = match field {
+            Some(field) => format!("{key}.{field}"),
+            None => key.clone(),
+        };
+        writeln!(html, "<th>{}</th>", escape(&header))?;
+    }
+    writeln!(html, "</tr>")?;
+    for item in items {
+        write!(html, "<tr>")?;
+        for (key, field) in &columns {
+            let value = match field {
+                Some(field) => &item[key][field],
+                None => &item[key],
+            };
+            write!(html, "{}", render_value(value))?;
+        }
+        writeln!(html, "</tr>")?;
+    }
+    writeln!(html, "</table>")
+}
+
+fn render_value(value: &Value) -> String {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            let number = number.as_f64().unwrap_or(f64::NAN);
+            let formatted = if number != 0.0 && number.abs() < 0.01 {
+                format!("{number:.3e}")
+            } else {
+                format!("{number:.2}")
+            };
+            format!("<td class=\"number\">{formatted}</td>")
+        }
+        Value::Number(number) => format!("<td class=\"number\">{number}</td>"),
+        Value::String(text) => format!("<td>{}</td>", escape(text)),
+        Value::Bool(flag) => format!("<td>{}</td>", if *flag { "yes" } else { "no" }),
+        Value::Null => "<td>NA</td>".to_string(),
+        other => format!("<td>{}</td>", escape(&other.to_string())),
+    }
+}
+
+/// Section title from a field name, e.g. read_stats as Read stats
+fn title(key: &str) -> String {
+    let title = key.replace('_', " ");
+    let mut chars = title.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => title,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_html_report() {
+        let mut report = metrics::Metrics::new(metrics::FileInfo {
+            name: "<sample>.bam".to_string(),
+            path: "/data/sample.bam".to_string(),
+            creation_time: "NA".to_string(),
+        });
+        report.read_stats.n50 = 15_000;
+        report.read_stats.nx = vec![metrics::NxStat {
+            x: 90,
+            length: 8000,
+        }];
+        let chart = BarChart {
+            name: "test",
+            title: "Test".to_string(),
+            xlab: "x".to_string(),
+            ylab: "y".to_string(),
+            bars: vec![("1".to_string(), 1.0)],
+            log_scale: false,
+        };
+        let html = render(&report, &[chart]).unwrap();
+        assert!(html.contains("<title>cramino report: &lt;sample&gt;.bam</title>"));
+        assert!(html.contains("<h2>Read stats</h2>"));
+        assert!(html.contains("<tr><th>n50</th><td class=\"number\">15000</td></tr>"));
+        assert!(html.contains("<th>length</th>"));
+        assert!(html.contains("<h3>Length percentiles</h3>"));
+        assert!(html.contains("<figure>\n<svg"));
+        assert!(html.ends_with("</html>\n"));
+    }
+}
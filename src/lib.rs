@@ -0,0 +1,1120 @@
+//! Quality metrics of bam and cram files, as the cramino command line tool or in-process:
+//!
+//! ```no_run
+//! let options = cramino::QcOptions::new().karyotype(true);
+//! let metrics = cramino::metrics_from_path("sample.bam", &options).unwrap();
+//! println!("{}", serde_json::to_string_pretty(&metrics).unwrap());
+//! ```
+
+use clap::{Parser, Subcommand};
+use log::{info, warn};
+use metrics_processor::OutputFormat; // Import the enum
+use rust_htslib::bam;
+use std::borrow::Borrow;
+
+pub mod bed;
+pub mod calculations;
+pub mod collectors;
+pub mod compare;
+pub mod config;
+pub mod contigs;
+pub mod error;
+pub mod extract_from_bam;
+pub mod feather;
+pub mod file_info;
+pub mod histograms;
+pub mod html_report;
+pub mod metrics;
+pub mod metrics_processor;
+pub mod multiqc;
+pub mod options;
+pub mod phased;
+pub mod plots;
+pub mod progress;
+pub mod qc;
+pub mod read_table;
+pub mod sketch;
+pub mod splicing;
+pub mod state;
+pub mod text_output;
+pub mod tsv_output;
+pub mod utils;
+pub mod watch;
+
+pub use collectors::{MetricCollector, ReadInfo};
+pub use error::CraminoError;
+pub use options::QcOptions;
+
+/// Calculates the metrics of a bam or cram file, a url (s3:// or https://) or stdin (-)
+pub fn metrics_from_path(
+    path: &str,
+    options: &QcOptions,
+) -> Result<metrics::Metrics, Box<dyn std::error::Error>> {
+    let mut reader = extract_from_bam::open(path, options)?;
+    let (data, header) = extract_from_bam::extract_reader(&mut reader, path, options)?;
+    let file_info = metrics_processor::file_info(path);
+    Ok(metrics_processor::calculate_metrics(&data, options, &header, file_info)?.0)
+}
+
+/// Calculates the metrics of the reads of a reader, e.g. an indexed reader fetching a region
+/// The file info of the metrics is NA, as the reader has no path
+pub fn metrics_from_reader<R: bam::Read>(
+    reader: &mut R,
+    options: &QcOptions,
+) -> Result<metrics::Metrics, Box<dyn std::error::Error>> {
+    let (data, header) = extract_from_bam::extract_reader(reader, "NA", options)?;
+    Ok(metrics_processor::calculate_metrics(&data, options, &header, unknown_file())?.0)
+}
+
+/// Calculates the metrics of records, e.g. those passing through a service, with the header of their file
+/// The file info of the metrics is NA
+pub fn metrics_from_records<R: Borrow<bam::Record>>(
+    records: impl IntoIterator<Item = R>,
+    header: &bam::HeaderView,
+    options: &QcOptions,
+) -> Result<metrics::Metrics, Box<dyn std::error::Error>> {
+    let data = extract_from_bam::extract_records(records.into_iter(), header, "NA", options)?;
+    let header = bam::Header::from_template(header);
+    Ok(metrics_processor::calculate_metrics(&data, options, &header, unknown_file())?.0)
+}
+
+fn unknown_file() -> metrics::FileInfo {
+    metrics::FileInfo {
+        name: "NA".to_string(),
+        path: "NA".to_string(),
+        creation_time: "NA".to_string(),
+    }
+}
+
+// The arguments end up in the Cli struct
+#[derive(Parser, Debug)]
+#[clap(author, version, about="Tool to extract QC metrics from cram or bam", long_about = None, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// cram or bam file to check
+    #[clap(value_parser, default_value = "-")]
+    input: String,
+
+    /// TOML file with default options, e.g. karyotype = true or nx = [10, 50, 90], which are overridden by those on the command line (flags are switched off with --no-<flag>)
+    #[clap(long, value_parser, value_name = "TOML", env = config::CONFIG_ENV)]
+    config: Option<String>,
+
+    /// Preset of options for a data type, which are overridden by those of --config and the command line
+    #[clap(long, value_enum)]
+    preset: Option<config::Preset>,
+
+    #[clap(flatten)]
+    metrics: options::MetricArgs,
+
+    /// Use bounded-memory sketches for the read length, identity and splice metrics, instead of keeping all values in memory (not with --read-level or --length-stats, and --phased requires coordinate-sorted input)
+    #[clap(long, value_parser, conflicts_with_all = ["read_level", "length_stats"])]
+    sketch: bool,
+
+    /// Write the intermediate state to a file, to combine it with those of other inputs using `cramino merge`
+    #[clap(long, value_parser, value_name = "FILE", requires = "sketch")]
+    state: Option<String>,
+
+    /// Calculate read length stats per read instead of per alignment, merging split (supplementary) alignments
+    #[clap(long, value_parser)]
+    read_level: bool,
+
+    /// Report query length (incl. clipped bases), aligned length and reference span separately
+    #[clap(long, value_parser)]
+    length_stats: bool,
+
+    /// If histograms have to be generated (optionally specify output file)
+    #[clap(long, value_parser, value_name = "FILE", num_args = 0..=1, conflicts_with = "hist_count")]
+    hist: Option<Option<String>>,
+
+    /// Write data to an arrow format file
+    #[clap(long, value_parser)]
+    arrow: Option<String>,
+
+    /// Write a table with QC metrics per read, as Arrow IPC, Parquet (.parquet) or TSV (.tsv)
+    #[clap(long, value_parser, value_name = "FILE")]
+    read_table: Option<String>,
+
+    /// Aux tags to add as columns to the read table, comma-separated (e.g. NM,de,MM)
+    #[clap(long, value_parser, value_delimiter = ',', requires = "read_table")]
+    read_table_tags: Vec<String>,
+
+    /// Calculate metrics for phased reads
+    #[clap(long, value_parser)]
+    phased: bool,
+
+    /// Write phase blocks to a BED file (chrom, start, end, phaseset, number of reads)
+    #[clap(long, value_parser, value_name = "FILE", requires = "phased")]
+    phaseblocks_bed: Option<String>,
+
+    /// BED file with regions (e.g. genes) for which to report the phase block coverage
+    #[clap(long, value_parser, value_name = "BED", requires = "phased")]
+    phaseblocks_regions: Option<String>,
+
+    /// Provide metrics for spliced data
+    #[clap(long, value_parser)]
+    spliced: bool,
+
+    /// Fail on the first invalid record, e.g. with a negative NM tag [default]
+    #[clap(long, value_parser, conflicts_with = "lenient")]
+    strict: bool,
+
+    /// Skip invalid records and report them in the warnings, instead of failing
+    #[clap(long, value_parser)]
+    lenient: bool,
+
+    /// Print the records and bases processed, the throughput and for local or sorted files the percentage and ETA to stderr
+    #[clap(long, value_parser)]
+    progress: bool,
+
+    /// Output format (text, json, or tsv)
+    #[clap(long, value_parser, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Write the metrics, histograms and karyotype as MultiQC custom content (*_mqc.json) to a directory
+    #[clap(long, value_parser, value_name = "DIR")]
+    multiqc_dir: Option<String>,
+
+    /// Write a self-contained HTML report with summary tables and plots
+    #[clap(long, value_parser, value_name = "FILE")]
+    html: Option<String>,
+
+    /// Write the histograms as image files to a directory, e.g. for slides
+    #[clap(long, value_parser, value_name = "DIR")]
+    plot_dir: Option<String>,
+
+    /// Image formats for --plot-dir, comma-separated
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "svg",
+        requires = "plot_dir"
+    )]
+    plot_format: Vec<plots::PlotFormat>,
+
+    /// Use a logarithmic y-axis for the histograms with --plot-dir and --html
+    #[clap(long, value_parser)]
+    log_scale: bool,
+
+    /// TOML file with fail and warn rules on the metrics (e.g. fail = ["read_stats.n50 >= 15000"]), exits with code 3 if a fail rule is not met
+    #[clap(long, value_parser = qc::read_thresholds, value_name = "TOML")]
+    qc_thresholds: Option<qc::QcThresholds>,
+
+    /// Scale histogram bins by total basepairs in each bin (not just read count)
+    #[clap(long, value_parser)]
+    pub scaled: bool,
+
+    /// Output histogram bin counts in TSV format (optionally specify output file)
+    #[clap(long, value_parser, value_name = "FILE", num_args = 0..=1, conflicts_with = "hist")]
+    pub hist_count: Option<Option<String>>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Combine state files (written with --state) into a single report
+    Merge(state::MergeArgs),
+    /// Compare reports (or bam/cram files) side by side, e.g. after re-basecalling or re-aligning
+    Compare(compare::CompareArgs),
+    /// Process the bam/cram files in a directory as they are written (e.g. by dorado) and keep rewriting the reports of all files
+    Watch(watch::WatchArgs),
+}
+
+/// Runs cramino with the arguments of the command line
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = config::parse_args();
+    match &args.command {
+        Some(Command::Merge(merge_args)) => return state::merge(merge_args),
+        Some(Command::Compare(compare_args)) => return compare::compare(compare_args),
+        Some(Command::Watch(watch_args)) => return watch::watch(watch_args),
+        None => (),
+    }
+    utils::is_file(&args.input).map_err(|_| CraminoError::Input {
+        input: args.input.clone(),
+        message: "not a file".to_string(),
+    })?;
+    check_stdin_input(&args.input);
+    if args.metrics.ubam {
+        args.metrics.karyotype = false;
+        args.phased = false;
+        args.spliced = false;
+        args.length_stats = false;
+        args.read_level = false;
+    };
+    info!("Collected arguments");
+    let (metrics, header) = extract_from_bam::extract(&args)?;
+    info!("Extracted metrics");
+    if let Some(state_file) = &args.state {
+        // the state of a truncated file would be merged as if it were complete
+        if metrics.incomplete.is_some() {
+            warn!("Not writing the state file {state_file} of an incomplete input");
+        } else {
+            let input = metrics_processor::file_info(&args.input).name;
+            state::State::from_data(&metrics, input, &QcOptions::from(&args), &header)?
+                .write(state_file)?;
+        }
+    }
+    let report = metrics_processor::process_metrics(metrics, &args, header)?;
+    info!("Finished");
+    if let Some(incomplete) = &report.incomplete {
+        eprintln!(
+            "Error: the input was not read completely: {}",
+            incomplete.reason
+        );
+        std::process::exit(error::EXIT_INCOMPLETE);
+    }
+    if report
+        .qc
+        .is_some_and(|qc| qc.status == metrics::QcStatus::Fail)
+    {
+        std::process::exit(qc::EXIT_FAILURE);
+    }
+    Ok(())
+}
+
+fn check_stdin_input(input: &str) {
+    if input == "-" {
+        eprintln!(
+            "Reading from stdin. If this is unexpected, make sure your input file is correctly specified."
+        );
+        // Check if stdin is connected to a terminal (interactive) using std library
+        if std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            eprintln!(
+                "Warning: stdin appears to be a terminal. Did you mean to specify an input file?"
+            );
+            eprintln!(
+                "Note: If you're using --hist or --hist-count as the last option followed by a filename, the filename may have been interpreted as the histogram output path."
+            );
+            eprintln!(
+                "To avoid this, either specify the input file before the flag, or use --hist=output.txt / --hist-count=output.txt syntax."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+#[ctor::ctor(unsafe)]
+fn init() {
+    env_logger::init();
+}
+
+#[test]
+fn verify_app() {
+    use clap::CommandFactory;
+    Cli::command().debug_assert()
+}
+
+#[test]
+fn extract() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: true,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: Some("test.feather".to_string()),
+        read_table: None,
+        read_table_tags: vec![],
+        phased: true,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+// this test is ignored because it uses a local reference file
+#[ignore]
+#[test]
+fn extract_cram() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.cram".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: Some("/home/wdecoster/reference/GRCh38.fa".to_string()),
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_ubam() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-ubam.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: true,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: Some("test.feather".to_string()),
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+// this test is ignored because it uses a local reference file and takes a very long time
+#[ignore]
+#[test]
+fn extract_url() {
+    let args = Cli {
+        command: None,
+        input: "https://ftp.1000genomes.ebi.ac.uk/vol1/ftp/data_collections/1KG_ONT_VIENNA/hg38/HG00096.hg38.cram".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: Some("/home/wdecoster/local/1KG_ONT_VIENNA_hg38.fa.gz".to_string()),
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_json() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: true,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: true,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_tsv() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: true,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(Some("hist.txt".to_string())),
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: true,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Tsv,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_spliced() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: true,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.intron_lengths.is_some());
+    assert!(metrics.exon_lengths.is_some());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_length_stats() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: true,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    let query_lengths = metrics.query_lengths.as_ref().unwrap();
+    let lengths = metrics.lengths.as_ref().unwrap();
+    assert_eq!(query_lengths.len(), lengths.len());
+    assert!(query_lengths.iter().sum::<u128>() >= lengths.iter().sum::<u128>());
+    assert!(metrics.reference_spans.is_some());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_read_level() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: true,
+        read_level: true,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    let lengths = metrics.lengths.as_ref().unwrap();
+    let alignment_lengths = metrics.alignment_lengths.as_ref().unwrap();
+    // supplementary alignments are not counted as separate reads
+    assert_eq!(lengths.len(), metrics.num_reads);
+    assert!(alignment_lengths.len() >= lengths.len());
+    assert!(metrics.num_split_reads.is_some());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_sketch_matches_exact() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: true,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+    let (exact, _) = extract_from_bam::extract(&args).unwrap();
+    let args = Cli {
+        sketch: true,
+        state: None,
+        ..args
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.lengths.is_none());
+    let sketch = metrics.length_sketch.as_ref().unwrap();
+    let lengths = exact.lengths.as_ref().unwrap();
+    let (data_yield, long_yields) = utils::calculate_data_yield(lengths, &[25_000, 25_000]);
+    assert_eq!(sketch.len(), lengths.len());
+    assert_eq!(sketch.data_yield(), (data_yield, long_yields));
+    let n50 = calculations::get_n(lengths, data_yield, 0.5);
+    assert!((sketch.get_n(0.5) as f64 - n50 as f64).abs() / (n50 as f64) < 0.001);
+    let median = calculations::median_length(lengths);
+    assert!((sketch.median() - median).abs() / median < 0.001);
+    let identities = exact.identities.as_ref().unwrap();
+    let identity_sketch = metrics.identity_sketch.as_ref().unwrap();
+    assert!((identity_sketch.median() - calculations::median(identities)).abs() <= 0.005);
+    // the histograms and the number of exons are exact
+    let exact_histograms = serde_json::to_value(histograms::build_histograms(&exact)).unwrap();
+    let histograms = serde_json::to_value(histograms::build_histograms(&metrics)).unwrap();
+    assert_eq!(histograms, exact_histograms);
+    let splice_stats = metrics.splice_sketch.as_ref().unwrap().splice_stats();
+    let mut exons = exact.exons.clone().unwrap();
+    exons.sort_unstable();
+    assert_eq!(
+        splice_stats.median_exons,
+        calculations::median_splice(&exons)
+    );
+    assert_eq!(
+        splice_stats.fraction_unspliced,
+        exons.iter().filter(|&&x| x == 1).count() as f32 / exons.len() as f32
+    );
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_with_high_min_length() {
+    // Use a minimum read length higher than any read in the test file
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 1_000_000, // Set very high to ensure no reads match
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+
+    // The test should still run without panicking
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.lengths.as_ref().unwrap().is_empty());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok());
+}
+
+#[test]
+fn extract_json_with_high_min_length() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 1_000_000, // Set very high to ensure no reads match
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Json,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.lengths.as_ref().unwrap().is_empty());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok());
+}
+
+#[test]
+fn extract_tsv_with_high_min_length() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 1_000_000, // Set very high to ensure no reads match
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Tsv,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: None,
+    };
+
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.lengths.as_ref().unwrap().is_empty());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok());
+}
+
+#[test]
+fn extract_hist_scaled() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: true,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: Some(None),
+        arrow: Some("test.feather".to_string()),
+        read_table: None,
+        read_table_tags: vec![],
+        phased: true,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: true, // Set scaled to true for this test
+        hist_count: None,
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_hist_count() {
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: Some(None),
+    };
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok())
+}
+
+#[test]
+fn extract_hist_count_with_high_min_length() {
+    // Test that --hist-count works with empty results
+    let args = Cli {
+        command: None,
+        input: "test-data/small-test-phased.bam".to_string(),
+        metrics: options::MetricArgs {
+            threads: 8,
+            reference: None,
+            min_read_len: 1_000_000, // Set very high to ensure no reads match
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            ubam: false,
+        },
+        length_stats: false,
+        read_level: false,
+        sketch: false,
+        state: None,
+        hist: None,
+        arrow: None,
+        read_table: None,
+        read_table_tags: vec![],
+        phased: false,
+        phaseblocks_bed: None,
+        phaseblocks_regions: None,
+        spliced: false,
+        strict: false,
+        lenient: false,
+        progress: false,
+        format: OutputFormat::Text,
+        multiqc_dir: None,
+        html: None,
+        plot_dir: None,
+        plot_format: vec![plots::PlotFormat::Svg],
+        log_scale: false,
+        qc_thresholds: None,
+        config: None,
+        preset: None,
+        scaled: false,
+        hist_count: Some(None),
+    };
+
+    let (metrics, header) = extract_from_bam::extract(&args).unwrap();
+    assert!(metrics.lengths.as_ref().unwrap().is_empty());
+    assert!(metrics_processor::process_metrics(metrics, &args, header).is_ok());
+}
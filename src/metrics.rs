@@ -1,11 +1,26 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Fields of array items that identify them, e.g. to match Nx values or chromosomes between reports
+const ITEM_KEYS: [&str; 3] = ["threshold", "x", "chromosome"];
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Metrics {
     pub file_info: FileInfo,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<Incomplete>,
+
     pub alignment_stats: AlignmentStats,
     pub read_stats: ReadStats,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_level_stats: Option<ReadLevelStats>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length_stats: Option<LengthStats>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub identity_stats: Option<IdentityStats>,
 
@@ -18,6 +33,16 @@ pub struct Metrics {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub splice_stats: Option<SpliceStats>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warnings: Option<Warnings>,
+
+    /// sections of collectors that are not built in, see MetricCollector
+    #[serde(flatten)]
+    pub sections: serde_json::Map<String, Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qc: Option<QcReport>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub histograms: Option<Histograms>,
 }
@@ -45,6 +70,77 @@ pub struct ReadStats {
     pub n75: u128,
     pub median_length: f64,
     pub mean_length: f64,
+    #[serde(default)]
+    pub long_read_yield: Vec<LongReadYield>,
+    #[serde(default)]
+    pub nx: Vec<NxStat>,
+    #[serde(default)]
+    pub lx: Vec<LxStat>,
+    #[serde(default)]
+    pub aun: f64,
+    #[serde(default)]
+    pub length_percentiles: LengthPercentiles,
+}
+
+/// Yield and number of reads longer than the threshold
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LongReadYield {
+    pub threshold: u64,
+    pub yield_gb: f64,
+    pub num_reads: usize,
+    pub fraction_of_yield: f64,
+}
+
+/// Nx: the length of the read at which x percent of the yield is reached
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NxStat {
+    pub x: u8,
+    pub length: u128,
+}
+
+/// Lx: the number of (longest) reads needed to reach x percent of the yield
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LxStat {
+    pub x: u8,
+    pub num_reads: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LengthPercentiles {
+    pub p5: u128,
+    pub p10: u128,
+    pub p25: u128,
+    pub p50: u128,
+    pub p75: u128,
+    pub p90: u128,
+    pub p95: u128,
+}
+
+/// With --read-level, read stats describe reads instead of alignments,
+/// and the aligned lengths of the alignments are summarized here
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadLevelStats {
+    /// reads with supplementary alignments, counted once
+    pub num_split_reads: usize,
+    pub alignment_length: LengthSummary,
+}
+
+/// Query length (including clipped bases), aligned length and reference span of the alignments
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LengthStats {
+    pub query_length: LengthSummary,
+    pub aligned_length: LengthSummary,
+    pub reference_span: LengthSummary,
+    /// aligned bases as a fraction of all query bases
+    pub fraction_aligned: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LengthSummary {
+    pub total_gb: f64,
+    pub n50: u128,
+    pub median: f64,
+    pub mean: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -63,6 +159,45 @@ pub struct PhaseStats {
     pub total_bases_phased_gb: f64,
     pub median_phaseblock_length: f64,
     pub n50_phaseblock_length: i64,
+    #[serde(default)]
+    pub fraction_genome_phased: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_stats: Option<RegionPhaseStats>,
+    #[serde(default)]
+    pub haplotypes: HaplotypeStats,
+    #[serde(default)]
+    pub chromosomes: Vec<ChromosomePhaseStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegionPhaseStats {
+    pub num_regions: usize,
+    /// fraction of the bases in the regions spanned by a phase block
+    pub fraction_bases_phased: f64,
+    /// regions contained within a single phase block
+    pub num_fully_phased: usize,
+    pub fraction_fully_phased: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct HaplotypeStats {
+    pub hp1_reads: usize,
+    pub hp2_reads: usize,
+    pub unassigned_reads: usize,
+    pub hp1_bases: u128,
+    pub hp2_bases: u128,
+    pub unassigned_bases: u128,
+    /// fraction of haplotagged bases assigned to HP1
+    pub hp1_fraction: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChromosomePhaseStats {
+    pub chromosome: String,
+    pub fraction_phased: f32,
+    pub num_phaseblocks: usize,
+    pub n50_phaseblock_length: i64,
+    pub haplotypes: HaplotypeStats,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -77,6 +212,24 @@ pub struct SpliceStats {
     pub median_exons: usize,
     pub mean_exons: f32,
     pub fraction_unspliced: f32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intron_lengths: Option<LengthDistribution>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exon_lengths: Option<LengthDistribution>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LengthDistribution {
+    pub count: usize,
+    pub median: f64,
+    pub mean: f64,
+    pub p5: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p95: f64,
+    pub max: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -85,6 +238,12 @@ pub struct Histograms {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub q_score: Option<Histogram>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub intron_length: Option<Histogram>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exon_length: Option<Histogram>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -103,15 +262,70 @@ pub struct HistogramBin {
     pub bases: u128,
 }
 
+/// Set if the input could not be read completely, e.g. a truncated file,
+/// in which case the metrics are those of the records before
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Incomplete {
+    pub reason: String,
+    /// number of records that were read, including secondary alignments and filtered reads
+    pub records_processed: usize,
+    /// position (1-based) of the last aligned record that was read, as contig:position
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_position: Option<String>,
+}
+
+/// Invalid records that were skipped with --lenient
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Warnings {
+    pub skipped_records: usize,
+    /// the errors of the first skipped records, with their read name and file offset
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QcReport {
+    pub status: QcStatus,
+    pub rules: Vec<QcRuleResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QcRuleResult {
+    pub rule: String,
+    pub metric: String,
+    pub value: Option<f64>,
+    pub status: QcStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum QcStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for QcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QcStatus::Pass => write!(f, "PASS"),
+            QcStatus::Warn => write!(f, "WARN"),
+            QcStatus::Fail => write!(f, "FAIL"),
+        }
+    }
+}
+
 impl Metrics {
     pub fn new(file_info: FileInfo) -> Self {
         Metrics {
             file_info,
+            incomplete: None,
             alignment_stats: AlignmentStats {
                 num_alignments: 0,
                 percent_from_total: 0.0,
                 num_reads: 0,
             },
+            read_level_stats: None,
+            length_stats: None,
             read_stats: ReadStats {
                 yield_gb: 0.0,
                 mean_coverage: 0.0,
@@ -120,12 +334,116 @@ impl Metrics {
                 n75: 0,
                 median_length: 0.0,
                 mean_length: 0.0,
+                long_read_yield: Vec::new(),
+                nx: Vec::new(),
+                lx: Vec::new(),
+                aun: 0.0,
+                length_percentiles: LengthPercentiles::default(),
             },
             identity_stats: None,
             phase_stats: None,
             karyotype_stats: None,
             splice_stats: None,
+            warnings: None,
+            sections: serde_json::Map::new(),
+            qc: None,
             histograms: None,
         }
     }
+
+    /// Flattens the numeric fields to names like read_stats.n50 or read_stats.nx.90.length
+    pub fn flatten(&self) -> Vec<(String, f64)> {
+        let mut fields = Vec::new();
+        if let Ok(Value::Object(sections)) = serde_json::to_value(self) {
+            for (section, value) in &sections {
+                // the file info differs by definition, histograms are summarized in the metrics
+                // and the qc section is derived from the metrics
+                if !["file_info", "histograms", "qc"].contains(&section.as_str()) {
+                    flatten_value(section, value, &mut fields);
+                }
+            }
+        }
+        fields
+    }
+}
+
+fn flatten_value(name: &str, value: &Value, fields: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                fields.push((name.to_string(), number));
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_value(&format!("{name}.{key}"), value, fields);
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                let key = ITEM_KEYS
+                    .iter()
+                    .find_map(|key| item.get(key).map(|v| (*key, v)));
+                let label = match key {
+                    Some((_, Value::String(label))) => label.clone(),
+                    Some((_, label)) => label.to_string(),
+                    None => index.to_string(),
+                };
+                match item {
+                    Value::Object(map) => {
+                        for (field, value) in map {
+                            // the identifying field is already part of the name
+                            if key.is_none_or(|(key, _)| key != field) {
+                                flatten_value(&format!("{name}.{label}.{field}"), value, fields);
+                            }
+                        }
+                    }
+                    _ => flatten_value(&format!("{name}.{label}"), item, fields),
+                }
+            }
+        }
+        // strings and flags are not numeric metrics
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten_matches_array_items_by_key() {
+        let mut report = Metrics::new(FileInfo {
+            name: "a.bam".to_string(),
+            path: "a.bam".to_string(),
+            creation_time: "NA".to_string(),
+        });
+        report.read_stats.nx = vec![NxStat {
+            x: 90,
+            length: 1000,
+        }];
+        report.read_stats.lx = vec![LxStat {
+            x: 90,
+            num_reads: 12,
+        }];
+        report.karyotype_stats = Some(vec![ChromosomeData {
+            chromosome: "chr1".to_string(),
+            count: 10,
+            normalized_count: 0.5,
+        }]);
+        let fields = report.flatten();
+        let field = |name: &str| fields.iter().find(|(n, _)| n == name).map(|(_, v)| *v);
+        assert_eq!(field("read_stats.nx.90.length"), Some(1000.0));
+        assert_eq!(field("read_stats.lx.90.num_reads"), Some(12.0));
+        assert_eq!(field("read_stats.nx.90.x"), None);
+        assert_eq!(field("karyotype_stats.chr1.count"), Some(10.0));
+        assert_eq!(field("alignment_stats.num_reads"), Some(0.0));
+        assert!(
+            fields
+                .iter()
+                .all(|(name, _)| !name.starts_with("file_info"))
+        );
+        // fields are kept in the order of the report
+        assert_eq!(fields[0].0, "alignment_stats.num_alignments");
+    }
 }
@@ -1,9 +1,10 @@
 use crate::{
-    Cli, calculations, extract_from_bam::Data, file_info, histograms, metrics, phased, utils,
+    Cli, QcOptions, bed, calculations, extract_from_bam::Data, file_info, histograms, html_report,
+    metrics, phased, plots, qc, utils,
 };
 use clap::builder::{TypedValueParser, ValueParserFactory};
+use log::warn;
 use rust_htslib::bam;
-use std::collections::HashMap;
 use std::fmt;
 use std::str::FromStr;
 
@@ -89,46 +90,56 @@ impl TypedValueParser for OutputFormatValueParser {
     }
 }
 
-pub fn process_metrics(
-    metrics_data: Data,
-    args: &Cli,
-    header: rust_htslib::bam::Header,
-) -> Result<(), Box<dyn std::error::Error>> {
+/// Name, path and creation time of the input file
+pub fn file_info(path: &str) -> metrics::FileInfo {
     let bam = file_info::BamFile {
-        path: args.input.clone(),
+        path: path.to_string(),
     };
-
-    // Create a metrics object
-    let mut metrics_obj = metrics::Metrics::new(metrics::FileInfo {
+    metrics::FileInfo {
         name: bam.file_name(),
         path: bam.to_string(),
         creation_time: bam.file_time(),
-    });
+    }
+}
 
-    let lengths = metrics_data.lengths.as_ref().unwrap();
+/// Calculates the metrics of the data of args.input and writes the report
+/// Returns the report, with the QC results of --qc-thresholds
+pub fn process_metrics(
+    metrics_data: Data,
+    args: &Cli,
+    header: rust_htslib::bam::Header,
+) -> Result<metrics::Metrics, Box<dyn std::error::Error>> {
+    let file_info = file_info(&args.input);
+    let (mut metrics_obj, phaseblocks) =
+        calculate_metrics(&metrics_data, &QcOptions::from(args), &header, file_info)?;
     let hist_requested = args.hist.is_some() || args.hist_count.is_some();
 
-    // Check if no reads passed the filters
-    if lengths.is_empty() {
-        eprintln!("Warning: No reads pass your filtering criteria");
-
-        // Set minimal metrics with zeros
-        metrics_obj.alignment_stats = metrics::AlignmentStats {
-            num_alignments: 0,
-            percent_from_total: 0.0,
-            num_reads: 0,
-        };
-
-        metrics_obj.read_stats = metrics::ReadStats {
-            yield_gb: 0.0,
-            mean_coverage: 0.0,
-            yield_gb_long: 0.0,
-            n50: 0,
-            n75: 0,
-            median_length: 0.0,
-            mean_length: 0.0,
-        };
+    if let Some(multiqc_dir) = &args.multiqc_dir {
+        let histograms =
+            (!no_reads(&metrics_data)).then(|| histograms::build_histograms(&metrics_data));
+        crate::multiqc::write_multiqc(multiqc_dir, &metrics_obj, histograms.as_ref(), args.scaled)?;
+    }
+    if let Some(html_file) = &args.html {
+        let charts = plots::charts(
+            &metrics_data,
+            &metrics_obj,
+            phaseblocks.as_deref(),
+            args.scaled,
+            args.log_scale,
+        );
+        html_report::write_html(html_file, &metrics_obj, &charts)?;
+    }
+    if let Some(plot_dir) = &args.plot_dir {
+        let charts = plots::all_charts(
+            &metrics_data,
+            &metrics_obj,
+            phaseblocks.as_deref(),
+            args.log_scale,
+        );
+        plots::write_plots(plot_dir, &charts, &args.plot_format)?;
+    }
 
+    if no_reads(&metrics_data) {
         // Output based on selected format
         match args.format {
             OutputFormat::Text => {
@@ -172,11 +183,146 @@ pub fn process_metrics(
             }
         }
 
-        return Ok(());
+        return Ok(metrics_obj);
+    }
+
+    // Output based on selected format
+    match args.format {
+        OutputFormat::Text => {
+            // Print text output using the collected metrics
+            crate::text_output::print_text_output(&metrics_obj);
+            if let Some(hist_file) = &args.hist {
+                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
+            }
+            // Handle --hist-count flag (output histogram counts after metrics)
+            if let Some(hist_count_file) = &args.hist_count {
+                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
+            }
+        }
+        OutputFormat::Json => {
+            if hist_requested {
+                metrics_obj.histograms = Some(histograms::build_histograms(&metrics_data));
+            }
+            println!("{}", serde_json::to_string_pretty(&metrics_obj).unwrap());
+            if let Some(hist_file) = &args.hist {
+                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
+            }
+            // Handle --hist-count flag (output histogram counts after metrics)
+            if let Some(hist_count_file) = &args.hist_count {
+                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
+            }
+        }
+        OutputFormat::Tsv => {
+            crate::tsv_output::print_tsv_output(&metrics_obj);
+            if let Some(hist_file) = &args.hist {
+                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
+            }
+            // Handle --hist-count flag (output histogram counts after metrics)
+            if let Some(hist_count_file) = &args.hist_count {
+                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
+            }
+        }
+    }
+
+    Ok(metrics_obj)
+}
+
+/// Prints the metrics in the output format, e.g. of merged state files
+pub fn print_metrics(metrics_obj: &metrics::Metrics, format: OutputFormat) {
+    match format {
+        OutputFormat::Text => crate::text_output::print_text_output(metrics_obj),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(metrics_obj).unwrap()),
+        OutputFormat::Tsv => crate::tsv_output::print_tsv_output(metrics_obj),
+    }
+}
+
+/// The metrics, and the phaseblock lengths for the histograms
+type CalculatedMetrics = (metrics::Metrics, Option<Vec<i64>>);
+
+/// Calculates the metrics from the extracted data, without writing the report
+/// The rules of the QC thresholds are evaluated on the metrics
+pub fn calculate_metrics(
+    metrics_data: &Data,
+    args: &QcOptions,
+    header: &bam::Header,
+    file_info: metrics::FileInfo,
+) -> Result<CalculatedMetrics, Box<dyn std::error::Error>> {
+    let (mut metrics_obj, phaseblocks) = calculate(metrics_data, args, header, file_info)?;
+    if let Some(thresholds) = &args.qc_thresholds {
+        metrics_obj.qc = Some(qc::evaluate(thresholds, &metrics_obj)?);
+    }
+    Ok((metrics_obj, phaseblocks))
+}
+
+fn calculate(
+    metrics_data: &Data,
+    args: &QcOptions,
+    header: &bam::Header,
+    file_info: metrics::FileInfo,
+) -> Result<CalculatedMetrics, Box<dyn std::error::Error>> {
+    // Create a metrics object
+    let mut metrics_obj = metrics::Metrics::new(file_info);
+    metrics_obj.sections = metrics_data
+        .collectors
+        .iter()
+        .filter_map(|collector| collector.finalize())
+        .collect();
+    metrics_obj.incomplete = metrics_data.incomplete.clone();
+    // with --lenient, the number of skipped records is reported, also if there are none
+    if args.lenient {
+        metrics_obj.warnings = Some(metrics::Warnings {
+            skipped_records: metrics_data.skipped_records,
+            errors: metrics_data.skipped_errors.clone(),
+        });
+    }
+
+    // with --sketch, the lengths are summarized in a sketch instead
+    let lengths: &[u128] = metrics_data.lengths.as_deref().unwrap_or_default();
+    let length_sketch = metrics_data.length_sketch.as_ref();
+
+    // Check if no reads passed the filters
+    if no_reads(metrics_data) {
+        eprintln!("Warning: No reads pass your filtering criteria");
+
+        // Set minimal metrics with zeros
+        metrics_obj.alignment_stats = metrics::AlignmentStats {
+            num_alignments: 0,
+            percent_from_total: 0.0,
+            num_reads: 0,
+        };
+
+        let (nx, lx, _, _) = calculations::read_length_stats(lengths, 0, &args.nx);
+        metrics_obj.read_stats = metrics::ReadStats {
+            yield_gb: 0.0,
+            mean_coverage: 0.0,
+            yield_gb_long: 0.0,
+            n50: 0,
+            n75: 0,
+            median_length: 0.0,
+            mean_length: 0.0,
+            long_read_yield: args
+                .long_thresholds
+                .iter()
+                .map(|threshold| metrics::LongReadYield {
+                    threshold: *threshold,
+                    yield_gb: 0.0,
+                    num_reads: 0,
+                    fraction_of_yield: 0.0,
+                })
+                .collect(),
+            nx,
+            lx,
+            aun: 0.0,
+            length_percentiles: metrics::LengthPercentiles::default(),
+        };
+
+        return Ok((metrics_obj, None));
     }
 
     // Continue with normal processing if we have reads
-    let num_alignments = lengths.len();
+    // with --read-level, lengths are per read and the aligned lengths per alignment are kept separately
+    let alignment_lengths = metrics_data.alignment_lengths.as_deref().unwrap_or(lengths);
+    let num_alignments = length_sketch.map_or(alignment_lengths.len(), |sketch| sketch.len());
     let num_reads = metrics_data.num_reads;
     let all_alignments = metrics_data.all_counts;
 
@@ -188,21 +334,109 @@ pub fn process_metrics(
     };
 
     // Calculate and fill read stats
-    let genome_size = utils::get_genome_size(&header)?;
-    let (data_yield, data_yield_long) = utils::calculate_data_yield(lengths);
-
+    // the genome size is the sum of the (selected) contig lengths, unless specified with --genome-size
+    // for unaligned reads there are no contig lengths, and the coverage is only estimated with --genome-size
+    let genome_size = match args.genome_size {
+        Some(genome_size) => genome_size,
+        None => utils::get_genome_size(header, args.contigs.as_ref())?,
+    };
+    if genome_size == 0 {
+        warn!("Genome size is unknown, specify --genome-size to calculate the mean coverage");
+    }
+    let (data_yield, long_yields) = match length_sketch {
+        Some(sketch) => sketch.data_yield(),
+        None => {
+            utils::calculate_data_yield(lengths, &utils::yield_thresholds(&args.long_thresholds))
+        }
+    };
+    let data_yield_long = long_yields[0].0;
+    // the coverage is based on the aligned bases (on the selected contigs), also with --read-level
+    let aligned_yield = if let Some(coverage_yield) = metrics_data.coverage_yield {
+        coverage_yield
+    } else if metrics_data.alignment_lengths.is_some() {
+        alignment_lengths.iter().sum::<u128>()
+    } else {
+        data_yield
+    };
+    let long_read_yield = args
+        .long_thresholds
+        .iter()
+        .zip(long_yields.iter().skip(1))
+        .map(
+            |(threshold, (long_yield, long_count))| metrics::LongReadYield {
+                threshold: *threshold,
+                yield_gb: *long_yield as f64 / 1e9,
+                num_reads: *long_count,
+                fraction_of_yield: *long_yield as f64 / data_yield as f64,
+            },
+        )
+        .collect();
+
+    let (nx, lx, aun, length_percentiles) = match length_sketch {
+        Some(sketch) => sketch.read_length_stats(&args.nx),
+        None => calculations::read_length_stats(lengths, data_yield, &args.nx),
+    };
+    let (n50, n75, median_length) = match length_sketch {
+        Some(sketch) => (sketch.get_n(0.50), sketch.get_n(0.75), sketch.median()),
+        None => (
+            calculations::get_n(lengths, data_yield, 0.50),
+            calculations::get_n(lengths, data_yield, 0.75),
+            calculations::median_length(lengths),
+        ),
+    };
     metrics_obj.read_stats = metrics::ReadStats {
         yield_gb: data_yield as f64 / 1e9,
-        mean_coverage: data_yield as f64 / genome_size as f64,
+        mean_coverage: if genome_size > 0 {
+            aligned_yield as f64 / genome_size as f64
+        } else {
+            0.0
+        },
         yield_gb_long: data_yield_long as f64 / 1e9,
-        n50: calculations::get_n(lengths, data_yield, 0.50),
-        n75: calculations::get_n(lengths, data_yield, 0.75),
-        median_length: calculations::median_length(lengths),
-        mean_length: data_yield as f64 / lengths.len() as f64,
+        n50,
+        n75,
+        median_length,
+        mean_length: data_yield as f64 / length_sketch.map_or(lengths.len(), |s| s.len()) as f64,
+        long_read_yield,
+        nx,
+        lx,
+        aun,
+        length_percentiles,
     };
 
+    // Add alignment length stats if read stats are calculated per read
+    if let Some(num_split_reads) = metrics_data.num_split_reads {
+        metrics_obj.read_level_stats = Some(metrics::ReadLevelStats {
+            num_split_reads,
+            alignment_length: calculations::length_summary(alignment_lengths),
+        });
+    }
+
+    // Add query length, aligned length and reference span if requested
+    if let Some(query_lengths) = metrics_data.query_lengths.as_ref() {
+        let query_summary = calculations::length_summary(query_lengths);
+        let aligned_summary = calculations::length_summary(alignment_lengths);
+        metrics_obj.length_stats = Some(metrics::LengthStats {
+            fraction_aligned: aligned_summary.total_gb / query_summary.total_gb,
+            query_length: query_summary,
+            aligned_length: aligned_summary,
+            reference_span: calculations::length_summary(
+                metrics_data
+                    .reference_spans
+                    .as_ref()
+                    .expect("Reference spans are missing"),
+            ),
+        });
+    }
+
     // Add identity metrics if available
-    if let Some(identities) = metrics_data.identities.as_ref() {
+    if let Some(sketch) = metrics_data.identity_sketch.as_ref() {
+        metrics_obj.identity_stats = Some(metrics::IdentityStats {
+            median_identity: sketch.median(),
+            mean_identity: sketch.mean(),
+            modal_identity: sketch.modal(),
+            is_estimated: metrics_data.is_ubam,
+        });
+    } else if let Some(identities) = metrics_data.identities.as_ref() {
         metrics_obj.identity_stats = Some(metrics::IdentityStats {
             median_identity: calculations::median(identities),
             mean_identity: identities.iter().sum::<f64>() / (identities.len() as f64),
@@ -212,48 +446,73 @@ pub fn process_metrics(
     }
 
     // Add phase metrics if available
-    let phaseblocks = if args.phased {
-        let phaseblocks = phased::phase_metrics(
-            metrics_data.tids.as_ref().expect("TIDs data is missing"),
-            metrics_data.starts.clone().expect("Starts data is missing"),
-            metrics_data.ends.clone().expect("Ends data is missing"),
-            metrics_data
-                .phasesets
-                .as_ref()
-                .expect("Phase sets data is missing"),
-        );
-
-        if !phaseblocks.is_empty() {
-            let phased_bases = phaseblocks.iter().sum::<i64>();
-            let phased_reads = metrics_data
-                .phasesets
-                .as_ref()
-                .expect("Phase sets data is missing")
-                .iter()
-                .filter(|p| p.is_some())
-                .count();
-
-            metrics_obj.phase_stats = Some(metrics::PhaseStats {
-                fraction_phased: (phased_reads as f32) / (num_reads as f32),
-                num_phaseblocks: phaseblocks.len(),
-                total_bases_phased_gb: phased_bases as f64 / 1e9,
-                median_phaseblock_length: phased::median(&phaseblocks),
-                n50_phaseblock_length: phased::get_n50(&phaseblocks, phased_bases),
-            });
+    let phaseblocks = if let Some(phaseblocks) = metrics_data.phaseblocks.as_ref() {
+        if let Some(bed_file) = &args.phaseblocks_bed {
+            phased::write_phaseblocks_bed(
+                bed_file,
+                phaseblocks,
+                &bam::HeaderView::from_header(header),
+            )?;
         }
-        Some(phaseblocks)
+        // phaseblock lengths are sorted in descending order (required for N50)
+        let mut phaseblock_lengths = phaseblocks.iter().map(|b| b.length()).collect::<Vec<_>>();
+        phaseblock_lengths.sort_unstable_by(|a, b| b.cmp(a));
+
+        let phased_bases = phaseblock_lengths.iter().sum::<i64>();
+        let phase_counts = metrics_data
+            .phase_counts
+            .as_ref()
+            .expect("Phase counts are missing");
+        let mut phased_reads = 0;
+        let mut genome_haplotypes = phased::HaplotypeCounts::default();
+        for counts in phase_counts.values() {
+            phased_reads += counts.phased_reads;
+            genome_haplotypes.merge(&counts.haplotypes);
+        }
+
+        // the haplotypes (HP tag) are summarised also without phase blocks (PS tag)
+        metrics_obj.phase_stats = Some(metrics::PhaseStats {
+            fraction_phased: (phased_reads as f32) / (num_reads as f32),
+            num_phaseblocks: phaseblocks.len(),
+            total_bases_phased_gb: phased_bases as f64 / 1e9,
+            median_phaseblock_length: if phaseblock_lengths.is_empty() {
+                0.0
+            } else {
+                phased::median(&phaseblock_lengths)
+            },
+            n50_phaseblock_length: if phaseblock_lengths.is_empty() {
+                0
+            } else {
+                phased::get_n50(&phaseblock_lengths, phased_bases)
+            },
+            fraction_genome_phased: phased::fraction_genome_phased(phaseblocks, genome_size),
+            region_stats: match &args.phaseblocks_regions {
+                Some(bed) => Some(phased::region_phase_stats(
+                    phaseblocks,
+                    &bed::read_regions(bed)?,
+                    &bam::HeaderView::from_header(header),
+                )),
+                None => None,
+            },
+            haplotypes: genome_haplotypes.to_stats(),
+            chromosomes: phased::chromosome_phase_stats(
+                &bam::HeaderView::from_header(header),
+                phase_counts,
+                phaseblocks,
+            ),
+        });
+        Some(phaseblock_lengths)
     } else {
         None
     };
 
     // Add karyotype data if requested
     if args.karyotype {
-        let head_view = bam::HeaderView::from_header(&header);
-        let mut tidcount = HashMap::new();
-
-        for tid in metrics_data.tids.as_ref().expect("TIDs data is missing") {
-            *tidcount.entry(*tid).or_default() += 1;
-        }
+        let head_view = bam::HeaderView::from_header(header);
+        let tidcount = metrics_data
+            .tid_counts
+            .as_ref()
+            .expect("TIDs data is missing");
 
         let mut karyotype_data = Vec::new();
         for (tid, count) in tidcount.iter() {
@@ -266,6 +525,13 @@ pub fn process_metrics(
                 let chrom_length = head_view
                     .target_len((*tid).try_into().expect("Failed to convert TID to usize"))
                     .unwrap();
+                if args
+                    .contigs
+                    .as_ref()
+                    .is_some_and(|contigs| !contigs.is_match(chrom))
+                {
+                    continue;
+                }
                 let norm_count = (*count as f32) / (chrom_length as f32);
 
                 karyotype_data.push(metrics::ChromosomeData {
@@ -280,8 +546,9 @@ pub fn process_metrics(
     }
 
     // Add splicing metrics if requested
-    if args.spliced && metrics_data.exons.is_some() {
-        let exon_counts = metrics_data.exons.as_ref().unwrap();
+    if args.spliced
+        && let Some(exon_counts) = metrics_data.exons.as_ref()
+    {
         let num_reads = exon_counts.len();
         let num_single_exon = exon_counts.iter().filter(|&&x| x == 1).count();
 
@@ -289,46 +556,32 @@ pub fn process_metrics(
             median_exons: calculations::median_splice(exon_counts),
             mean_exons: (exon_counts.iter().sum::<usize>() as f32) / (num_reads as f32),
             fraction_unspliced: (num_single_exon as f32) / (num_reads as f32),
+            intron_lengths: metrics_data
+                .intron_lengths
+                .as_ref()
+                .and_then(|introns| calculations::length_distribution(introns)),
+            exon_lengths: metrics_data
+                .exon_lengths
+                .as_ref()
+                .and_then(|exons| calculations::length_distribution(exons)),
         });
+    } else if args.spliced
+        && let Some(sketch) = &metrics_data.splice_sketch
+    {
+        metrics_obj.splice_stats = Some(sketch.splice_stats());
     }
 
-    // Output based on selected format
-    match args.format {
-        OutputFormat::Text => {
-            // Print text output using the collected metrics
-            crate::text_output::print_text_output(&metrics_obj);
-            if let Some(hist_file) = &args.hist {
-                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
-            }
-            // Handle --hist-count flag (output histogram counts after metrics)
-            if let Some(hist_count_file) = &args.hist_count {
-                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
-            }
-        }
-        OutputFormat::Json => {
-            if hist_requested {
-                metrics_obj.histograms = Some(histograms::build_histograms(&metrics_data));
-            }
-            println!("{}", serde_json::to_string_pretty(&metrics_obj).unwrap());
-            if let Some(hist_file) = &args.hist {
-                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
-            }
-            // Handle --hist-count flag (output histogram counts after metrics)
-            if let Some(hist_count_file) = &args.hist_count {
-                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
-            }
-        }
-        OutputFormat::Tsv => {
-            crate::tsv_output::print_tsv_output(&metrics_obj);
-            if let Some(hist_file) = &args.hist {
-                histograms::create_histograms(&metrics_data, hist_file, phaseblocks, args.scaled)?;
-            }
-            // Handle --hist-count flag (output histogram counts after metrics)
-            if let Some(hist_count_file) = &args.hist_count {
-                histograms::output_histogram_counts(&metrics_data, hist_count_file, args.scaled)?;
-            }
-        }
-    }
+    Ok((metrics_obj, phaseblocks))
+}
 
-    Ok(())
+/// Checks if no reads passed the filters
+fn no_reads(metrics_data: &Data) -> bool {
+    metrics_data
+        .lengths
+        .as_deref()
+        .is_none_or(|lengths| lengths.is_empty())
+        && metrics_data
+            .length_sketch
+            .as_ref()
+            .is_none_or(|sketch| sketch.is_empty())
 }
@@ -0,0 +1,320 @@
+use crate::metrics;
+use serde_json::{Map, Value, json};
+use std::fs;
+use std::path::Path;
+
+/// Writes the metrics as MultiQC custom content files (<sample>_<section>_mqc.json)
+/// MultiQC combines the sections with the same id of all samples in a single table or plot
+pub fn write_multiqc(
+    dir: &str,
+    metrics: &metrics::Metrics,
+    histograms: Option<&metrics::Histograms>,
+    scaled: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let sample = sample_name(&metrics.file_info.name);
+    for section in sections(&sample, metrics, histograms, scaled) {
+        let id = section["id"].as_str().expect("MultiQC section without id");
+        let path = Path::new(dir).join(format!("{sample}_{id}_mqc.json"));
+        fs::write(path, serde_json::to_string_pretty(&section)?)?;
+    }
+    Ok(())
+}
+
+/// The sample name is the file name without the bam/cram extension
+fn sample_name(file_name: &str) -> String {
+    [".bam", ".cram", ".sam"]
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(extension))
+        .unwrap_or(file_name)
+        .to_string()
+}
+
+fn sections(
+    sample: &str,
+    metrics: &metrics::Metrics,
+    histograms: Option<&metrics::Histograms>,
+    scaled: bool,
+) -> Vec<Value> {
+    let mut sections = vec![general_stats(sample, metrics)];
+    if let Some(histograms) = histograms {
+        let plots = [
+            (
+                "cramino_read_length",
+                "Read length distribution",
+                "Read length (bp)",
+                Some(&histograms.read_length),
+            ),
+            (
+                "cramino_q_score",
+                "Read identity distribution",
+                "Phred-scaled identity",
+                histograms.q_score.as_ref(),
+            ),
+            (
+                "cramino_intron_length",
+                "Intron length distribution",
+                "Intron length (bp)",
+                histograms.intron_length.as_ref(),
+            ),
+            (
+                "cramino_exon_length",
+                "Exon length distribution",
+                "Exon length (bp)",
+                histograms.exon_length.as_ref(),
+            ),
+        ];
+        for (id, name, xlab, histogram) in plots {
+            // e.g. the read lengths are not kept with --sketch
+            if let Some(histogram) = histogram.filter(|histogram| !histogram.bins.is_empty()) {
+                sections.push(line_graph(sample, id, name, xlab, histogram, scaled));
+            }
+        }
+    }
+    if let Some(karyotype) = &metrics.karyotype_stats {
+        sections.push(karyotype_bar_graph(sample, karyotype));
+    }
+    if let Some(qc) = &metrics.qc {
+        sections.push(qc_table(sample, qc));
+    }
+    sections
+}
+
+/// Columns for the General Statistics table
+fn general_stats(sample: &str, metrics: &metrics::Metrics) -> Value {
+    let mut columns = vec![
+        (
+            "yield_gb",
+            json!(metrics.read_stats.yield_gb),
+            json!({"title": "Yield", "description": "Yield (Gb)", "suffix": " Gb", "scale": "Blues", "format": "{:,.2f}"}),
+        ),
+        (
+            "mean_coverage",
+            json!(metrics.read_stats.mean_coverage),
+            json!({"title": "Coverage", "description": "Mean coverage", "suffix": "X", "scale": "Greens", "format": "{:,.2f}"}),
+        ),
+        (
+            "num_reads",
+            json!(metrics.alignment_stats.num_reads),
+            json!({"title": "Reads", "description": "Number of reads", "scale": "Purples", "format": "{:,.0f}"}),
+        ),
+        (
+            "n50",
+            json!(metrics.read_stats.n50),
+            json!({"title": "Read N50", "description": "Read length N50", "suffix": " bp", "scale": "RdYlGn", "format": "{:,.0f}"}),
+        ),
+        (
+            "median_length",
+            json!(metrics.read_stats.median_length),
+            json!({"title": "Median length", "description": "Median read length", "suffix": " bp", "scale": "RdYlGn", "format": "{:,.0f}"}),
+        ),
+    ];
+    if let Some(identity_stats) = &metrics.identity_stats {
+        columns.push((
+            "median_identity",
+            json!(identity_stats.median_identity),
+            json!({"title": "Identity", "description": "Median (gap-compressed) identity", "suffix": "%", "max": 100, "scale": "RdYlGn", "format": "{:,.2f}"}),
+        ));
+    }
+    if let Some(phase_stats) = &metrics.phase_stats {
+        columns.push((
+            "fraction_phased",
+            json!(phase_stats.fraction_phased),
+            json!({"title": "Phased", "description": "Fraction of phased reads", "min": 0, "max": 1, "scale": "Oranges", "format": "{:,.2f}"}),
+        ));
+    }
+    if let Some(qc) = &metrics.qc {
+        columns.push((
+            "qc_status",
+            json!(qc.status),
+            json!({"title": "QC", "description": "Status of the rules of --qc-thresholds (PASS, WARN or FAIL)"}),
+        ));
+    }
+    let mut values = Map::new();
+    let mut pconfig = Vec::new();
+    for (name, value, config) in columns {
+        values.insert(name.to_string(), value);
+        pconfig.push(json!({ name: config }));
+    }
+    json!({
+        "id": "cramino_general_stats",
+        "plot_type": "generalstats",
+        "pconfig": pconfig,
+        "data": { sample: values },
+    })
+}
+
+/// A line graph of the histogram counts (or bases with --scaled), at the start of every bin
+fn line_graph(
+    sample: &str,
+    id: &str,
+    name: &str,
+    xlab: &str,
+    histogram: &metrics::Histogram,
+    scaled: bool,
+) -> Value {
+    let points = histogram
+        .bins
+        .iter()
+        .map(|bin| {
+            let value = if scaled {
+                json!(bin.bases)
+            } else {
+                json!(bin.count)
+            };
+            (bin.start.to_string(), value)
+        })
+        .collect::<Map<_, _>>();
+    json!({
+        "id": id,
+        "section_name": name,
+        "description": format!("{name}, from cramino"),
+        "plot_type": "linegraph",
+        "pconfig": {
+            "id": format!("{id}_plot"),
+            "title": format!("cramino: {name}"),
+            "xlab": xlab,
+            "ylab": if scaled { "Bases" } else { "Reads" },
+        },
+        "data": { sample: points },
+    })
+}
+
+/// The number of reads per chromosome, which MultiQC can also show as percentages
+fn karyotype_bar_graph(sample: &str, karyotype: &[metrics::ChromosomeData]) -> Value {
+    let mut karyotype = karyotype.iter().collect::<Vec<_>>();
+    karyotype.sort_by(|a, b| a.chromosome.cmp(&b.chromosome));
+    let counts = karyotype
+        .iter()
+        .map(|chromosome| (chromosome.chromosome.clone(), json!(chromosome.count)))
+        .collect::<Map<_, _>>();
+    json!({
+        "id": "cramino_karyotype",
+        "section_name": "Reads per chromosome",
+        "description": "Number of reads per chromosome, from cramino",
+        "plot_type": "bargraph",
+        "pconfig": {
+            "id": "cramino_karyotype_plot",
+            "title": "cramino: Reads per chromosome",
+            "ylab": "Reads",
+            "cpswitch_c_active": false,
+        },
+        "data": { sample: counts },
+    })
+}
+
+/// The status of every rule of --qc-thresholds, with a column per rule
+/// the rules are the column ids, so that the same rules of all samples are combined
+fn qc_table(sample: &str, qc: &metrics::QcReport) -> Value {
+    let mut statuses = Map::new();
+    let mut headers = Map::new();
+    for result in &qc.rules {
+        statuses.insert(result.rule.clone(), json!(result.status));
+        headers.insert(
+            result.rule.clone(),
+            json!({"title": result.rule, "description": format!("Status of {} (PASS, WARN or FAIL)", result.metric)}),
+        );
+    }
+    json!({
+        "id": "cramino_qc",
+        "section_name": "QC thresholds",
+        "description": "Status of the rules of --qc-thresholds (PASS, WARN or FAIL), from cramino",
+        "plot_type": "table",
+        "pconfig": {
+            "id": "cramino_qc_table",
+            "title": "cramino: QC thresholds",
+        },
+        "headers": headers,
+        "data": { sample: statuses },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multiqc_sections() {
+        let mut report = metrics::Metrics::new(metrics::FileInfo {
+            name: "sample1.cram".to_string(),
+            path: "/data/sample1.cram".to_string(),
+            creation_time: "NA".to_string(),
+        });
+        report.read_stats.n50 = 15_000;
+        report.karyotype_stats = Some(vec![
+            metrics::ChromosomeData {
+                chromosome: "chr2".to_string(),
+                count: 5,
+                normalized_count: 0.1,
+            },
+            metrics::ChromosomeData {
+                chromosome: "chr1".to_string(),
+                count: 10,
+                normalized_count: 0.1,
+            },
+        ]);
+        let histograms = metrics::Histograms {
+            read_length: metrics::Histogram {
+                step: 2000,
+                max_value: 4000,
+                bins: vec![metrics::HistogramBin {
+                    start: 2000,
+                    end: Some(4000),
+                    count: 3,
+                    bases: 9000,
+                }],
+            },
+            q_score: None,
+            intron_length: None,
+            exon_length: None,
+        };
+        let sample = sample_name(&report.file_info.name);
+        assert_eq!(sample, "sample1");
+        let sections = sections(&sample, &report, Some(&histograms), false);
+        let ids = sections
+            .iter()
+            .map(|section| section["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                "cramino_general_stats",
+                "cramino_read_length",
+                "cramino_karyotype"
+            ]
+        );
+        assert_eq!(sections[0]["data"]["sample1"]["n50"], 15_000);
+        assert_eq!(sections[1]["data"]["sample1"]["2000"], 3);
+        let chromosomes = sections[2]["data"]["sample1"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(chromosomes, ["chr1", "chr2"]);
+
+        report.qc = Some(metrics::QcReport {
+            status: metrics::QcStatus::Warn,
+            rules: vec![
+                metrics::QcRuleResult {
+                    rule: "read_stats.n50 >= 10000".to_string(),
+                    metric: "read_stats.n50".to_string(),
+                    value: Some(15_000.0),
+                    status: metrics::QcStatus::Pass,
+                },
+                metrics::QcRuleResult {
+                    rule: "mean_coverage >= 30".to_string(),
+                    metric: "read_stats.mean_coverage".to_string(),
+                    value: Some(0.0),
+                    status: metrics::QcStatus::Warn,
+                },
+            ],
+        });
+        let qc_sections = super::sections(&sample, &report, None, false);
+        assert_eq!(qc_sections[0]["data"]["sample1"]["qc_status"], "WARN");
+        let qc = qc_sections.last().unwrap();
+        assert_eq!(qc["id"], "cramino_qc");
+        assert_eq!(qc["plot_type"], "table");
+        assert_eq!(qc["data"]["sample1"]["read_stats.n50 >= 10000"], "PASS");
+        assert_eq!(qc["data"]["sample1"]["mean_coverage >= 30"], "WARN");
+    }
+}
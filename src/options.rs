@@ -0,0 +1,315 @@
+use crate::collectors::{CollectorFactory, MetricCollector};
+use crate::contigs::{self, ContigFilter};
+use crate::qc::QcThresholds;
+use clap::Args;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// Options for calculating the metrics, independent of the command line
+///
+/// ```no_run
+/// let options = cramino::QcOptions::new()
+///     .threads(8)
+///     .min_read_len(1000)
+///     .karyotype(true);
+/// let metrics = cramino::metrics_from_path("sample.cram", &options).unwrap();
+/// println!("N50: {}", metrics.read_stats.n50);
+/// ```
+#[derive(Debug, Clone)]
+pub struct QcOptions {
+    pub(crate) threads: usize,
+    pub(crate) reference: Option<String>,
+    pub(crate) min_read_len: usize,
+    pub(crate) long_thresholds: Vec<u64>,
+    pub(crate) genome_size: Option<u64>,
+    pub(crate) contigs: Option<ContigFilter>,
+    pub(crate) sketch: bool,
+    pub(crate) read_level: bool,
+    pub(crate) length_stats: bool,
+    pub(crate) nx: Vec<u8>,
+    pub(crate) karyotype: bool,
+    pub(crate) phased: bool,
+    pub(crate) phaseblocks_regions: Option<String>,
+    pub(crate) spliced: bool,
+    pub(crate) ubam: bool,
+    pub(crate) lenient: bool,
+    pub(crate) progress: bool,
+    pub(crate) histograms: bool,
+    pub(crate) qc_thresholds: Option<QcThresholds>,
+    pub(crate) arrow: Option<String>,
+    pub(crate) read_table: Option<String>,
+    pub(crate) read_table_tags: Vec<String>,
+    pub(crate) phaseblocks_bed: Option<String>,
+    pub(crate) collectors: Vec<CollectorFactory>,
+}
+
+impl Default for QcOptions {
+    /// The defaults of the command line
+    fn default() -> Self {
+        QcOptions {
+            threads: 4,
+            reference: None,
+            min_read_len: 0,
+            long_thresholds: vec![25_000],
+            genome_size: None,
+            contigs: None,
+            sketch: false,
+            read_level: false,
+            length_stats: false,
+            nx: vec![10, 50, 90],
+            karyotype: false,
+            phased: false,
+            phaseblocks_regions: None,
+            spliced: false,
+            ubam: false,
+            lenient: false,
+            progress: false,
+            histograms: false,
+            qc_thresholds: None,
+            arrow: None,
+            read_table: None,
+            read_table_tags: Vec::new(),
+            phaseblocks_bed: None,
+            collectors: Vec::new(),
+        }
+    }
+}
+
+impl QcOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of parallel decompression threads
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Reference for decompressing cram
+    pub fn reference(mut self, reference: impl Into<String>) -> Self {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    /// Minimal length of the reads to be considered
+    pub fn min_read_len(mut self, min_read_len: usize) -> Self {
+        self.min_read_len = min_read_len;
+        self
+    }
+
+    /// Read length thresholds for the yield of long reads
+    pub fn long_thresholds(mut self, long_thresholds: Vec<u64>) -> Self {
+        self.long_thresholds = long_thresholds;
+        self
+    }
+
+    /// Genome size for the mean coverage, instead of the sum of the contig lengths
+    pub fn genome_size(mut self, genome_size: u64) -> Self {
+        self.genome_size = Some(genome_size);
+        self
+    }
+
+    /// Contigs for the coverage and karyotype
+    pub fn contigs(mut self, contigs: ContigFilter) -> Self {
+        self.contigs = Some(contigs);
+        self
+    }
+
+    /// Bounded-memory sketches for the read length and identity metrics
+    pub fn sketch(mut self, sketch: bool) -> Self {
+        self.sketch = sketch;
+        self
+    }
+
+    /// Read length stats per read instead of per alignment
+    pub fn read_level(mut self, read_level: bool) -> Self {
+        self.read_level = read_level;
+        self
+    }
+
+    /// Query length, aligned length and reference span separately
+    pub fn length_stats(mut self, length_stats: bool) -> Self {
+        self.length_stats = length_stats;
+        self
+    }
+
+    /// Nx statistics, as percentages of the yield
+    pub fn nx(mut self, nx: Vec<u8>) -> Self {
+        self.nx = nx;
+        self
+    }
+
+    /// Normalized number of reads per chromosome
+    pub fn karyotype(mut self, karyotype: bool) -> Self {
+        self.karyotype = karyotype;
+        self
+    }
+
+    /// Metrics of phased reads
+    pub fn phased(mut self, phased: bool) -> Self {
+        self.phased = phased;
+        self
+    }
+
+    /// BED file with regions for the phase block coverage, with phased
+    pub fn phaseblocks_regions(mut self, bed: impl Into<String>) -> Self {
+        self.phaseblocks_regions = Some(bed.into());
+        self
+    }
+
+    /// Metrics of spliced reads
+    pub fn spliced(mut self, spliced: bool) -> Self {
+        self.spliced = spliced;
+        self
+    }
+
+    /// Metrics of unaligned reads, which can't be combined with karyotype, phased, spliced,
+    /// length_stats or read_level
+    pub fn ubam(mut self, ubam: bool) -> Self {
+        self.ubam = ubam;
+        self
+    }
+
+    /// Skip invalid records (e.g. with a negative NM tag) and report them in the warnings,
+    /// instead of failing with a CraminoError::InvalidRecord
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Print the progress of reading the input to stderr
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Collect the histogram of the identities, next to the metrics
+    pub fn histograms(mut self, histograms: bool) -> Self {
+        self.histograms = histograms;
+        self
+    }
+
+    /// Rules to evaluate on the metrics, in the qc section of the report
+    pub fn qc_thresholds(mut self, qc_thresholds: QcThresholds) -> Self {
+        self.qc_thresholds = Some(qc_thresholds);
+        self
+    }
+
+    /// Write the lengths and identities to an arrow file while reading
+    pub fn arrow(mut self, path: impl Into<String>) -> Self {
+        self.arrow = Some(path.into());
+        self
+    }
+
+    /// Write a table with QC metrics per read while reading, with the values of the aux tags as columns
+    pub fn read_table(mut self, path: impl Into<String>, tags: Vec<String>) -> Self {
+        self.read_table = Some(path.into());
+        self.read_table_tags = tags;
+        self
+    }
+
+    /// Write the phase blocks to a BED file, with phased
+    pub fn phaseblocks_bed(mut self, path: impl Into<String>) -> Self {
+        self.phaseblocks_bed = Some(path.into());
+        self
+    }
+
+    /// Register a collector for other metrics, created for every input
+    /// Its partial state is (de)serialized for state files, to merge it with that of other inputs
+    pub fn collector<C, F>(mut self, new: F) -> Self
+    where
+        C: MetricCollector + Serialize + DeserializeOwned,
+        F: Fn() -> C + Send + Sync + 'static,
+    {
+        self.collectors.push(CollectorFactory::new(new));
+        self
+    }
+}
+
+/// Options of the command line for reading the inputs and calculating the metrics,
+/// shared by cramino and cramino watch
+#[derive(Args, Debug, Clone)]
+pub struct MetricArgs {
+    /// Number of parallel decompression threads to use
+    #[clap(short, long, value_parser, default_value_t = 4)]
+    pub(crate) threads: usize,
+
+    /// reference for decompressing cram
+    #[clap(long, value_parser)]
+    pub(crate) reference: Option<String>,
+
+    /// Minimal length of read to be considered
+    #[clap(short, long, value_parser, default_value_t = 0)]
+    pub(crate) min_read_len: usize,
+
+    /// Read length thresholds for reporting the yield of long reads, comma-separated (e.g. 1000,50000,100000)
+    #[clap(long, value_parser, value_delimiter = ',', default_values_t = vec![25_000])]
+    pub(crate) long_thresholds: Vec<u64>,
+
+    /// Genome size used for the mean coverage, optionally with a k, m or g suffix (e.g. 3.1g) [default: sum of the contig lengths]
+    #[clap(long, value_parser = contigs::parse_genome_size)]
+    pub(crate) genome_size: Option<u64>,
+
+    /// Contigs to use for the coverage and karyotype: "primary" (autosomes and sex chromosomes) or a comma-separated list of names or regular expressions
+    #[clap(long, value_parser)]
+    pub(crate) contigs: Option<ContigFilter>,
+
+    /// Nx statistics to report, as comma-separated percentages of the yield (e.g. 10,50,90)
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=100), value_delimiter = ',', default_values_t = vec![10, 50, 90])]
+    pub(crate) nx: Vec<u8>,
+
+    /// Provide normalized number of reads per chromosome
+    #[clap(long, value_parser)]
+    pub(crate) karyotype: bool,
+
+    /// Provide metrics for unaligned reads
+    #[clap(long, value_parser)]
+    pub(crate) ubam: bool,
+}
+
+impl From<&MetricArgs> for QcOptions {
+    fn from(args: &MetricArgs) -> Self {
+        QcOptions {
+            threads: args.threads,
+            reference: args.reference.clone(),
+            min_read_len: args.min_read_len,
+            long_thresholds: args.long_thresholds.clone(),
+            genome_size: args.genome_size,
+            contigs: args.contigs.clone(),
+            nx: args.nx.clone(),
+            // unaligned reads have no chromosome
+            karyotype: args.karyotype && !args.ubam,
+            ubam: args.ubam,
+            ..QcOptions::default()
+        }
+    }
+}
+
+impl From<&crate::Cli> for QcOptions {
+    fn from(args: &crate::Cli) -> Self {
+        QcOptions {
+            sketch: args.sketch,
+            read_level: args.read_level,
+            length_stats: args.length_stats,
+            phased: args.phased,
+            phaseblocks_regions: args.phaseblocks_regions.clone(),
+            spliced: args.spliced,
+            lenient: args.lenient,
+            progress: args.progress,
+            // the MultiQC, HTML and plot output include the histograms,
+            // and the state has them for the reports of merged states
+            histograms: args.hist.is_some()
+                || args.state.is_some()
+                || args.hist_count.is_some()
+                || args.multiqc_dir.is_some()
+                || args.html.is_some()
+                || args.plot_dir.is_some(),
+            qc_thresholds: args.qc_thresholds.clone(),
+            arrow: args.arrow.clone(),
+            read_table: args.read_table.clone(),
+            read_table_tags: args.read_table_tags.clone(),
+            phaseblocks_bed: args.phaseblocks_bed.clone(),
+            ..QcOptions::from(&args.metrics)
+        }
+    }
+}
@@ -1,40 +1,354 @@
-use itertools::izip;
-use log::error;
-
-pub fn phase_metrics(
-    tids: &[i32],
-    starts: Vec<i64>,
-    ends: Vec<i64>,
-    phasesets: &Vec<Option<u32>>,
-) -> Vec<i64> {
-    let mut phased_reads = izip!(tids, starts, ends, phasesets)
-        .filter(|(_, _, _, p)| p.is_some())
-        .collect::<Vec<_>>();
+use crate::metrics;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// A phase block is a run of alignments on a chromosome with the same phaseset (PS tag),
+/// when sorted by position: it ends where an alignment with another phaseset starts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseBlock {
+    pub tid: i32,
+    pub start: i64,
+    pub end: i64,
+    pub phaseset: u32,
+    pub num_reads: usize,
+}
+
+impl PhaseBlock {
+    pub fn length(&self) -> i64 {
+        self.end - self.start
+    }
+}
+
+/// Number of alignments and bases per haplotype (HP tag)
+/// index 0 is for alignments without (valid) HP tag, 1 and 2 for the haplotypes
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct HaplotypeCounts {
+    pub reads: [usize; 3],
+    pub bases: [u128; 3],
+}
+
+impl HaplotypeCounts {
+    pub fn add(&mut self, haplotype: u8, bases: u128) {
+        let index = if haplotype <= 2 {
+            haplotype as usize
+        } else {
+            0
+        };
+        self.reads[index] += 1;
+        self.bases[index] += bases;
+    }
+
+    pub fn merge(&mut self, other: &HaplotypeCounts) {
+        for index in 0..3 {
+            self.reads[index] += other.reads[index];
+            self.bases[index] += other.bases[index];
+        }
+    }
+
+    pub fn to_stats(self) -> metrics::HaplotypeStats {
+        let haplotagged_bases = self.bases[1] + self.bases[2];
+        metrics::HaplotypeStats {
+            hp1_reads: self.reads[1],
+            hp2_reads: self.reads[2],
+            unassigned_reads: self.reads[0],
+            hp1_bases: self.bases[1],
+            hp2_bases: self.bases[2],
+            unassigned_bases: self.bases[0],
+            hp1_fraction: if haplotagged_bases > 0 {
+                self.bases[1] as f64 / haplotagged_bases as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// Alignment counts per chromosome, used for the per chromosome phasing summary
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ChromosomePhaseCounts {
+    pub reads: usize,
+    pub phased_reads: usize,
+    pub haplotypes: HaplotypeCounts,
+}
+
+impl ChromosomePhaseCounts {
+    pub fn add(&mut self, phased: bool, haplotype: u8, bases: u128) {
+        self.reads += 1;
+        if phased {
+            self.phased_reads += 1;
+        }
+        self.haplotypes.add(haplotype, bases);
+    }
+
+    pub fn merge(&mut self, other: &ChromosomePhaseCounts) {
+        self.reads += other.reads;
+        self.phased_reads += other.phased_reads;
+        self.haplotypes.merge(&other.haplotypes);
+    }
+}
+
+/// Builds phase blocks from (tid, start, end, phaseset) of all phased alignments
+/// this requires keeping all phased alignments in memory, see PhaseBlockBuilder for sorted input
+pub fn phase_metrics(mut phased_reads: Vec<(i32, i64, i64, u32)>) -> Vec<PhaseBlock> {
     phased_reads.sort_unstable();
+    let mut runs = PhaseBlockRuns::default();
+    for read in phased_reads {
+        runs.add(read);
+    }
+    runs.finish()
+}
+
+/// Splits alignments, sorted by (tid, start, end, phaseset), into phase blocks
+#[derive(Debug, Default)]
+struct PhaseBlockRuns {
+    block: Option<PhaseBlock>,
+    blocks: Vec<PhaseBlock>,
+}
+
+impl PhaseBlockRuns {
+    fn add(&mut self, (tid, start, end, phaseset): (i32, i64, i64, u32)) {
+        match &mut self.block {
+            Some(block) if block.tid == tid && block.phaseset == phaseset => {
+                // the block ends at the end of its last alignment
+                block.end = end;
+                block.num_reads += 1;
+            }
+            _ => {
+                self.blocks.extend(self.block.replace(PhaseBlock {
+                    tid,
+                    start,
+                    end,
+                    phaseset,
+                    num_reads: 1,
+                }));
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<PhaseBlock> {
+        self.blocks.extend(self.block);
+        if self.blocks.is_empty() {
+            error!("Not a single phased read found!");
+        }
+        self.blocks
+    }
+}
+
+/// Builds phase blocks on the fly from coordinate-sorted alignments
+/// Only the alignments at the current position are kept, to sort them by end and phaseset
+/// The result is identical to phase_metrics, without keeping every phased alignment in memory
+#[derive(Debug, Default)]
+pub struct PhaseBlockBuilder {
+    same_start: Vec<(i32, i64, i64, u32)>,
+    runs: PhaseBlockRuns,
+    unsorted: bool,
+}
+
+impl PhaseBlockBuilder {
+    pub fn add(&mut self, tid: i32, start: i64, end: i64, phaseset: u32) {
+        if let Some(&(last_tid, last_start, _, _)) = self.same_start.last()
+            && (tid, start) != (last_tid, last_start)
+        {
+            if (tid, start) < (last_tid, last_start) && !self.unsorted {
+                warn!("Input is not coordinate-sorted, phase blocks may be split");
+                self.unsorted = true;
+            }
+            self.add_same_start();
+        }
+        self.same_start.push((tid, start, end, phaseset));
+    }
+
+    fn add_same_start(&mut self) {
+        self.same_start.sort_unstable();
+        for read in self.same_start.drain(..) {
+            self.runs.add(read);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<PhaseBlock> {
+        self.add_same_start();
+        self.runs.finish()
+    }
+}
+
+/// Combines the phase blocks of shards of the input: sorted by position, consecutive blocks with
+/// the same chromosome and phaseset are one block, as the run of alignments continues in the next shard
+/// This gives the phase blocks of the whole input for shards of consecutive regions, which
+/// State::merge checks with the range of the phased alignments of the shards
+pub fn merge_phaseblocks(mut phaseblocks: Vec<PhaseBlock>) -> Vec<PhaseBlock> {
+    phaseblocks.sort_unstable_by_key(|b| (b.tid, b.start, b.end, b.phaseset));
+    let mut merged: Vec<PhaseBlock> = Vec::with_capacity(phaseblocks.len());
+    for block in phaseblocks {
+        match merged.last_mut() {
+            Some(last) if (last.tid, last.phaseset) == (block.tid, block.phaseset) => {
+                last.end = block.end;
+                last.num_reads += block.num_reads;
+            }
+            _ => merged.push(block),
+        }
+    }
+    merged
+}
+
+/// Writes the phase blocks as BED: chromosome, start, end, phaseset and number of reads
+pub fn write_phaseblocks_bed(
+    path: &str,
+    phaseblocks: &[PhaseBlock],
+    header: &rust_htslib::bam::HeaderView,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for block in phaseblocks {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{}\t{}",
+            String::from_utf8_lossy(header.tid2name(block.tid as u32)),
+            block.start,
+            block.end,
+            block.phaseset,
+            block.num_reads
+        )?;
+    }
+    writer.flush()
+}
+
+/// Merges overlapping phase blocks, returning sorted and non-overlapping intervals per chromosome
+fn merged_intervals(phaseblocks: &[PhaseBlock]) -> HashMap<i32, Vec<(i64, i64)>> {
+    let mut per_tid: HashMap<i32, Vec<(i64, i64)>> = HashMap::new();
+    for block in phaseblocks {
+        per_tid
+            .entry(block.tid)
+            .or_default()
+            .push((block.start, block.end));
+    }
+    for intervals in per_tid.values_mut() {
+        intervals.sort_unstable();
+        let mut merged: Vec<(i64, i64)> = Vec::with_capacity(intervals.len());
+        for &(start, end) in intervals.iter() {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        *intervals = merged;
+    }
+    per_tid
+}
+
+/// Fraction of the genome spanned by at least one phase block
+pub fn fraction_genome_phased(phaseblocks: &[PhaseBlock], genome_size: u64) -> f64 {
+    if genome_size == 0 {
+        return 0.0;
+    }
+    let spanned = merged_intervals(phaseblocks)
+        .values()
+        .flatten()
+        .map(|(start, end)| end - start)
+        .sum::<i64>();
+    spanned as f64 / genome_size as f64
+}
 
-    let num_phased_reads = phased_reads.len();
-    if num_phased_reads == 0 {
-        error!("Not a single phased read found!");
-        return vec![];
+/// Phase block coverage of a set of regions (e.g. genes) from a BED file
+/// A region is fully phased if it is contained within a single phase block
+pub fn region_phase_stats(
+    phaseblocks: &[PhaseBlock],
+    regions: &[crate::bed::Region],
+    header: &rust_htslib::bam::HeaderView,
+) -> metrics::RegionPhaseStats {
+    let merged = merged_intervals(phaseblocks);
+    // blocks per chromosome sorted by start, with the running maximum of the end coordinate
+    let mut blocks_per_tid: HashMap<i32, (Vec<i64>, Vec<i64>)> = HashMap::new();
+    for block in phaseblocks {
+        let entry = blocks_per_tid.entry(block.tid).or_default();
+        let max_end = entry.1.last().copied().unwrap_or(0).max(block.end);
+        entry.0.push(block.start);
+        entry.1.push(max_end);
     }
 
-    let mut phased_reads_iter = phased_reads.into_iter();
-    let (mut chrom1, mut start1, mut block_end, mut phaseset1) = phased_reads_iter.next().unwrap();
-    let mut phaseblocks = vec![];
-    for (chrom, start, end, phaseset) in phased_reads_iter {
-        if chrom == chrom1 && phaseset == phaseset1 {
-            block_end = end;
+    let mut total_bases = 0;
+    let mut spanned_bases = 0;
+    let mut fully_phased = 0;
+    for region in regions {
+        total_bases += region.end - region.start;
+        let Some(tid) = header.tid(region.chrom.as_bytes()) else {
             continue;
-        } else {
-            phaseblocks.push(block_end - start1);
-            chrom1 = chrom;
-            start1 = start;
-            block_end = end;
-            phaseset1 = phaseset;
+        };
+        let tid = tid as i32;
+        if let Some(intervals) = merged.get(&tid) {
+            let first = intervals.partition_point(|(_, end)| *end <= region.start);
+            spanned_bases += intervals[first..]
+                .iter()
+                .take_while(|(start, _)| *start < region.end)
+                .map(|(start, end)| end.min(&region.end) - start.max(&region.start))
+                .sum::<i64>();
+        }
+        if let Some((block_starts, max_ends)) = blocks_per_tid.get(&tid) {
+            let index = block_starts.partition_point(|start| *start <= region.start);
+            if index > 0 && max_ends[index - 1] >= region.end {
+                fully_phased += 1;
+            }
         }
     }
-    phaseblocks.push(block_end - start1);
-    phaseblocks
+
+    metrics::RegionPhaseStats {
+        num_regions: regions.len(),
+        fraction_bases_phased: if total_bases > 0 {
+            spanned_bases as f64 / total_bases as f64
+        } else {
+            0.0
+        },
+        num_fully_phased: fully_phased,
+        fraction_fully_phased: if regions.is_empty() {
+            0.0
+        } else {
+            fully_phased as f64 / regions.len() as f64
+        },
+    }
+}
+
+/// Summarizes phasing per chromosome, in the order of the chromosomes in the header
+/// The fraction phased is the fraction of alignments on the chromosome with a PS tag
+pub fn chromosome_phase_stats(
+    header: &rust_htslib::bam::HeaderView,
+    phase_counts: &HashMap<i32, ChromosomePhaseCounts>,
+    phaseblocks: &[PhaseBlock],
+) -> Vec<metrics::ChromosomePhaseStats> {
+    let mut blocks_per_tid: HashMap<i32, Vec<i64>> = HashMap::new();
+    for block in phaseblocks {
+        blocks_per_tid
+            .entry(block.tid)
+            .or_default()
+            .push(block.length());
+    }
+
+    let mut tids_sorted = phase_counts
+        .keys()
+        .copied()
+        .filter(|t| *t >= 0)
+        .collect::<Vec<_>>();
+    tids_sorted.sort_unstable();
+    tids_sorted
+        .into_iter()
+        .map(|tid| {
+            let counts = phase_counts[&tid];
+            let mut blocks = blocks_per_tid.remove(&tid).unwrap_or_default();
+            blocks.sort_unstable_by(|a, b| b.cmp(a));
+            let phased_bases = blocks.iter().sum::<i64>();
+            metrics::ChromosomePhaseStats {
+                chromosome: String::from_utf8_lossy(header.tid2name(tid as u32)).to_string(),
+                fraction_phased: counts.phased_reads as f32 / counts.reads as f32,
+                num_phaseblocks: blocks.len(),
+                n50_phaseblock_length: if blocks.is_empty() {
+                    0
+                } else {
+                    get_n50(&blocks, phased_bases)
+                },
+                haplotypes: counts.haplotypes.to_stats(),
+            }
+        })
+        .collect()
 }
 
 pub fn median(array: &[i64]) -> f64 {
@@ -58,3 +372,174 @@ pub fn get_n50(lengths: &[i64], nb_bases_total: i64) -> i64 {
 
     lengths[lengths.len() - 1]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_metrics_blocks() {
+        let phased_reads = vec![
+            (0, 100, 1100, 100),
+            (0, 200, 1200, 100),
+            (0, 5000, 6000, 5000),
+            (0, 300, 800, 100),
+            (1, 10, 1010, 10),
+        ];
+        let blocks = phase_metrics(phased_reads);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[0],
+            PhaseBlock {
+                tid: 0,
+                start: 100,
+                end: 800,
+                phaseset: 100,
+                num_reads: 3,
+            }
+        );
+        assert_eq!(blocks[1].length(), 1000);
+        assert_eq!(blocks[2].tid, 1);
+    }
+
+    #[test]
+    fn test_phase_blocks_split_by_interleaved_phasesets() {
+        // a read of phaseset 900 between those of phaseset 100 ends the block of phaseset 100,
+        // so there are four blocks rather than one per phaseset
+        let phased_reads = vec![
+            (0, 100, 1100, 100),
+            (0, 200, 5200, 100),
+            (0, 900, 1900, 900),
+            (0, 1000, 2500, 100),
+            (0, 1500, 2000, 900),
+        ];
+        let blocks = phase_metrics(phased_reads);
+        let spans = blocks
+            .iter()
+            .map(|block| (block.phaseset, block.start, block.end))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            vec![
+                (100, 100, 5200),
+                (900, 900, 1900),
+                (100, 1000, 2500),
+                (900, 1500, 2000)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_streaming_phase_blocks_match_sorted() {
+        // coordinate-sorted reads with interleaved phasesets, and reads at the same position
+        // in another order than (end, phaseset)
+        let phased_reads = vec![
+            (0, 100, 1100, 100),
+            (0, 200, 5200, 100),
+            (0, 900, 1900, 900),
+            (0, 900, 1900, 100),
+            (0, 900, 1200, 900),
+            (0, 1000, 2500, 900),
+            (2, 10, 1010, 10),
+            (2, 400, 900, 500),
+        ];
+        let mut builder = PhaseBlockBuilder::default();
+        for (tid, start, end, phaseset) in &phased_reads {
+            builder.add(*tid, *start, *end, *phaseset);
+        }
+        assert_eq!(builder.finish(), phase_metrics(phased_reads));
+    }
+
+    fn block(tid: i32, start: i64, end: i64) -> PhaseBlock {
+        PhaseBlock {
+            tid,
+            start,
+            end,
+            phaseset: start as u32,
+            num_reads: 1,
+        }
+    }
+
+    #[test]
+    fn test_merge_phaseblocks_of_consecutive_regions() {
+        let phased_reads = vec![
+            (0, 100, 600, 1),
+            (0, 300, 800, 2),
+            (0, 500, 900, 1),
+            (0, 1000, 1500, 1),
+            (0, 1200, 1800, 1),
+            (1, 50, 400, 1),
+        ];
+        // shards split at position 1000, the last phase block continues in the second shard
+        let (first, second): (Vec<_>, Vec<_>) = phased_reads
+            .iter()
+            .copied()
+            .partition(|(tid, start, _, _)| *tid == 0 && *start < 1000);
+        let mut blocks = phase_metrics(first);
+        blocks.extend(phase_metrics(second));
+        assert_eq!(merge_phaseblocks(blocks), phase_metrics(phased_reads));
+    }
+
+    #[test]
+    fn test_fraction_genome_phased_merges_overlaps() {
+        let blocks = vec![block(0, 0, 100), block(0, 50, 150), block(1, 0, 50)];
+        assert_eq!(fraction_genome_phased(&blocks, 1000), 0.2);
+        assert_eq!(fraction_genome_phased(&blocks, 0), 0.0);
+    }
+
+    #[test]
+    fn test_region_phase_stats() {
+        let mut header = rust_htslib::bam::Header::new();
+        for (name, len) in [("chr1", 10_000), ("chr2", 10_000)] {
+            let mut record = rust_htslib::bam::header::HeaderRecord::new(b"SQ");
+            record.push_tag(b"SN", name);
+            record.push_tag(b"LN", len);
+            header.push_record(&record);
+        }
+        let header = rust_htslib::bam::HeaderView::from_header(&header);
+        let blocks = vec![block(0, 0, 1000), block(0, 900, 2000), block(1, 500, 600)];
+        let regions = vec![
+            crate::bed::Region {
+                chrom: "chr1".to_string(),
+                start: 100,
+                end: 500,
+            },
+            // spans two overlapping blocks, so covered but not fully phased
+            crate::bed::Region {
+                chrom: "chr1".to_string(),
+                start: 800,
+                end: 1200,
+            },
+            crate::bed::Region {
+                chrom: "chr2".to_string(),
+                start: 0,
+                end: 1000,
+            },
+            crate::bed::Region {
+                chrom: "chrUnknown".to_string(),
+                start: 0,
+                end: 200,
+            },
+        ];
+        let stats = region_phase_stats(&blocks, &regions, &header);
+        assert_eq!(stats.num_regions, 4);
+        assert_eq!(stats.num_fully_phased, 1);
+        assert_eq!(stats.fraction_bases_phased, 900.0 / 2000.0);
+    }
+
+    #[test]
+    fn test_haplotype_counts() {
+        let mut counts = HaplotypeCounts::default();
+        counts.add(1, 300);
+        counts.add(2, 100);
+        counts.add(0, 50);
+        // values other than 1 and 2 are counted as unassigned
+        counts.add(3, 50);
+        let stats = counts.to_stats();
+        assert_eq!(stats.hp1_reads, 1);
+        assert_eq!(stats.hp2_reads, 1);
+        assert_eq!(stats.unassigned_reads, 2);
+        assert_eq!(stats.unassigned_bases, 100);
+        assert_eq!(stats.hp1_fraction, 0.75);
+    }
+}
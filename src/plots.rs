@@ -0,0 +1,539 @@
+use crate::extract_from_bam::Data;
+use crate::{histograms, metrics};
+use clap::ValueEnum;
+use resvg::{tiny_skia, usvg};
+use std::fmt::Write;
+use std::path::Path;
+
+const WIDTH: f64 = 720.0;
+const HEIGHT: f64 = 400.0;
+const MARGIN_LEFT: f64 = 80.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 40.0;
+const MARGIN_BOTTOM: f64 = 90.0;
+const BAR_COLOR: &str = "#4c72b0";
+/// Maximal number of labels on the x-axis, to keep them readable
+const MAX_X_LABELS: usize = 25;
+const PNG_SCALE: u32 = 2;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum PlotFormat {
+    Svg,
+    Png,
+}
+
+/// A bar chart of a histogram or of the reads per chromosome,
+/// rendered as SVG without depending on a plotting library
+pub struct BarChart {
+    /// short name, e.g. to use in a file name
+    pub name: &'static str,
+    pub title: String,
+    pub xlab: String,
+    pub ylab: String,
+    pub bars: Vec<(String, f64)>,
+    /// logarithmic y-axis, bars of 0 are not shown
+    pub log_scale: bool,
+}
+
+/// The plots for the HTML report: read length, identity, karyotype, phase block length, exon count
+/// and with --spliced the intron and exon block lengths
+pub fn charts(
+    metrics_data: &Data,
+    metrics: &metrics::Metrics,
+    phaseblocks: Option<&[i64]>,
+    scaled: bool,
+    log_scale: bool,
+) -> Vec<BarChart> {
+    let histograms = histograms::build_histograms(metrics_data);
+    let mut charts = length_charts(&histograms, scaled, log_scale);
+    charts.extend(other_charts(metrics_data, metrics, phaseblocks, log_scale));
+    charts
+}
+
+/// The plots for --plot-dir, with both the read count and basepair-weighted (scaled) histograms
+pub fn all_charts(
+    metrics_data: &Data,
+    metrics: &metrics::Metrics,
+    phaseblocks: Option<&[i64]>,
+    log_scale: bool,
+) -> Vec<BarChart> {
+    let histograms = histograms::build_histograms(metrics_data);
+    let mut charts = length_charts(&histograms, false, log_scale);
+    charts.extend(length_charts(&histograms, true, log_scale));
+    charts.extend(other_charts(metrics_data, metrics, phaseblocks, log_scale));
+    charts
+}
+
+/// Histograms of which the bins can be weighted by the number of bases
+fn length_charts(histograms: &metrics::Histograms, scaled: bool, log_scale: bool) -> Vec<BarChart> {
+    let plots = [
+        (
+            ["read_length", "read_length_scaled"],
+            "Read length distribution",
+            "Read length (bp)",
+            Some(&histograms.read_length),
+        ),
+        (
+            ["q_score", "q_score_scaled"],
+            "Read identity distribution",
+            "Phred-scaled identity (Q)",
+            histograms.q_score.as_ref(),
+        ),
+        (
+            ["intron_length", "intron_length_scaled"],
+            "Intron length distribution",
+            "Intron length (bp)",
+            histograms.intron_length.as_ref(),
+        ),
+        (
+            ["exon_length", "exon_length_scaled"],
+            "Exon block length distribution",
+            "Exon block length (bp)",
+            histograms.exon_length.as_ref(),
+        ),
+    ];
+    plots
+        .into_iter()
+        // e.g. the read lengths are not kept with --sketch
+        .filter_map(|(names, title, xlab, histogram)| {
+            histogram
+                .filter(|histogram| histogram.bins.iter().any(|bin| bin.count > 0))
+                .map(|histogram| BarChart {
+                    log_scale,
+                    ..BarChart::from_histogram(
+                        names[scaled as usize],
+                        title,
+                        xlab,
+                        if scaled { "Bases" } else { "Reads" },
+                        histogram,
+                        scaled,
+                    )
+                })
+        })
+        .collect()
+}
+
+/// The karyotype, phase block lengths and exon counts, of which only the histograms can have a log scale
+fn other_charts(
+    metrics_data: &Data,
+    metrics: &metrics::Metrics,
+    phaseblocks: Option<&[i64]>,
+    log_scale: bool,
+) -> Vec<BarChart> {
+    let mut charts = Vec::new();
+    if let Some(karyotype) = metrics.karyotype_stats.as_ref().filter(|k| !k.is_empty()) {
+        charts.push(BarChart::karyotype(karyotype));
+    }
+    if let Some(phaseblocks) = phaseblocks.filter(|p| !p.is_empty()) {
+        charts.push(BarChart {
+            log_scale,
+            ..BarChart::from_histogram(
+                "phaseblocks",
+                "Phase block length distribution",
+                "Phase block length (bp)",
+                "Phase blocks",
+                &histograms::build_phaseblock_histogram(phaseblocks),
+                false,
+            )
+        });
+    }
+    if let Some(exons) = histograms::build_exon_count_histogram(metrics_data)
+        .filter(|hist| hist.bins.iter().any(|bin| bin.count > 0))
+    {
+        let mut chart = BarChart {
+            log_scale,
+            ..BarChart::from_histogram(
+                "exons",
+                "Number of exons per read",
+                "Exons",
+                "Reads",
+                &exons,
+                false,
+            )
+        };
+        // reads have at least one exon
+        chart.bars.remove(0);
+        charts.push(chart);
+    }
+    charts
+}
+
+/// Writes the plots as <name>.svg and/or <name>.png to a directory
+pub fn write_plots(
+    dir: &str,
+    charts: &[BarChart],
+    formats: &[PlotFormat],
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+    // loading the system fonts is slow, and only needed once for all png files
+    let mut options = usvg::Options::default();
+    if formats.contains(&PlotFormat::Png) {
+        options.fontdb_mut().load_system_fonts();
+    }
+    for chart in charts {
+        let svg = chart.to_svg();
+        let path = Path::new(dir).join(chart.name);
+        for format in formats {
+            match format {
+                PlotFormat::Svg => std::fs::write(path.with_extension("svg"), &svg)?,
+                PlotFormat::Png => {
+                    std::fs::write(path.with_extension("png"), svg_to_png(&svg, &options)?)?
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Renders the svg at twice its size, to be sharp on slides
+fn svg_to_png(svg: &str, options: &usvg::Options) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let tree = usvg::Tree::from_str(svg, options)?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width() * PNG_SCALE, size.height() * PNG_SCALE)
+        .ok_or("Invalid plot size")?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(PNG_SCALE as f32, PNG_SCALE as f32),
+        &mut pixmap.as_mut(),
+    );
+    Ok(pixmap.encode_png()?)
+}
+
+impl BarChart {
+    /// Bars of the counts (or bases with scaled) of the bins, labelled with the start of the bin
+    pub fn from_histogram(
+        name: &'static str,
+        title: &str,
+        xlab: &str,
+        ylab: &str,
+        histogram: &metrics::Histogram,
+        scaled: bool,
+    ) -> Self {
+        let bars = histogram
+            .bins
+            .iter()
+            .map(|bin| {
+                let label = match bin.end {
+                    Some(_) => bin.start.to_string(),
+                    None => format!("{}+", bin.start),
+                };
+                let value = if scaled {
+                    bin.bases as f64
+                } else {
+                    bin.count as f64
+                };
+                (label, value)
+            })
+            .collect();
+        BarChart {
+            name,
+            title: title.to_string(),
+            xlab: xlab.to_string(),
+            ylab: ylab.to_string(),
+            bars,
+            log_scale: false,
+        }
+    }
+
+    /// The normalized number of reads per chromosome, relative to the median as in the text output
+    pub fn karyotype(karyotype: &[metrics::ChromosomeData]) -> Self {
+        // a contig of length zero has a NaN or infinite normalized count
+        let mut counts = karyotype
+            .iter()
+            .map(|c| c.normalized_count)
+            .filter(|count| count.is_finite())
+            .collect::<Vec<_>>();
+        counts.sort_by(f32::total_cmp);
+        let median = counts.get(counts.len() / 2).copied().unwrap_or(0.0);
+        let mut karyotype = karyotype.iter().collect::<Vec<_>>();
+        karyotype.sort_by_cached_key(|c| contig_sort_key(&c.chromosome));
+        BarChart {
+            name: "karyotype",
+            title: "Normalized read count per chromosome".to_string(),
+            xlab: "Chromosome".to_string(),
+            ylab: "Normalized read count".to_string(),
+            bars: karyotype
+                .iter()
+                .map(|c| {
+                    let value = if median > 0.0 && c.normalized_count.is_finite() {
+                        (c.normalized_count / median) as f64
+                    } else {
+                        0.0
+                    };
+                    (c.chromosome.clone(), value)
+                })
+                .collect(),
+            log_scale: false,
+        }
+    }
+
+    pub fn to_svg(&self) -> String {
+        let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+        let plot_height = HEIGHT - MARGIN_TOP - MARGIN_BOTTOM;
+        let bottom = MARGIN_TOP + plot_height;
+        let axis = if self.log_scale {
+            Axis::log(self.bars.iter().map(|(_, v)| *v))
+        } else {
+            Axis::linear(self.bars.iter().map(|(_, v)| *v).fold(0.0, f64::max))
+        };
+        let y = |value: f64| bottom - axis.fraction(value) * plot_height;
+        let bar_width = plot_width / self.bars.len().max(1) as f64;
+        let label_step = self.bars.len().div_ceil(MAX_X_LABELS).max(1);
+
+        let mut svg = String::new();
+        // writing to a String can't fail
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{HEIGHT}" viewBox="0 0 {WIDTH} {HEIGHT}" font-family="DejaVu Sans, Arial, Helvetica, sans-serif" font-size="11">"#
+        );
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{WIDTH}" height="{HEIGHT}" fill="white"/>"#
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="24" text-anchor="middle" font-size="15">{}</text>"#,
+            WIDTH / 2.0,
+            escape(&self.title)
+        );
+        for tick in &axis.ticks {
+            let tick_y = y(*tick);
+            let _ = writeln!(
+                svg,
+                r##"<line x1="{MARGIN_LEFT}" x2="{}" y1="{tick_y:.1}" y2="{tick_y:.1}" stroke="#dddddd"/><text x="{}" y="{:.1}" text-anchor="end">{}</text>"##,
+                WIDTH - MARGIN_RIGHT,
+                MARGIN_LEFT - 6.0,
+                tick_y + 4.0,
+                format_number(*tick),
+            );
+        }
+        for (index, (label, value)) in self.bars.iter().enumerate() {
+            let x = MARGIN_LEFT + index as f64 * bar_width;
+            // the smallest value on a log scale is at the bottom of the axis, but should still be visible
+            let top = if *value > 0.0 {
+                y(*value).min(bottom - 1.0)
+            } else {
+                bottom
+            };
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{x:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="{BAR_COLOR}"><title>{}: {}</title></rect>"#,
+                top,
+                (bar_width * 0.9).max(0.5),
+                bottom - top,
+                escape(label),
+                format_number(*value),
+            );
+            if index % label_step == 0 {
+                let center = x + bar_width / 2.0;
+                let _ = writeln!(
+                    svg,
+                    r#"<text x="{center:.1}" y="{:.1}" text-anchor="end" transform="rotate(-45 {center:.1} {:.1})">{}</text>"#,
+                    bottom + 14.0,
+                    bottom + 14.0,
+                    escape(label)
+                );
+            }
+        }
+        let _ = writeln!(
+            svg,
+            r#"<line x1="{MARGIN_LEFT}" x2="{MARGIN_LEFT}" y1="{MARGIN_TOP}" y2="{bottom}" stroke="black"/><line x1="{MARGIN_LEFT}" x2="{}" y1="{bottom}" y2="{bottom}" stroke="black"/>"#,
+            WIDTH - MARGIN_RIGHT
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle" font-size="13">{}</text>"#,
+            MARGIN_LEFT + plot_width / 2.0,
+            HEIGHT - 8.0,
+            escape(&self.xlab)
+        );
+        let _ = writeln!(
+            svg,
+            r#"<text x="16" y="{0}" text-anchor="middle" font-size="13" transform="rotate(-90 16 {0})">{1}</text>"#,
+            MARGIN_TOP + plot_height / 2.0,
+            escape(&if self.log_scale {
+                format!("{} (log scale)", self.ylab)
+            } else {
+                self.ylab.clone()
+            })
+        );
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// The y-axis, with ticks at round numbers
+struct Axis {
+    ticks: Vec<f64>,
+    log_scale: bool,
+}
+
+impl Axis {
+    fn linear(max_value: f64) -> Self {
+        Axis {
+            ticks: axis_ticks(max_value),
+            log_scale: false,
+        }
+    }
+
+    /// Ticks at the powers of 10 around the positive values
+    fn log(values: impl Iterator<Item = f64>) -> Self {
+        let (min, max) = values
+            .filter(|value| *value > 0.0)
+            .fold((f64::INFINITY, 0.0f64), |(min, max), value| {
+                (min.min(value), max.max(value))
+            });
+        if max == 0.0 {
+            return Axis::linear(0.0);
+        }
+        let low = min.log10().floor() as i32;
+        let high = (max.log10().ceil() as i32).max(low + 1);
+        Axis {
+            ticks: (low..=high).map(|exponent| 10f64.powi(exponent)).collect(),
+            log_scale: true,
+        }
+    }
+
+    /// Position of a value as a fraction of the height of the axis
+    fn fraction(&self, value: f64) -> f64 {
+        let (first, last) = (self.ticks[0], self.ticks[self.ticks.len() - 1]);
+        if !self.log_scale {
+            value / last
+        } else if value <= 0.0 {
+            0.0
+        } else {
+            ((value.log10() - first.log10()) / (last.log10() - first.log10())).max(0.0)
+        }
+    }
+}
+
+/// Ticks at round numbers (1, 2 or 5 times a power of 10) from 0 to at least the maximum
+fn axis_ticks(max_value: f64) -> Vec<f64> {
+    if max_value <= 0.0 {
+        return vec![0.0, 1.0];
+    }
+    let raw_step = max_value / 5.0;
+    let magnitude = 10f64.powf(raw_step.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|factor| factor * magnitude)
+        .find(|step| *step >= raw_step)
+        .unwrap_or(10.0 * magnitude);
+    let num_ticks = (max_value / step).ceil() as usize;
+    (0..=num_ticks).map(|index| index as f64 * step).collect()
+}
+
+/// Formats numbers compactly, e.g. 25000 as 25k
+fn format_number(value: f64) -> String {
+    let (value, suffix) = match value.abs() {
+        v if v >= 1e9 => (value / 1e9, "G"),
+        v if v >= 1e6 => (value / 1e6, "M"),
+        v if v >= 1e4 => (value / 1e3, "k"),
+        _ => (value, ""),
+    };
+    let formatted = format!("{value:.2}");
+    let formatted = formatted.trim_end_matches('0').trim_end_matches('.');
+    format!("{formatted}{suffix}")
+}
+
+/// Sorts chromosomes as chr1, chr2, ..., chr10, chrX rather than alphabetically
+fn contig_sort_key(contig: &str) -> (u32, String) {
+    let name = contig.strip_prefix("chr").unwrap_or(contig);
+    (name.parse().unwrap_or(u32::MAX), name.to_string())
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_axis_ticks() {
+        assert_eq!(axis_ticks(0.0), vec![0.0, 1.0]);
+        assert_eq!(axis_ticks(9.0), vec![0.0, 2.0, 4.0, 6.0, 8.0, 10.0]);
+        assert_eq!(axis_ticks(1300.0), vec![0.0, 500.0, 1000.0, 1500.0]);
+        assert_eq!(format_number(25_000.0), "25k");
+        assert_eq!(format_number(1_500_000.0), "1.5M");
+        assert_eq!(format_number(0.5), "0.5");
+        assert_eq!(format_number(120.0), "120");
+    }
+
+    #[test]
+    fn test_log_axis() {
+        let axis = Axis::log([0.0, 11.0, 2500.0].into_iter());
+        assert_eq!(axis.ticks, vec![10.0, 100.0, 1000.0, 10000.0]);
+        assert_eq!(axis.fraction(0.0), 0.0);
+        assert_eq!(axis.fraction(100.0), 1.0 / 3.0);
+        assert_eq!(axis.fraction(10000.0), 1.0);
+        // without positive values the axis is linear
+        assert!(!Axis::log([0.0].into_iter()).log_scale);
+    }
+
+    #[test]
+    fn test_write_plots() {
+        let histogram = metrics::Histogram {
+            step: 2000,
+            max_value: 4000,
+            bins: vec![metrics::HistogramBin {
+                start: 0,
+                end: Some(2000),
+                count: 3,
+                bases: 3000,
+            }],
+        };
+        let chart = BarChart::from_histogram("read_length", "Lengths", "x", "y", &histogram, false);
+        let dir = std::env::temp_dir().join("cramino-plots-test");
+        let dir = dir.to_str().unwrap();
+        write_plots(dir, &[chart], &[PlotFormat::Svg, PlotFormat::Png]).unwrap();
+        let png = std::fs::read(Path::new(dir).join("read_length.png")).unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+        assert!(Path::new(dir).join("read_length.svg").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_karyotype_chart() {
+        let chromosome = |name: &str, normalized_count| metrics::ChromosomeData {
+            chromosome: name.to_string(),
+            count: 10,
+            normalized_count,
+        };
+        let chart = BarChart::karyotype(&[
+            chromosome("chrX", 0.5),
+            chromosome("chr10", 1.0),
+            chromosome("chr2", 1.0),
+        ]);
+        let labels = chart
+            .bars
+            .iter()
+            .map(|(l, _)| l.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, ["chr2", "chr10", "chrX"]);
+        assert_eq!(chart.bars[2].1, 0.5);
+        let svg = chart.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(svg.matches("<rect").count(), 4);
+        assert!(svg.contains(">chr10</text>"));
+
+        // a contig of length zero doesn't change the median, and is plotted as zero
+        let chart = BarChart::karyotype(&[
+            chromosome("chr1", 1.0),
+            chromosome("chrEmpty", f32::NAN),
+            chromosome("chr2", 0.5),
+            chromosome("chr3", f32::INFINITY),
+        ]);
+        let values = chart.bars.iter().map(|(_, v)| *v).collect::<Vec<_>>();
+        assert_eq!(values, [1.0, 0.5, 0.0, 0.0]);
+        assert!(
+            BarChart::karyotype(&[chromosome("chrEmpty", f32::NAN)])
+                .to_svg()
+                .starts_with("<svg")
+        );
+    }
+}
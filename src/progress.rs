@@ -0,0 +1,147 @@
+use rust_htslib::bam;
+use std::time::{Duration, Instant};
+
+/// Time between the progress lines of --progress
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// Prints the records and bases processed and the throughput to stderr, with the percentage
+/// and ETA from the file offset of a local bam file, or else from the position of the last
+/// record in a coordinate-sorted file
+pub struct Progress {
+    start: Instant,
+    last_report: Instant,
+    records: u64,
+    bases: u64,
+    /// size of the local input file
+    file_size: Option<u64>,
+    /// cumulative start of each contig and the sum of the contig lengths, for sorted input
+    contig_starts: Option<(Vec<u64>, u64)>,
+    fraction: Option<f64>,
+}
+
+impl Progress {
+    pub fn new(input: &str, header: &bam::HeaderView) -> Self {
+        let file_size = std::fs::metadata(input)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len());
+        let sorted = crate::utils::is_coordinate_sorted(&bam::Header::from_template(header));
+        let contig_starts = (sorted && header.target_count() > 0).then(|| {
+            let mut total = 0;
+            let starts = (0..header.target_count())
+                .map(|tid| {
+                    let start = total;
+                    total += header.target_len(tid).unwrap_or(0);
+                    start
+                })
+                .collect::<Vec<u64>>();
+            (starts, total)
+        });
+        let now = Instant::now();
+        Progress {
+            start: now,
+            last_report: now,
+            records: 0,
+            bases: 0,
+            file_size,
+            contig_starts,
+            fraction: None,
+        }
+    }
+
+    /// Counts a record, with the byte offset of its block in a bam file if it is known
+    pub fn update(&mut self, record: &bam::Record, offset: Option<u64>) {
+        self.records += 1;
+        self.bases += record.seq_len() as u64;
+        // the clock is only checked every 1024 records
+        if !self.records.is_multiple_of(1024) || self.last_report.elapsed() < INTERVAL {
+            return;
+        }
+        if let Some(fraction) = self.fraction_done(record, offset) {
+            self.fraction = Some(fraction);
+        }
+        self.last_report = Instant::now();
+        eprintln!("{}", self.line());
+    }
+
+    /// Prints the totals when all records are processed
+    pub fn finish(&self) {
+        eprintln!(
+            "Processed {} records, {:.2} Gb in {}",
+            self.records,
+            self.bases as f64 / 1e9,
+            format_duration(self.start.elapsed())
+        );
+    }
+
+    fn fraction_done(&self, record: &bam::Record, offset: Option<u64>) -> Option<f64> {
+        if let (Some(offset), Some(size)) = (offset, self.file_size) {
+            return Some(offset as f64 / size as f64);
+        }
+        // unmapped reads at the end of a sorted file have no position, the last one is kept
+        let (starts, total) = self.contig_starts.as_ref()?;
+        let start = starts.get(usize::try_from(record.tid()).ok()?)?;
+        Some((start + record.pos().max(0) as u64) as f64 / *total as f64)
+    }
+
+    fn line(&self) -> String {
+        let elapsed = self.start.elapsed();
+        let mut line = format!(
+            "Processed {} records, {:.2} Gb, {:.0} records/s",
+            self.records,
+            self.bases as f64 / 1e9,
+            self.records as f64 / elapsed.as_secs_f64()
+        );
+        if let Some(fraction) = self.fraction.filter(|fraction| *fraction > 0.0) {
+            let remaining = elapsed.mul_f64((1.0 - fraction).max(0.0) / fraction);
+            line.push_str(&format!(
+                ", {:.1}%, ETA {}",
+                100.0 * fraction.min(1.0),
+                format_duration(remaining)
+            ));
+        }
+        line
+    }
+}
+
+/// Formats a duration as hours, minutes and seconds, e.g. 1:02:03
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(59)), "0:00:59");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1:02:03");
+    }
+
+    #[test]
+    fn test_fraction_done() {
+        let mut header = bam::Header::new();
+        let mut hd = bam::header::HeaderRecord::new(b"HD");
+        hd.push_tag(b"VN", "1.6").push_tag(b"SO", "coordinate");
+        header.push_record(&hd);
+        for (name, length) in [("chr1", 300), ("chr2", 100)] {
+            let mut contig = bam::header::HeaderRecord::new(b"SQ");
+            contig.push_tag(b"SN", name).push_tag(b"LN", length);
+            header.push_record(&contig);
+        }
+        let progress = Progress::new("NA", &bam::HeaderView::from_header(&header));
+        let mut record = bam::Record::new();
+        record.set_tid(1);
+        record.set_pos(50);
+        assert_eq!(progress.fraction_done(&record, None), Some(350.0 / 400.0));
+        record.set_tid(-1);
+        assert_eq!(progress.fraction_done(&record, None), None);
+    }
+}
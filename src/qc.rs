@@ -0,0 +1,271 @@
+use crate::metrics::{self, QcStatus};
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::LazyLock;
+
+/// Exit code when a rule at the fail level is not met
+pub const EXIT_FAILURE: i32 = 3;
+
+static RULE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^\s*([A-Za-z0-9_.\-]+)\s*(>=|<=|==|!=|>|<)\s*(\S+)\s*$").unwrap()
+});
+
+/// Rules read from a TOML file with --qc-thresholds, e.g.
+///
+/// fail = ["read_stats.n50 >= 15000", "mean_coverage >= 28"]
+/// warn = ["identity_stats.median_identity >= 97"]
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct QcThresholds {
+    #[serde(default, deserialize_with = "deserialize_rules")]
+    pub fail: Vec<Rule>,
+    #[serde(default, deserialize_with = "deserialize_rules")]
+    pub warn: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/// A threshold on a metric, named as in the json output (e.g. read_stats.n50)
+/// or by its last part if that is unique (e.g. n50)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub metric: String,
+    pub comparison: Comparison,
+    pub threshold: f64,
+}
+
+impl Comparison {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        }
+    }
+
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Less => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let captures = RULE.captures(s).ok_or_else(|| {
+            format!("Invalid QC rule, expected e.g. read_stats.n50 >= 15000: {s}")
+        })?;
+        let comparison = match &captures[2] {
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterOrEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessOrEqual,
+            "==" => Comparison::Equal,
+            _ => Comparison::NotEqual,
+        };
+        let threshold = captures[3]
+            .parse::<f64>()
+            .map_err(|_| format!("Invalid threshold in QC rule: {s}"))?;
+        Ok(Rule {
+            metric: captures[1].to_string(),
+            comparison,
+            threshold,
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.metric,
+            self.comparison.symbol(),
+            self.threshold
+        )
+    }
+}
+
+fn deserialize_rules<'de, D>(deserializer: D) -> Result<Vec<Rule>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|rule| rule.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Reads the rules of --qc-thresholds, used as value parser to fail before reading the input
+pub fn read_thresholds(path: &str) -> Result<QcThresholds, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let thresholds: QcThresholds = toml::from_str(&content).map_err(|e| format!("{path}: {e}"))?;
+    if thresholds.fail.is_empty() && thresholds.warn.is_empty() {
+        return Err(format!("{path}: no QC rules in fail or warn"));
+    }
+    Ok(thresholds)
+}
+
+/// Evaluates the rules on the metrics, a metric that is not in the report (e.g. identity_stats
+/// with --ubam) does not meet its rule
+pub fn evaluate(
+    thresholds: &QcThresholds,
+    metrics: &metrics::Metrics,
+) -> Result<metrics::QcReport, String> {
+    let fields = metrics.flatten();
+    let levels = thresholds
+        .fail
+        .iter()
+        .map(|rule| (rule, QcStatus::Fail))
+        .chain(thresholds.warn.iter().map(|rule| (rule, QcStatus::Warn)));
+    let mut rules = Vec::new();
+    for (rule, level) in levels {
+        let (metric, value) = match resolve(&fields, &rule.metric)? {
+            Some((metric, value)) => (metric.to_string(), Some(value)),
+            None => (rule.metric.clone(), None),
+        };
+        let status = match value {
+            Some(value) if rule.comparison.holds(value, rule.threshold) => QcStatus::Pass,
+            _ => level,
+        };
+        rules.push(metrics::QcRuleResult {
+            rule: rule.to_string(),
+            metric,
+            value,
+            status,
+        });
+    }
+    Ok(metrics::QcReport {
+        status: rules
+            .iter()
+            .map(|rule| rule.status)
+            .max()
+            .unwrap_or(QcStatus::Pass),
+        rules,
+    })
+}
+
+/// The value of a metric in the QC section of the text output
+pub fn format_value(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.fract() == 0.0 => format!("{value:.0}"),
+        Some(value) => format!("{value:.2}"),
+        None => "NA".to_string(),
+    }
+}
+
+/// The metric with the exact name, or else the only one ending in .name
+fn resolve<'a>(fields: &'a [(String, f64)], name: &str) -> Result<Option<(&'a str, f64)>, String> {
+    if let Some((metric, value)) = fields.iter().find(|(metric, _)| metric == name) {
+        return Ok(Some((metric, *value)));
+    }
+    let suffix = format!(".{name}");
+    let matches = fields
+        .iter()
+        .filter(|(metric, _)| metric.ends_with(&suffix))
+        .collect::<Vec<_>>();
+    match matches.as_slice() {
+        [] => Ok(None),
+        [(metric, value)] => Ok(Some((metric, *value))),
+        _ => Err(format!(
+            "QC rule metric {name} is ambiguous, use one of {}",
+            matches
+                .iter()
+                .map(|(metric, _)| metric.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> metrics::Metrics {
+        let mut report = metrics::Metrics::new(metrics::FileInfo {
+            name: "a.bam".to_string(),
+            path: "a.bam".to_string(),
+            creation_time: "NA".to_string(),
+        });
+        report.read_stats.n50 = 16_000;
+        report.read_stats.mean_coverage = 25.0;
+        report
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let thresholds: QcThresholds = toml::from_str(
+            r#"
+            fail = ["read_stats.n50 >= 15000", "mean_coverage>=28"]
+            warn = ["identity_stats.median_identity >= 97"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            thresholds.fail[1],
+            Rule {
+                metric: "mean_coverage".to_string(),
+                comparison: Comparison::GreaterOrEqual,
+                threshold: 28.0,
+            }
+        );
+        assert_eq!(thresholds.fail[1].to_string(), "mean_coverage >= 28");
+        assert!("n50 => 15000".parse::<Rule>().is_err());
+        assert!("n50 >= 15kb".parse::<Rule>().is_err());
+        assert!(toml::from_str::<QcThresholds>("error = [\"n50 > 1\"]").is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let thresholds: QcThresholds = toml::from_str(
+            r#"
+            fail = ["read_stats.n50 >= 15000"]
+            warn = ["mean_coverage >= 28", "identity_stats.median_identity >= 97"]
+            "#,
+        )
+        .unwrap();
+        let qc = evaluate(&thresholds, &report()).unwrap();
+        let statuses = qc.rules.iter().map(|rule| rule.status).collect::<Vec<_>>();
+        assert_eq!(statuses, [QcStatus::Pass, QcStatus::Warn, QcStatus::Warn]);
+        assert_eq!(qc.rules[1].metric, "read_stats.mean_coverage");
+        // without identity stats the rule is not met
+        assert_eq!(qc.rules[2].value, None);
+        assert_eq!(qc.status, QcStatus::Warn);
+
+        let thresholds: QcThresholds = toml::from_str("fail = [\"n50 > 20000\"]").unwrap();
+        assert_eq!(
+            evaluate(&thresholds, &report()).unwrap().status,
+            QcStatus::Fail
+        );
+        // read_stats.yield_gb and read_stats.long_read_yield.25000.yield_gb
+        let mut report = report();
+        report.read_stats.long_read_yield = vec![metrics::LongReadYield {
+            threshold: 25_000,
+            yield_gb: 1.0,
+            num_reads: 10,
+            fraction_of_yield: 0.1,
+        }];
+        let thresholds: QcThresholds = toml::from_str("fail = [\"yield_gb > 1\"]").unwrap();
+        assert!(evaluate(&thresholds, &report).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use arrow::array::{
+    ArrayRef, Float64Array, Int64Array, StringArray, UInt8Array, UInt16Array, UInt32Array,
+    UInt64Array,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+/// Number of rows per record batch for the Arrow and Parquet output
+const BATCH_SIZE: usize = 65_536;
+
+/// One row of the per-read QC table
+/// Optional values are missing for unmapped reads, or if the tag is absent
+pub struct ReadRow {
+    pub name: String,
+    pub flag: u16,
+    pub contig: Option<String>,
+    /// 1-based leftmost position of the alignment
+    pub position: Option<i64>,
+    pub strand: Option<char>,
+    pub mapq: u8,
+    /// length of the read, including clipped bases
+    pub query_length: u64,
+    pub aligned_length: u64,
+    pub identity: Option<f64>,
+    /// phred-scaled mean error probability of the base qualities
+    pub mean_base_q: Option<f64>,
+    pub read_group: Option<String>,
+    pub phaseset: Option<u32>,
+    pub haplotype: Option<u8>,
+    pub exons: Option<u32>,
+    /// values of the aux tags requested with --read-table-tags, in that order
+    pub tags: Vec<Option<String>>,
+}
+
+enum TableFormat {
+    Arrow,
+    Parquet,
+    Tsv,
+}
+
+impl TableFormat {
+    /// The format is inferred from the file extension, defaulting to Arrow IPC (feather)
+    fn from_path(path: &str) -> Self {
+        let path = path.to_lowercase();
+        if path.ends_with(".parquet") || path.ends_with(".pq") {
+            TableFormat::Parquet
+        } else if path.ends_with(".tsv") || path.ends_with(".txt") {
+            TableFormat::Tsv
+        } else {
+            TableFormat::Arrow
+        }
+    }
+}
+
+enum Sink {
+    Arrow(FileWriter<File>),
+    Parquet(ArrowWriter<File>),
+    Tsv(BufWriter<File>),
+}
+
+/// Writes the per-read table, in batches of rows to keep the memory bounded
+pub struct ReadTableWriter {
+    sink: Sink,
+    schema: SchemaRef,
+    rows: Vec<ReadRow>,
+}
+
+impl ReadTableWriter {
+    pub fn new(
+        path: &str,
+        tags: &[String],
+        metadata: HashMap<String, String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let schema = Arc::new(schema(tags).with_metadata(metadata));
+        let file = File::create(path)?;
+        let sink = match TableFormat::from_path(path) {
+            TableFormat::Arrow => Sink::Arrow(FileWriter::try_new(file, &schema)?),
+            TableFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Sink::Parquet(ArrowWriter::try_new(file, schema.clone(), Some(props))?)
+            }
+            TableFormat::Tsv => {
+                let mut writer = BufWriter::new(file);
+                let header = schema
+                    .fields()
+                    .iter()
+                    .map(|field| field.name().as_str())
+                    .collect::<Vec<_>>();
+                writeln!(writer, "{}", header.join("\t"))?;
+                Sink::Tsv(writer)
+            }
+        };
+        Ok(ReadTableWriter {
+            sink,
+            schema,
+            rows: Vec::with_capacity(BATCH_SIZE),
+        })
+    }
+
+    pub fn push(&mut self, row: ReadRow) -> Result<(), Box<dyn std::error::Error>> {
+        if let Sink::Tsv(writer) = &mut self.sink {
+            return write_tsv_row(writer, &row);
+        }
+        self.rows.push(row);
+        if self.rows.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let batch = to_record_batch(&self.schema, &self.rows)?;
+        self.rows.clear();
+        match &mut self.sink {
+            Sink::Arrow(writer) => writer.write(&batch)?,
+            Sink::Parquet(writer) => writer.write(&batch)?,
+            Sink::Tsv(_) => unreachable!("rows are written directly to the tsv file"),
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush()?;
+        match self.sink {
+            Sink::Arrow(mut writer) => writer.finish()?,
+            Sink::Parquet(writer) => {
+                writer.close()?;
+            }
+            Sink::Tsv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+fn schema(tags: &[String]) -> Schema {
+    let mut fields = vec![
+        Field::new("name", DataType::Utf8, false),
+        Field::new("flag", DataType::UInt16, false),
+        Field::new("contig", DataType::Utf8, true),
+        Field::new("position", DataType::Int64, true),
+        Field::new("strand", DataType::Utf8, true),
+        Field::new("mapq", DataType::UInt8, false),
+        Field::new("query_length", DataType::UInt64, false),
+        Field::new("aligned_length", DataType::UInt64, false),
+        Field::new("identity", DataType::Float64, true),
+        Field::new("mean_base_q", DataType::Float64, true),
+        Field::new("read_group", DataType::Utf8, true),
+        Field::new("phaseset", DataType::UInt32, true),
+        Field::new("haplotype", DataType::UInt8, true),
+        Field::new("exons", DataType::UInt32, true),
+    ];
+    fields.extend(
+        tags.iter()
+            .map(|tag| Field::new(format!("tag_{tag}"), DataType::Utf8, true)),
+    );
+    Schema::new(fields)
+}
+
+fn to_record_batch(
+    schema: &SchemaRef,
+    rows: &[ReadRow],
+) -> Result<RecordBatch, arrow::error::ArrowError> {
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| r.name.as_str()),
+        )),
+        Arc::new(UInt16Array::from_iter_values(rows.iter().map(|r| r.flag))),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.contig.as_deref()),
+        )),
+        Arc::new(Int64Array::from_iter(rows.iter().map(|r| r.position))),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.strand.map(String::from)),
+        )),
+        Arc::new(UInt8Array::from_iter_values(rows.iter().map(|r| r.mapq))),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|r| r.query_length),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|r| r.aligned_length),
+        )),
+        Arc::new(Float64Array::from_iter(rows.iter().map(|r| r.identity))),
+        Arc::new(Float64Array::from_iter(rows.iter().map(|r| r.mean_base_q))),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.read_group.as_deref()),
+        )),
+        Arc::new(UInt32Array::from_iter(rows.iter().map(|r| r.phaseset))),
+        Arc::new(UInt8Array::from_iter(rows.iter().map(|r| r.haplotype))),
+        Arc::new(UInt32Array::from_iter(rows.iter().map(|r| r.exons))),
+    ];
+    let num_tags = rows.first().map_or(0, |r| r.tags.len());
+    for index in 0..num_tags {
+        columns.push(Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.tags[index].as_deref()),
+        )));
+    }
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+/// Writes a row to the tsv file, with missing values as empty fields
+fn write_tsv_row(
+    writer: &mut BufWriter<File>,
+    row: &ReadRow,
+) -> Result<(), Box<dyn std::error::Error>> {
+    fn opt<T: ToString>(value: Option<T>) -> String {
+        value.map(|v| v.to_string()).unwrap_or_default()
+    }
+    let mut fields = vec![
+        row.name.clone(),
+        row.flag.to_string(),
+        opt(row.contig.as_ref()),
+        opt(row.position),
+        opt(row.strand),
+        row.mapq.to_string(),
+        row.query_length.to_string(),
+        row.aligned_length.to_string(),
+        opt(row.identity.map(|v| format!("{:.3}", v))),
+        opt(row.mean_base_q.map(|v| format!("{:.2}", v))),
+        opt(row.read_group.as_ref()),
+        opt(row.phaseset),
+        opt(row.haplotype),
+        opt(row.exons),
+    ];
+    fields.extend(row.tags.iter().map(|tag| opt(tag.as_ref())));
+    writeln!(writer, "{}", fields.join("\t"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::ipc::reader::FileReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn row(index: usize) -> ReadRow {
+        ReadRow {
+            name: format!("read{index}"),
+            flag: 16,
+            contig: Some("chr1".to_string()),
+            position: Some(index as i64 + 1),
+            strand: Some('-'),
+            mapq: 60,
+            query_length: 1000,
+            aligned_length: 950,
+            identity: Some(98.5),
+            mean_base_q: None,
+            read_group: None,
+            phaseset: Some(100),
+            haplotype: Some(1),
+            exons: Some(1),
+            tags: vec![Some("12".to_string()), None],
+        }
+    }
+
+    fn write_table(path: &std::path::Path, num_rows: usize) {
+        let tags = vec!["NM".to_string(), "XX".to_string()];
+        let mut writer =
+            ReadTableWriter::new(path.to_str().unwrap(), &tags, HashMap::new()).unwrap();
+        for index in 0..num_rows {
+            writer.push(row(index)).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_read_table_formats() {
+        let dir = std::env::temp_dir();
+        let num_rows = BATCH_SIZE + 10;
+
+        let arrow_path = dir.join("cramino-read-table-test.arrow");
+        write_table(&arrow_path, num_rows);
+        let reader = FileReader::try_new(File::open(&arrow_path).unwrap(), None).unwrap();
+        assert_eq!(reader.schema().fields().len(), 16);
+        let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            batches.iter().map(|b| b.num_rows()).sum::<usize>(),
+            num_rows
+        );
+
+        let parquet_path = dir.join("cramino-read-table-test.parquet");
+        write_table(&parquet_path, num_rows);
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let rows = reader.map(|b| b.unwrap().num_rows()).sum::<usize>();
+        assert_eq!(rows, num_rows);
+
+        let tsv_path = dir.join("cramino-read-table-test.tsv");
+        write_table(&tsv_path, 2);
+        let content = std::fs::read_to_string(&tsv_path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("exons\ttag_NM\ttag_XX"));
+        assert_eq!(
+            lines[1],
+            "read0\t16\tchr1\t1\t-\t60\t1000\t950\t98.500\t\t\t100\t1\t1\t12\t"
+        );
+
+        for path in [arrow_path, parquet_path, tsv_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,662 @@
+use crate::histograms::{self, LengthHistogramData};
+use crate::metrics;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of sub-buckets per power of two is 2^SUB_BUCKET_BITS
+/// lengths below 2^SUB_BUCKET_BITS (1024) are counted exactly, larger lengths in buckets
+/// with a width of at most 1/1024 (0.1%) of the lengths in that bucket
+const SUB_BUCKET_BITS: u32 = 10;
+
+/// Identities are counted in bins of 0.01%
+const IDENTITY_BINS_PER_PERCENT: f64 = 100.0;
+const IDENTITY_BINS: usize = 100 * IDENTITY_BINS_PER_PERCENT as usize + 1;
+/// The sum of identities is kept as an integer in units of 1e-9%,
+/// so that merged sketches give the same mean as a single sketch of all reads
+const IDENTITY_SUM_SCALE: f64 = 1e9;
+
+/// Bounded-memory summary of read lengths, used with --sketch instead of keeping all lengths
+///
+/// Lengths are counted in log-linear buckets (like an HDR histogram), which keep the number of reads
+/// and the sum of their lengths. The memory use depends on the number of distinct buckets,
+/// which is a few thousand for long reads, regardless of the number of reads.
+/// The lengths are also counted in the fixed bins of their histogram.
+///
+/// Error bounds:
+/// - number of reads, yield, mean length, auN, maximum length, the yield above --long-thresholds
+///   and the histogram are exact
+/// - median, percentiles and Nx are the mean length of the bucket holding that read, which has a relative error below 0.1%,
+///   and are exact for lengths below 1024
+/// - Lx assumes that all reads in a bucket have the same length, so it can differ from the exact value
+///   by the number of reads in the bucket that holds the Nx read
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LengthSketch {
+    /// bucket index -> (number of reads, sum of their lengths)
+    buckets: BTreeMap<u32, (u64, u128)>,
+    count: u64,
+    total: u128,
+    sum_squares: u128,
+    /// length thresholds of the long read yield, and the yield and number of reads above them
+    thresholds: Vec<u64>,
+    long: Vec<(u128, usize)>,
+    max: u128,
+    histogram: LengthHistogramData,
+}
+
+impl LengthSketch {
+    /// Sketch of read lengths, with the bins of the read length histogram
+    pub fn new(thresholds: &[u64]) -> Self {
+        Self::with_histogram(thresholds, histograms::LENGTH_STEP, histograms::LENGTH_MAX)
+    }
+
+    /// Sketch of lengths with histogram bins of the given step up to max_value
+    pub fn with_histogram(thresholds: &[u64], step: u64, max_value: u64) -> Self {
+        LengthSketch {
+            thresholds: thresholds.to_vec(),
+            long: vec![(0, 0); thresholds.len()],
+            histogram: LengthHistogramData::new(step, max_value),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, length: u128) {
+        let bucket = self
+            .buckets
+            .entry(bucket_index(length as u64))
+            .or_insert((0, 0));
+        bucket.0 += 1;
+        bucket.1 += length;
+        self.count += 1;
+        self.total += length;
+        self.sum_squares += length * length;
+        self.max = self.max.max(length);
+        self.histogram.add(length);
+        for (threshold, (long_yield, long_count)) in
+            self.thresholds.iter().zip(self.long.iter_mut())
+        {
+            if length > *threshold as u128 {
+                *long_yield += length;
+                *long_count += 1;
+            }
+        }
+    }
+
+    /// Adds the reads of another sketch, which has to use the same thresholds
+    pub fn merge(&mut self, other: &LengthSketch) -> Result<(), String> {
+        if self.thresholds != other.thresholds {
+            return Err(format!(
+                "Can't merge sketches with different long read thresholds ({:?} and {:?})",
+                self.thresholds, other.thresholds
+            ));
+        }
+        self.histogram.merge(&other.histogram)?;
+        for (index, (count, bases)) in &other.buckets {
+            let bucket = self.buckets.entry(*index).or_insert((0, 0));
+            bucket.0 += count;
+            bucket.1 += bases;
+        }
+        self.count += other.count;
+        self.total += other.total;
+        self.sum_squares += other.sum_squares;
+        self.max = self.max.max(other.max);
+        for (long, other_long) in self.long.iter_mut().zip(&other.long) {
+            long.0 += other_long.0;
+            long.1 += other_long.1;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn max(&self) -> u128 {
+        self.max
+    }
+
+    /// Number of lengths and their sum in the bins of the histogram
+    pub fn histogram(&self) -> &LengthHistogramData {
+        &self.histogram
+    }
+
+    /// Same as utils::calculate_data_yield, for the thresholds the sketch was created with
+    pub fn data_yield(&self) -> (u128, Vec<(u128, usize)>) {
+        (self.total, self.long.clone())
+    }
+
+    /// Mean length of the reads in each bucket, in ascending order, with the number of reads
+    fn values(&self) -> impl DoubleEndedIterator<Item = (f64, u64)> + '_ {
+        self.buckets
+            .values()
+            .map(|(count, bases)| (*bases as f64 / *count as f64, *count))
+    }
+
+    /// Length of the read with the given 1-based rank, in ascending order
+    fn value_at_rank(&self, rank: u64) -> f64 {
+        let mut acc = 0;
+        for (value, count) in self.values() {
+            acc += count;
+            if acc >= rank {
+                return value;
+            }
+        }
+        0.0
+    }
+
+    /// Same as calculations::length_percentile
+    pub fn percentile(&self, fraction: f64) -> u128 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((fraction * self.count as f64).ceil() as u64).clamp(1, self.count);
+        self.value_at_rank(rank).round() as u128
+    }
+
+    /// Same as calculations::median_length
+    pub fn median(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else if self.count.is_multiple_of(2) {
+            (self.value_at_rank(self.count / 2) + self.value_at_rank(self.count / 2 + 1)) / 2.0
+        } else {
+            self.value_at_rank(self.count / 2 + 1)
+        }
+    }
+
+    /// Same as calculations::get_nl, the Nx length and Lx count
+    pub fn get_nl(&self, fraction: f64) -> (u128, usize) {
+        let target = self.total as f64 * fraction;
+        let mut acc_bases = 0.0;
+        let mut acc_count = 0;
+        for (value, count) in self.values().rev() {
+            let bases = value * count as f64;
+            if acc_bases + bases > target {
+                // the number of reads of this bucket that is needed to exceed the target
+                let needed = (((target - acc_bases) / value).floor() as u64 + 1).clamp(1, count);
+                return (value.round() as u128, (acc_count + needed) as usize);
+            }
+            acc_bases += bases;
+            acc_count += count;
+        }
+        self.values()
+            .next()
+            .map_or((0, 0), |(value, _)| (value.round() as u128, self.len()))
+    }
+
+    pub fn get_n(&self, fraction: f64) -> u128 {
+        self.get_nl(fraction).0
+    }
+
+    /// Same as calculations::get_aun
+    pub fn aun(&self) -> f64 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.sum_squares as f64 / self.total as f64
+    }
+
+    /// Same as calculations::read_length_stats
+    pub fn read_length_stats(
+        &self,
+        nx: &[u8],
+    ) -> (
+        Vec<metrics::NxStat>,
+        Vec<metrics::LxStat>,
+        f64,
+        metrics::LengthPercentiles,
+    ) {
+        let (nx_stats, lx_stats) = nx
+            .iter()
+            .map(|x| {
+                let (length, num_reads) = self.get_nl(*x as f64 / 100.0);
+                (
+                    metrics::NxStat { x: *x, length },
+                    metrics::LxStat { x: *x, num_reads },
+                )
+            })
+            .unzip();
+        let percentiles = metrics::LengthPercentiles {
+            p5: self.percentile(0.05),
+            p10: self.percentile(0.10),
+            p25: self.percentile(0.25),
+            p50: self.percentile(0.50),
+            p75: self.percentile(0.75),
+            p90: self.percentile(0.90),
+            p95: self.percentile(0.95),
+        };
+        (nx_stats, lx_stats, self.aun(), percentiles)
+    }
+
+    /// Same as calculations::length_distribution
+    pub fn length_distribution(&self) -> Option<metrics::LengthDistribution> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(metrics::LengthDistribution {
+            count: self.len(),
+            median: self.median(),
+            mean: self.total as f64 / self.count as f64,
+            p5: self.percentile(0.05) as f64,
+            p25: self.percentile(0.25) as f64,
+            p75: self.percentile(0.75) as f64,
+            p95: self.percentile(0.95) as f64,
+            max: self.max as u64,
+        })
+    }
+}
+
+/// Index of the log-linear bucket of a length
+/// lengths below 2^SUB_BUCKET_BITS have their own bucket, above that
+/// every power of two is split in 2^SUB_BUCKET_BITS buckets of equal width
+fn bucket_index(length: u64) -> u32 {
+    let sub_buckets = 1u64 << SUB_BUCKET_BITS;
+    if length < sub_buckets {
+        return length as u32;
+    }
+    let exponent = 63 - length.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let octave = shift + 1;
+    ((octave as u64) << SUB_BUCKET_BITS) as u32 + ((length >> shift) - sub_buckets) as u32
+}
+
+/// Bounded-memory summary of identities, used with --sketch instead of keeping all identities
+///
+/// Identities are counted in bins of 0.01%, so the memory use is constant (about 80kb).
+///
+/// Error bounds:
+/// - the mean identity is exact, up to 1e-9%
+/// - the median identity is the center of its bin, which is at most 0.005% from the exact value
+/// - the modal identity is exact, as it is calculated at a resolution of 0.1%
+/// - identities below 0 (which is possible for very bad alignments) are counted as 0
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "SparseIdentitySketch", from = "SparseIdentitySketch")]
+pub struct IdentitySketch {
+    counts: Vec<u64>,
+    count: u64,
+    sum: i128,
+}
+
+/// Serialized form of the IdentitySketch, with only the bins that are not empty
+#[derive(Serialize, Deserialize)]
+struct SparseIdentitySketch {
+    /// (bin, number of reads)
+    counts: Vec<(usize, u64)>,
+    count: u64,
+    sum: i128,
+}
+
+impl From<IdentitySketch> for SparseIdentitySketch {
+    fn from(sketch: IdentitySketch) -> Self {
+        SparseIdentitySketch {
+            counts: sketch
+                .counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(bin, count)| (bin, *count))
+                .collect(),
+            count: sketch.count,
+            sum: sketch.sum,
+        }
+    }
+}
+
+impl From<SparseIdentitySketch> for IdentitySketch {
+    fn from(sparse: SparseIdentitySketch) -> Self {
+        let mut sketch = IdentitySketch {
+            count: sparse.count,
+            sum: sparse.sum,
+            ..Default::default()
+        };
+        for (bin, count) in sparse.counts {
+            sketch.counts[bin.min(IDENTITY_BINS - 1)] += count;
+        }
+        sketch
+    }
+}
+
+impl Default for IdentitySketch {
+    fn default() -> Self {
+        IdentitySketch {
+            counts: vec![0; IDENTITY_BINS],
+            count: 0,
+            sum: 0,
+        }
+    }
+}
+
+impl IdentitySketch {
+    pub fn add(&mut self, identity: f64) {
+        let bin = (identity.clamp(0.0, 100.0) * IDENTITY_BINS_PER_PERCENT) as usize;
+        self.counts[bin.min(IDENTITY_BINS - 1)] += 1;
+        self.count += 1;
+        self.sum += (identity * IDENTITY_SUM_SCALE).round() as i128;
+    }
+
+    /// Adds the reads of another sketch
+    pub fn merge(&mut self, other: &IdentitySketch) {
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / IDENTITY_SUM_SCALE / self.count as f64
+    }
+
+    /// Center of the bin of the identity with the given 1-based rank, in descending order
+    fn value_at_rank(&self, rank: u64) -> f64 {
+        let mut acc = 0;
+        for (bin, count) in self.counts.iter().enumerate().rev() {
+            acc += count;
+            if acc >= rank {
+                return ((bin as f64 + 0.5) / IDENTITY_BINS_PER_PERCENT).min(100.0);
+            }
+        }
+        0.0
+    }
+
+    /// Same as calculations::median, on identities sorted in descending order
+    pub fn median(&self) -> f64 {
+        if self.count.is_multiple_of(2) {
+            (self.value_at_rank(self.count / 2) + self.value_at_rank(self.count / 2 + 1)) / 2.0
+        } else {
+            self.value_at_rank(self.count / 2 + 1)
+        }
+    }
+
+    /// Same as calculations::modal_accuracy, the most frequent identity at a resolution of 0.1%
+    pub fn modal(&self) -> f64 {
+        let bins_per_mode = (IDENTITY_BINS_PER_PERCENT / 10.0) as usize;
+        let modes = self
+            .counts
+            .chunks(bins_per_mode)
+            .map(|bins| bins.iter().sum::<u64>())
+            .collect::<Vec<u64>>();
+        let mode = modes
+            .iter()
+            .enumerate()
+            .max_by_key(|&(_, count)| count)
+            .map(|(mode, _)| mode)
+            .unwrap_or(0);
+        mode as f64 / 10.0
+    }
+}
+
+/// Bounded-memory summary of spliced reads, used with --sketch instead of keeping the number
+/// of exons of every read and all intron and exon block lengths
+///
+/// Error bounds:
+/// - the number of exons per read is counted exactly, and so are the median and mean number of exons
+///   and the fraction of unspliced reads
+/// - the intron and exon block lengths have the error bounds of a LengthSketch
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpliceSketch {
+    /// number of exons -> number of reads
+    exons: BTreeMap<usize, u64>,
+    intron_lengths: LengthSketch,
+    exon_lengths: LengthSketch,
+}
+
+impl Default for SpliceSketch {
+    fn default() -> Self {
+        SpliceSketch {
+            exons: BTreeMap::new(),
+            intron_lengths: LengthSketch::with_histogram(
+                &[],
+                histograms::INTRON_STEP,
+                histograms::INTRON_MAX,
+            ),
+            exon_lengths: LengthSketch::with_histogram(
+                &[],
+                histograms::EXON_BLOCK_STEP,
+                histograms::EXON_BLOCK_MAX,
+            ),
+        }
+    }
+}
+
+impl SpliceSketch {
+    /// Adds a read with its number of exons and the lengths of its introns and exon blocks
+    pub fn add(&mut self, exons: usize, intron_lengths: &[u32], exon_lengths: &[u32]) {
+        *self.exons.entry(exons).or_insert(0) += 1;
+        for length in intron_lengths {
+            self.intron_lengths.add(*length as u128);
+        }
+        for length in exon_lengths {
+            self.exon_lengths.add(*length as u128);
+        }
+    }
+
+    /// Adds the reads of another sketch
+    pub fn merge(&mut self, other: &SpliceSketch) -> Result<(), String> {
+        self.intron_lengths.merge(&other.intron_lengths)?;
+        self.exon_lengths.merge(&other.exon_lengths)?;
+        for (exons, count) in &other.exons {
+            *self.exons.entry(*exons).or_insert(0) += count;
+        }
+        Ok(())
+    }
+
+    /// Number of exons and the number of reads with that many exons, in ascending order
+    pub fn exon_counts(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.exons.iter().map(|(exons, count)| (*exons, *count))
+    }
+
+    pub fn intron_lengths(&self) -> &LengthSketch {
+        &self.intron_lengths
+    }
+
+    pub fn exon_lengths(&self) -> &LengthSketch {
+        &self.exon_lengths
+    }
+
+    /// Number of exons of the read with the given 0-based rank, in ascending order
+    fn exons_at_rank(&self, rank: u64) -> usize {
+        let mut acc = 0;
+        for (exons, count) in self.exon_counts() {
+            acc += count;
+            if acc > rank {
+                return exons;
+            }
+        }
+        0
+    }
+
+    /// Same as the splice stats of all reads, see metrics_processor::calculate_metrics
+    pub fn splice_stats(&self) -> metrics::SpliceStats {
+        let num_reads = self.exons.values().sum::<u64>();
+        let total_exons = self
+            .exon_counts()
+            .map(|(exons, count)| exons as u64 * count)
+            .sum::<u64>();
+        // same as calculations::median_splice
+        let median_exons = if num_reads == 0 {
+            0
+        } else if num_reads.is_multiple_of(2) {
+            (self.exons_at_rank(num_reads / 2 - 1) + self.exons_at_rank(num_reads / 2)) / 2
+        } else {
+            self.exons_at_rank(num_reads / 2)
+        };
+        metrics::SpliceStats {
+            median_exons,
+            mean_exons: total_exons as f32 / num_reads as f32,
+            fraction_unspliced: self.exons.get(&1).copied().unwrap_or(0) as f32 / num_reads as f32,
+            intron_lengths: self.intron_lengths.length_distribution(),
+            exon_lengths: self.exon_lengths.length_distribution(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculations;
+
+    /// Pseudo-random read lengths with a long tail, from a linear congruential generator
+    fn random_lengths(n: usize) -> Vec<u128> {
+        let mut state = 42u64;
+        (0..n)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let uniform = (state >> 11) as f64 / (1u64 << 53) as f64;
+                (100.0 + 20_000.0 * -(1.0 - uniform).ln()) as u128
+            })
+            .collect()
+    }
+
+    fn relative_error(sketch: u128, exact: u128) -> f64 {
+        (sketch as f64 - exact as f64).abs() / exact as f64
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(1023), 1023);
+        assert_eq!(bucket_index(1024), 1024);
+        assert_eq!(bucket_index(2047), 2047);
+        // from 2048 on, buckets have a width of 2
+        assert_eq!(bucket_index(2048), 2048);
+        assert_eq!(bucket_index(2049), 2048);
+        assert_eq!(bucket_index(2050), 2049);
+        // bucket indices are increasing with the length
+        let mut previous = 0;
+        for length in (0..10_000_000).step_by(997) {
+            let index = bucket_index(length);
+            assert!(index >= previous);
+            previous = index;
+        }
+    }
+
+    #[test]
+    fn test_length_sketch_against_exact() {
+        let mut lengths = random_lengths(100_001);
+        let thresholds = [25_000, 50_000];
+        let mut sketch = LengthSketch::new(&thresholds);
+        for length in &lengths {
+            sketch.add(*length);
+        }
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+
+        let (total, long) = crate::utils::calculate_data_yield(&lengths, &thresholds);
+        assert_eq!(sketch.data_yield(), (total, long));
+        assert_eq!(sketch.len(), lengths.len());
+        assert_eq!(sketch.max(), lengths[0]);
+        assert!((sketch.aun() - calculations::get_aun(&lengths, total)).abs() < 1e-6);
+
+        let max_error = 1.0 / (1 << SUB_BUCKET_BITS) as f64;
+        let median = calculations::median_length(&lengths);
+        assert!((sketch.median() - median).abs() / median < max_error);
+        for fraction in [0.05, 0.25, 0.5, 0.75, 0.95] {
+            let exact = calculations::length_percentile(&lengths, fraction);
+            assert!(relative_error(sketch.percentile(fraction), exact) < max_error);
+        }
+        for fraction in [0.1, 0.5, 0.9] {
+            let (exact_n, exact_l) = calculations::get_nl(&lengths, total, fraction);
+            let (sketch_n, sketch_l) = sketch.get_nl(fraction);
+            assert!(relative_error(sketch_n, exact_n) < max_error);
+            assert!((sketch_l as f64 - exact_l as f64).abs() / (exact_l as f64) < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_length_sketch_is_exact_for_short_lengths() {
+        let mut lengths = vec![5u128, 800, 120, 120, 1000, 33, 999];
+        let mut sketch = LengthSketch::new(&[100]);
+        for length in &lengths {
+            sketch.add(*length);
+        }
+        lengths.sort_unstable_by(|a, b| b.cmp(a));
+        let total = lengths.iter().sum::<u128>();
+        assert_eq!(sketch.median(), calculations::median_length(&lengths));
+        for fraction in [0.1, 0.5, 0.9] {
+            assert_eq!(
+                sketch.get_nl(fraction),
+                calculations::get_nl(&lengths, total, fraction)
+            );
+            assert_eq!(
+                sketch.percentile(fraction),
+                calculations::length_percentile(&lengths, fraction)
+            );
+        }
+    }
+
+    #[test]
+    fn test_identity_sketch_against_exact() {
+        let mut identities = random_lengths(10_000)
+            .iter()
+            .map(|x| 100.0 - (*x % 1500) as f64 / 100.0)
+            .collect::<Vec<f64>>();
+        let mut sketch = IdentitySketch::default();
+        for identity in &identities {
+            sketch.add(*identity);
+        }
+        identities.sort_unstable_by(|a, b| b.partial_cmp(a).unwrap());
+        let mean = identities.iter().sum::<f64>() / identities.len() as f64;
+        assert!((sketch.mean() - mean).abs() < 1e-9);
+        assert!((sketch.median() - calculations::median(&identities)).abs() <= 0.005 + 1e-9);
+        let mut counts = std::collections::HashMap::new();
+        for identity in &identities {
+            *counts.entry((identity * 10.0) as i32).or_insert(0) += 1;
+        }
+        let max_count = *counts.values().max().unwrap();
+        // ties can be resolved differently, so check that the mode has the maximal count
+        assert_eq!(counts[&((sketch.modal() * 10.0).round() as i32)], max_count);
+    }
+
+    #[test]
+    fn test_splice_sketch_against_exact() {
+        let reads = [
+            (1, vec![], vec![900]),
+            (3, vec![80, 1500], vec![120, 60, 2500]),
+            (2, vec![250_000], vec![40, 300]),
+            (1, vec![], vec![1200]),
+        ];
+        let mut sketch = SpliceSketch::default();
+        let mut merged = SpliceSketch::default();
+        let mut other = SpliceSketch::default();
+        for (index, (exons, introns, exon_blocks)) in reads.iter().enumerate() {
+            sketch.add(*exons, introns, exon_blocks);
+            // the same reads split over two sketches, e.g. of two shards
+            let shard = if index % 2 == 0 {
+                &mut merged
+            } else {
+                &mut other
+            };
+            shard.add(*exons, introns, exon_blocks);
+        }
+        merged.merge(&other).unwrap();
+        assert_eq!(merged, sketch);
+
+        let stats = sketch.splice_stats();
+        let mut exons = reads.iter().map(|read| read.0).collect::<Vec<usize>>();
+        exons.sort_unstable();
+        assert_eq!(stats.median_exons, calculations::median_splice(&exons));
+        assert_eq!(stats.mean_exons, 7.0 / 4.0);
+        assert_eq!(stats.fraction_unspliced, 0.5);
+        let mut introns = reads
+            .iter()
+            .flat_map(|read| read.1.clone())
+            .collect::<Vec<u32>>();
+        introns.sort_unstable();
+        let exact = calculations::length_distribution(&introns).unwrap();
+        let introns = stats.intron_lengths.unwrap();
+        // lengths below 1024 are exact, the others within 0.1%
+        assert_eq!((introns.count, introns.max), (exact.count, exact.max));
+        assert_eq!((introns.p5, introns.mean), (exact.p5, exact.mean));
+        assert!((introns.p95 - exact.p95).abs() / exact.p95 < 0.001);
+        assert_eq!(stats.exon_lengths.unwrap().count, 7);
+    }
+}
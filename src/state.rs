@@ -0,0 +1,578 @@
+use crate::extract_from_bam::{Data, MAX_SKIPPED_ERRORS, QScoreHistogramData};
+use crate::metrics_processor::{self, OutputFormat};
+use crate::phased::{self, ChromosomePhaseCounts, PhaseBlock};
+use crate::sketch::{IdentitySketch, LengthSketch, SpliceSketch};
+use crate::{QcOptions, metrics};
+use clap::Args;
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
+use rust_htslib::bam::{Header, header::HeaderRecord};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+/// Intermediate state of a run with --sketch, written with --state as gzip-compressed json
+/// State files of e.g. shards of a sample can be combined with `cramino merge`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct State {
+    pub cramino_version: String,
+    /// file names of the inputs, more than one after merging
+    pub inputs: Vec<String>,
+    pub options: StateOptions,
+    /// names and lengths of the contigs in the header
+    pub contigs: Vec<(String, u64)>,
+    pub num_reads: usize,
+    pub all_counts: usize,
+    pub coverage_yield: Option<u128>,
+    pub length_sketch: LengthSketch,
+    pub identity_sketch: IdentitySketch,
+    /// number of reads per contig, with --karyotype
+    pub contig_counts: Option<HashMap<String, usize>>,
+    /// histogram of the Phred scores of the identities
+    pub q_score_hist: Option<QScoreHistogramData>,
+    /// alignment counts per contig (by index in the contigs) and phase blocks, with --phased
+    pub phase_counts: Option<HashMap<i32, ChromosomePhaseCounts>>,
+    pub phaseblocks: Option<Vec<PhaseBlock>>,
+    /// the first and last (tid, start) of the phased alignments, the phase blocks of states
+    /// can only be merged if these ranges don't overlap
+    pub phased_range: Option<((i32, i64), (i32, i64))>,
+    /// with --spliced
+    pub splice_sketch: Option<SpliceSketch>,
+    /// number of invalid records skipped with --lenient, and the errors of the first skipped records
+    pub skipped_records: usize,
+    pub skipped_errors: Vec<String>,
+    /// partial states of the collectors registered with QcOptions::collector, for every
+    /// collector those of all inputs, which are merged when the metrics are calculated
+    pub collectors: Vec<Vec<serde_json::Value>>,
+}
+
+/// Options that change the state, which have to be the same for state files to be merged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateOptions {
+    pub min_read_len: usize,
+    pub ubam: bool,
+    pub long_thresholds: Vec<u64>,
+    pub genome_size: Option<u64>,
+    pub contigs: Option<String>,
+    pub karyotype: bool,
+    pub phased: bool,
+    pub spliced: bool,
+}
+
+impl From<&QcOptions> for StateOptions {
+    fn from(options: &QcOptions) -> Self {
+        StateOptions {
+            min_read_len: options.min_read_len,
+            ubam: options.ubam,
+            long_thresholds: options.long_thresholds.clone(),
+            genome_size: options.genome_size,
+            contigs: options.contigs.as_ref().map(|contigs| contigs.to_string()),
+            karyotype: options.karyotype,
+            phased: options.phased,
+            spliced: options.spliced,
+        }
+    }
+}
+
+impl StateOptions {
+    /// The options to calculate the metrics of a state with
+    pub(crate) fn qc_options(&self) -> Result<QcOptions, Box<dyn std::error::Error>> {
+        Ok(QcOptions {
+            min_read_len: self.min_read_len,
+            ubam: self.ubam,
+            long_thresholds: self.long_thresholds.clone(),
+            genome_size: self.genome_size,
+            contigs: self.contigs.as_deref().map(str::parse).transpose()?,
+            sketch: true,
+            karyotype: self.karyotype,
+            phased: self.phased,
+            spliced: self.spliced,
+            histograms: true,
+            ..QcOptions::default()
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct MergeArgs {
+    /// State files to combine, written with --state
+    #[clap(value_parser, required = true)]
+    inputs: Vec<String>,
+
+    /// Nx statistics to report, as comma-separated percentages of the yield (e.g. 10,50,90)
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=100), value_delimiter = ',', default_values_t = vec![10, 50, 90])]
+    nx: Vec<u8>,
+
+    /// Write the merged state to a file, to combine it with other state files later
+    #[clap(long, value_parser, value_name = "FILE")]
+    state: Option<String>,
+
+    /// Output format (text, json, or tsv)
+    #[clap(long, value_parser, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+impl State {
+    /// The state of the data of an input, with its name in the inputs
+    pub fn from_data(
+        data: &Data,
+        input: String,
+        options: &QcOptions,
+        header: &Header,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if options.read_level || options.length_stats {
+            return Err(
+                "--read-level and --length-stats keep values per read, which can't be written to a state"
+                    .into(),
+            );
+        }
+        if options.collectors.len() != data.collectors.len() {
+            return Err(
+                "The data was extracted with other collectors than those of the options".into(),
+            );
+        }
+        let collectors = options
+            .collectors
+            .iter()
+            .zip(&data.collectors)
+            .map(|(factory, collector)| factory.save(collector.as_ref()).map(|state| vec![state]))
+            .collect::<Result<Vec<_>, _>>()?;
+        let contigs = header
+            .to_hashmap()
+            .get("SQ")
+            .map(|records| {
+                records
+                    .iter()
+                    .map(|record| (record["SN"].clone(), record["LN"].parse().unwrap_or(0)))
+                    .collect::<Vec<(String, u64)>>()
+            })
+            .unwrap_or_default();
+        // unmapped reads (tid -1) are not counted in the karyotype
+        let contig_counts = data.tid_counts.as_ref().map(|tid_counts| {
+            tid_counts
+                .iter()
+                .filter(|(tid, _)| **tid >= 0)
+                .map(|(tid, count)| (contigs[*tid as usize].0.clone(), *count))
+                .collect()
+        });
+        Ok(State {
+            cramino_version: env!("CARGO_PKG_VERSION").to_string(),
+            inputs: vec![input],
+            options: StateOptions::from(options),
+            contigs,
+            num_reads: data.num_reads,
+            all_counts: data.all_counts,
+            coverage_yield: data.coverage_yield,
+            length_sketch: data
+                .length_sketch
+                .clone()
+                .ok_or("Writing a state file requires --sketch")?,
+            identity_sketch: data
+                .identity_sketch
+                .clone()
+                .ok_or("Writing a state file requires --sketch")?,
+            contig_counts,
+            q_score_hist: data.q_score_hist.clone(),
+            phase_counts: data.phase_counts.clone(),
+            phaseblocks: data.phaseblocks.clone(),
+            phased_range: data.phased_range,
+            splice_sketch: data.splice_sketch.clone(),
+            skipped_records: data.skipped_records,
+            skipped_errors: data.skipped_errors.clone(),
+            collectors,
+        })
+    }
+
+    pub fn read(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let reader = MultiGzDecoder::new(BufReader::new(File::open(path)?));
+        serde_json::from_reader(reader)
+            .map_err(|err| format!("Error reading state file {path}: {err}").into())
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut writer =
+            GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+        serde_json::to_writer(&mut writer, self)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Adds the counts of another state, which has to be created with the same options and reference
+    pub fn merge(&mut self, other: State) -> Result<(), String> {
+        if self.options != other.options {
+            return Err(format!(
+                "State files were created with different options ({} and {})",
+                serde_json::to_string(&self.options).unwrap_or_default(),
+                serde_json::to_string(&other.options).unwrap_or_default()
+            ));
+        }
+        if self.contigs != other.contigs {
+            return Err("State files were created with a different reference".to_string());
+        }
+        if self.collectors.len() != other.collectors.len() {
+            return Err("State files were created with different collectors".to_string());
+        }
+        // the phase blocks are runs of sorted alignments, which can only be joined across shards
+        // if all phased alignments of one shard come before those of the other
+        if let (Some((first, last)), Some((other_first, other_last))) =
+            (self.phased_range, other.phased_range)
+            && first <= other_last
+            && other_first <= last
+        {
+            return Err(
+                "The phased alignments of the state files overlap, their phase blocks can only be merged for shards of consecutive regions of a coordinate-sorted input"
+                    .to_string(),
+            );
+        }
+        self.inputs.extend(other.inputs);
+        self.num_reads += other.num_reads;
+        self.all_counts += other.all_counts;
+        self.coverage_yield = match (self.coverage_yield, other.coverage_yield) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
+        self.length_sketch.merge(&other.length_sketch)?;
+        self.identity_sketch.merge(&other.identity_sketch);
+        if let (Some(counts), Some(other_counts)) = (&mut self.contig_counts, other.contig_counts) {
+            for (contig, count) in other_counts {
+                *counts.entry(contig).or_default() += count;
+            }
+        }
+        self.q_score_hist = match (self.q_score_hist.take(), other.q_score_hist) {
+            (Some(mut hist), Some(other_hist)) => {
+                hist.merge(&other_hist);
+                Some(hist)
+            }
+            _ => None,
+        };
+        if let (Some(counts), Some(other_counts)) = (&mut self.phase_counts, other.phase_counts) {
+            for (tid, other_counts) in other_counts {
+                counts.entry(tid).or_default().merge(&other_counts);
+            }
+        }
+        if let (Some(blocks), Some(other_blocks)) = (&mut self.phaseblocks, other.phaseblocks) {
+            blocks.extend(other_blocks);
+            *blocks = phased::merge_phaseblocks(std::mem::take(blocks));
+        }
+        self.phased_range = match (self.phased_range, other.phased_range) {
+            (Some((first, last)), Some((other_first, other_last))) => {
+                Some((first.min(other_first), last.max(other_last)))
+            }
+            (range, other_range) => range.or(other_range),
+        };
+        if let (Some(sketch), Some(other_sketch)) = (&mut self.splice_sketch, &other.splice_sketch)
+        {
+            sketch.merge(other_sketch)?;
+        }
+        self.skipped_records += other.skipped_records;
+        self.skipped_errors.extend(other.skipped_errors);
+        self.skipped_errors.truncate(MAX_SKIPPED_ERRORS);
+        for (states, other_states) in self.collectors.iter_mut().zip(other.collectors) {
+            states.extend(other_states);
+        }
+        Ok(())
+    }
+
+    /// The data and header to calculate the metrics from, with the options the metrics are
+    /// calculated with, which have to register the collectors of the state
+    pub fn into_data(self, options: &QcOptions) -> Result<(Data, Header), String> {
+        if options.collectors.len() != self.collectors.len() {
+            return Err(format!(
+                "The state has {} collectors registered with QcOptions::collector, and the options {}",
+                self.collectors.len(),
+                options.collectors.len()
+            ));
+        }
+        let collectors = options
+            .collectors
+            .iter()
+            .zip(self.collectors)
+            .map(|(factory, states)| {
+                let mut collectors = states.into_iter().map(|state| factory.load(state));
+                let mut collector = collectors
+                    .next()
+                    .ok_or("The state has no partial state of a collector")??;
+                for other in collectors {
+                    collector.merge(other?)?;
+                }
+                Ok(collector)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let mut header = Header::new();
+        for (name, length) in &self.contigs {
+            let mut record = HeaderRecord::new(b"SQ");
+            record.push_tag(b"SN", name).push_tag(b"LN", length);
+            header.push_record(&record);
+        }
+        let tid_counts = self.contig_counts.map(|counts| {
+            self.contigs
+                .iter()
+                .enumerate()
+                .filter_map(|(tid, (name, _))| counts.get(name).map(|count| (tid as i32, *count)))
+                .collect()
+        });
+        let data = Data {
+            num_reads: self.num_reads,
+            all_counts: self.all_counts,
+            coverage_yield: self.coverage_yield,
+            length_sketch: Some(self.length_sketch),
+            identity_sketch: Some(self.identity_sketch),
+            tid_counts,
+            q_score_hist: self.q_score_hist,
+            phase_counts: self.phase_counts,
+            phaseblocks: self.phaseblocks,
+            phased_range: self.phased_range,
+            splice_sketch: self.splice_sketch,
+            is_ubam: self.options.ubam,
+            skipped_records: self.skipped_records,
+            skipped_errors: self.skipped_errors,
+            collectors,
+            ..Default::default()
+        };
+        Ok((data, header))
+    }
+}
+
+/// Combines state files into a report, as if cramino was run on all inputs at once
+pub fn merge(merge_args: &MergeArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut states = merge_args.inputs.iter().map(|path| State::read(path));
+    let mut state = states.next().expect("No state files to merge")?;
+    for other in states {
+        state.merge(other?)?;
+    }
+    if let Some(path) = &merge_args.state {
+        state.write(path)?;
+    }
+
+    // the report is made with the options the state files were created with
+    let options = state.options.qc_options()?.nx(merge_args.nx.clone());
+
+    let file_info = metrics::FileInfo {
+        name: state.inputs.join(","),
+        path: merge_args.inputs.join(","),
+        creation_time: "NA".to_string(),
+    };
+    let (data, header) = state.into_data(&options)?;
+    let (metrics, _) = metrics_processor::calculate_metrics(&data, &options, &header, file_info)?;
+    metrics_processor::print_metrics(&metrics, merge_args.format);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(lengths: &[u128], contig_counts: &[(&str, usize)]) -> State {
+        let mut length_sketch = LengthSketch::new(&[25_000]);
+        let mut identity_sketch = IdentitySketch::default();
+        for length in lengths {
+            length_sketch.add(*length);
+            identity_sketch.add(95.0);
+        }
+        State {
+            cramino_version: env!("CARGO_PKG_VERSION").to_string(),
+            inputs: vec!["shard.bam".to_string()],
+            options: StateOptions {
+                min_read_len: 0,
+                ubam: false,
+                long_thresholds: vec![],
+                genome_size: None,
+                contigs: None,
+                karyotype: true,
+                phased: false,
+                spliced: false,
+            },
+            contigs: vec![("chr1".to_string(), 1000), ("chr2".to_string(), 500)],
+            num_reads: lengths.len(),
+            all_counts: lengths.len() + 1,
+            coverage_yield: None,
+            length_sketch,
+            identity_sketch,
+            contig_counts: Some(
+                contig_counts
+                    .iter()
+                    .map(|(contig, count)| (contig.to_string(), *count))
+                    .collect(),
+            ),
+            q_score_hist: None,
+            phase_counts: None,
+            phaseblocks: None,
+            phased_range: None,
+            splice_sketch: None,
+            skipped_records: 0,
+            skipped_errors: Vec::new(),
+            collectors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge_equals_concatenated_input() {
+        let mut merged = state(&[100, 30_000, 5000], &[("chr1", 3)]);
+        merged.skipped_records = 12;
+        merged.skipped_errors = vec!["negative NM tag".to_string(); 10];
+        let mut other = state(&[2000, 40_000], &[("chr1", 1), ("chr2", 1)]);
+        other.skipped_records = 1;
+        other.skipped_errors = vec!["negative de tag".to_string()];
+        merged.merge(other).unwrap();
+        let all = state(&[100, 30_000, 5000, 2000, 40_000], &[]);
+        assert_eq!(merged.num_reads, 5);
+        assert_eq!(merged.all_counts, 7);
+        assert_eq!(merged.length_sketch, all.length_sketch);
+        assert_eq!(merged.identity_sketch, all.identity_sketch);
+        assert_eq!(merged.contig_counts.as_ref().unwrap()["chr1"], 4);
+        assert_eq!(merged.inputs.len(), 2);
+        // the errors of the first skipped records are kept
+        assert_eq!(merged.skipped_records, 13);
+        assert_eq!(merged.skipped_errors.len(), MAX_SKIPPED_ERRORS);
+
+        let (data, header) = merged.into_data(&QcOptions::new()).unwrap();
+        assert_eq!(data.tid_counts.unwrap()[&1], 1);
+        assert_eq!(header.to_hashmap()["SQ"].len(), 2);
+    }
+
+    #[test]
+    fn test_merge_requires_same_options() {
+        let mut first = state(&[100], &[]);
+        let mut second = state(&[100], &[]);
+        second.options.min_read_len = 500;
+        assert!(first.merge(second).is_err());
+        let mut third = state(&[100], &[]);
+        third.contigs.pop();
+        assert!(first.merge(third).is_err());
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let state = state(&[100, 30_000, 5000], &[("chr1", 3)]);
+        let path = std::env::temp_dir().join("cramino-state-test.json");
+        state.write(path.to_str().unwrap()).unwrap();
+        let read = State::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(read.length_sketch, state.length_sketch);
+        assert_eq!(read.identity_sketch, state.identity_sketch);
+        assert_eq!(read.options, state.options);
+        // the state is compressed
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[..2], [0x1f, 0x8b]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_states_of_shards_match_whole_input() {
+        use rust_htslib::bam::{self, Read};
+        let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+        let header = reader.header().clone();
+        let records = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<bam::Record>>();
+        let options = QcOptions::new()
+            .sketch(true)
+            .karyotype(true)
+            .phased(true)
+            .spliced(true)
+            .histograms(true);
+        let state_of = |records: &[bam::Record]| {
+            let data =
+                crate::extract_from_bam::extract_records(records.iter(), &header, "NA", &options)
+                    .unwrap();
+            State::from_data(
+                &data,
+                "shard.bam".to_string(),
+                &options,
+                &Header::from_template(&header),
+            )
+            .unwrap()
+        };
+        // shards of consecutive regions, split between two positions
+        let split = (records.len() / 2..records.len())
+            .find(|&index| records[index].pos() != records[index - 1].pos())
+            .unwrap();
+        let mut merged = state_of(&records[..split]);
+        merged.merge(state_of(&records[split..])).unwrap();
+        let whole = state_of(&records);
+
+        let metrics = |state: State| {
+            let options = state.options.qc_options().unwrap();
+            let (data, header) = state.into_data(&options).unwrap();
+            let (metrics, _) =
+                metrics_processor::calculate_metrics(&data, &options, &header, file_info())
+                    .unwrap();
+            let mut metrics = serde_json::to_value(metrics).unwrap();
+            // the contigs of the karyotype are not in a fixed order
+            metrics["karyotype_stats"]
+                .as_array_mut()
+                .unwrap()
+                .sort_by_key(|contig| contig["chromosome"].to_string());
+            metrics["histograms"] =
+                serde_json::to_value(crate::histograms::build_histograms(&data)).unwrap();
+            metrics
+        };
+        let merged = metrics(merged);
+        assert!(merged["phase_stats"].is_object());
+        assert!(merged["splice_stats"].is_object());
+        assert_eq!(merged, metrics(whole));
+    }
+
+    #[test]
+    fn test_states_of_interleaved_shards() {
+        use rust_htslib::bam::{self, Read};
+        let mut reader = bam::Reader::from_path("test-data/small-test-phased.bam").unwrap();
+        let header = reader.header().clone();
+        let records = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<bam::Record>>();
+        let state_of = |records: &[&bam::Record], options: &QcOptions| {
+            let data = crate::extract_from_bam::extract_records(
+                records.iter().copied(),
+                &header,
+                "NA",
+                options,
+            )
+            .unwrap();
+            State::from_data(
+                &data,
+                "shard.bam".to_string(),
+                options,
+                &Header::from_template(&header),
+            )
+            .unwrap()
+        };
+        // every other read in a shard, so the shards cover the same regions
+        let (even, odd): (Vec<_>, Vec<_>) = records
+            .iter()
+            .enumerate()
+            .partition(|(index, _)| index % 2 == 0);
+        let even = even
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>();
+        let odd = odd
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>();
+
+        // the runs of phased alignments can't be joined across the shards
+        let options = QcOptions::new().sketch(true).phased(true);
+        let mut merged = state_of(&even, &options);
+        let err = merged.merge(state_of(&odd, &options)).unwrap_err();
+        assert!(err.contains("overlap"));
+
+        // the other metrics don't depend on the order of the reads
+        let options = QcOptions::new().sketch(true).karyotype(true);
+        let mut merged = state_of(&even, &options);
+        merged.merge(state_of(&odd, &options)).unwrap();
+        let whole = state_of(&records.iter().collect::<Vec<_>>(), &options);
+        assert_eq!(merged.length_sketch, whole.length_sketch);
+        assert_eq!(merged.identity_sketch, whole.identity_sketch);
+        assert_eq!(merged.contig_counts, whole.contig_counts);
+    }
+
+    fn file_info() -> metrics::FileInfo {
+        metrics::FileInfo {
+            name: "NA".to_string(),
+            path: "NA".to_string(),
+            creation_time: "NA".to_string(),
+        }
+    }
+}
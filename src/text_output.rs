@@ -1,8 +1,16 @@
-use crate::metrics;
+use crate::{metrics, qc, utils};
 
 pub fn print_text_output(metrics: &metrics::Metrics) {
     // Print file info
     println!("File name\t{}", metrics.file_info.name);
+    // Flag the partial report of a truncated or corrupt file
+    if let Some(incomplete) = &metrics.incomplete {
+        println!("INCOMPLETE\t{}", incomplete.reason);
+        println!("Records processed\t{}", incomplete.records_processed);
+        if let Some(position) = &incomplete.last_position {
+            println!("Last position\t{position}");
+        }
+    }
 
     // Print alignment stats
     println!(
@@ -18,16 +26,65 @@ pub fn print_text_output(metrics: &metrics::Metrics) {
     // Print read stats
     println!("Yield [Gb]\t{:.2}", metrics.read_stats.yield_gb);
     println!("Mean coverage\t{:.2}", metrics.read_stats.mean_coverage);
-    println!(
-        "Yield [Gb] (>25kb)\t{:.2}",
-        metrics.read_stats.yield_gb_long
-    );
+    for long in &metrics.read_stats.long_read_yield {
+        let threshold = utils::format_threshold(long.threshold);
+        println!("Yield [Gb] (>{})\t{:.2}", threshold, long.yield_gb);
+        println!("Number of reads (>{})\t{}", threshold, long.num_reads);
+        println!(
+            "Fraction of yield (>{})\t{:.2}",
+            threshold, long.fraction_of_yield
+        );
+    }
     println!("N50\t{}", metrics.read_stats.n50);
     println!("N75\t{}", metrics.read_stats.n75);
+    for nx in &metrics.read_stats.nx {
+        // N50 and N75 are always reported above
+        if nx.x != 50 && nx.x != 75 {
+            println!("N{}\t{}", nx.x, nx.length);
+        }
+    }
+    for lx in &metrics.read_stats.lx {
+        println!("L{}\t{}", lx.x, lx.num_reads);
+    }
+    println!("auN\t{:.2}", metrics.read_stats.aun);
     println!("Median length\t{:.2}", metrics.read_stats.median_length);
     println!("Mean length\t{:.2}", metrics.read_stats.mean_length);
+    let p = &metrics.read_stats.length_percentiles;
+    println!(
+        "Length percentiles (P5,P10,P25,P50,P75,P90,P95)\t{},{},{},{},{},{},{}",
+        p.p5, p.p10, p.p25, p.p50, p.p75, p.p90, p.p95
+    );
     println!();
 
+    // Print alignment lengths if read stats are per read
+    if let Some(read_level_stats) = &metrics.read_level_stats {
+        println!("Split reads\t{}", read_level_stats.num_split_reads);
+        let summary = &read_level_stats.alignment_length;
+        println!("N50 alignment length\t{}", summary.n50);
+        println!("Median alignment length\t{:.2}", summary.median);
+        println!("Mean alignment length\t{:.2}", summary.mean);
+        println!();
+    }
+
+    // Print query length, aligned length and reference span if available
+    if let Some(length_stats) = &metrics.length_stats {
+        let summaries = [
+            ("query length", &length_stats.query_length),
+            ("aligned length", &length_stats.aligned_length),
+            ("reference span", &length_stats.reference_span),
+        ];
+        for (name, summary) in summaries {
+            println!("N50 {}\t{}", name, summary.n50);
+            println!("Median {}\t{:.2}", name, summary.median);
+            println!("Mean {}\t{:.2}", name, summary.mean);
+        }
+        println!(
+            "Fraction of query bases aligned\t{:.3}",
+            length_stats.fraction_aligned
+        );
+        println!();
+    }
+
     // Print identity stats if available
     if let Some(identity_stats) = &metrics.identity_stats {
         if identity_stats.is_estimated {
@@ -61,7 +118,54 @@ pub fn print_text_output(metrics: &metrics::Metrics) {
             "N50 phaseblock length\t{}",
             phase_stats.n50_phaseblock_length
         );
+        println!(
+            "Fraction genome in phaseblocks\t{:.3}",
+            phase_stats.fraction_genome_phased
+        );
+        if let Some(region_stats) = &phase_stats.region_stats {
+            println!(
+                "Fraction region bases in phaseblocks\t{:.3}",
+                region_stats.fraction_bases_phased
+            );
+            println!(
+                "Regions fully phased\t{}/{} ({:.3})",
+                region_stats.num_fully_phased,
+                region_stats.num_regions,
+                region_stats.fraction_fully_phased
+            );
+        }
+        println!("HP1 reads\t{}", phase_stats.haplotypes.hp1_reads);
+        println!("HP2 reads\t{}", phase_stats.haplotypes.hp2_reads);
+        println!(
+            "Reads without haplotype\t{}",
+            phase_stats.haplotypes.unassigned_reads
+        );
+        println!(
+            "Fraction HP1 (of haplotagged bases)\t{:.3}",
+            phase_stats.haplotypes.hp1_fraction
+        );
         println!();
+
+        if !phase_stats.chromosomes.is_empty() {
+            println!("\n# Phasing per chromosome\n");
+            println!(
+                "chromosome\tfraction_phased\tphaseblocks\tN50\tHP1_reads\tHP2_reads\tunassigned_reads\tHP1_fraction"
+            );
+            for chrom in &phase_stats.chromosomes {
+                println!(
+                    "{}\t{:.2}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+                    chrom.chromosome,
+                    chrom.fraction_phased,
+                    chrom.num_phaseblocks,
+                    chrom.n50_phaseblock_length,
+                    chrom.haplotypes.hp1_reads,
+                    chrom.haplotypes.hp2_reads,
+                    chrom.haplotypes.unassigned_reads,
+                    chrom.haplotypes.hp1_fraction
+                );
+            }
+            println!();
+        }
     }
 
     // Print karyotype stats if available
@@ -102,6 +206,51 @@ pub fn print_text_output(metrics: &metrics::Metrics) {
             "Fraction unspliced reads\t{:.2}",
             splice_stats.fraction_unspliced
         );
+        if let Some(introns) = &splice_stats.intron_lengths {
+            println!("Number of introns\t{}", introns.count);
+            println!("Median intron length\t{:.2}", introns.median);
+            println!("Mean intron length\t{:.2}", introns.mean);
+            println!("P5 intron length\t{:.0}", introns.p5);
+            println!("P95 intron length\t{:.0}", introns.p95);
+            println!("Max intron length\t{}", introns.max);
+        }
+        if let Some(exons) = &splice_stats.exon_lengths {
+            println!("Median exon block length\t{:.2}", exons.median);
+            println!("Mean exon block length\t{:.2}", exons.mean);
+            println!("P5 exon block length\t{:.0}", exons.p5);
+            println!("P95 exon block length\t{:.0}", exons.p95);
+        }
+        println!();
+    }
+
+    // Print the invalid records that were skipped with --lenient
+    if let Some(warnings) = &metrics.warnings {
+        println!("# Warnings\n");
+        println!("Skipped records\t{}", warnings.skipped_records);
+        for error in &warnings.errors {
+            println!("{error}");
+        }
+        if warnings.skipped_records > warnings.errors.len() {
+            println!(
+                "... and {} more",
+                warnings.skipped_records - warnings.errors.len()
+            );
+        }
+        println!();
+    }
+
+    // Print the QC rules of --qc-thresholds
+    if let Some(qc) = &metrics.qc {
+        println!("# QC\n");
+        for rule in &qc.rules {
+            println!(
+                "{}\t{}\t{}",
+                rule.rule,
+                qc::format_value(rule.value),
+                rule.status
+            );
+        }
+        println!("QC status\t{}", qc.status);
         println!();
     }
     // Print file info
@@ -1,86 +1,242 @@
 use crate::metrics;
 
 pub fn print_tsv_output(metrics: &metrics::Metrics) {
+    print!("{}", tsv_output(metrics));
+}
+
+/// The header line and the line of values, e.g. for a file
+pub fn tsv_output(metrics: &metrics::Metrics) -> String {
     // Prepare headers and values separately
     let mut headers = Vec::new();
     let mut values = Vec::new();
 
     // File info
-    headers.push("file_name");
+    headers.push("file_name".to_string());
     values.push(metrics.file_info.name.clone());
-    headers.push("file_path");
+    headers.push("file_path".to_string());
     values.push(metrics.file_info.path.clone());
-    headers.push("creation_time");
+    headers.push("creation_time".to_string());
     values.push(metrics.file_info.creation_time.clone());
 
     // Alignment stats
-    headers.push("num_alignments");
+    headers.push("num_alignments".to_string());
     values.push(metrics.alignment_stats.num_alignments.to_string());
-    headers.push("percent_from_total");
+    headers.push("percent_from_total".to_string());
     values.push(format!("{:.2}", metrics.alignment_stats.percent_from_total));
-    headers.push("num_reads");
+    headers.push("num_reads".to_string());
     values.push(metrics.alignment_stats.num_reads.to_string());
 
     // Read stats
-    headers.push("yield_gb");
+    headers.push("yield_gb".to_string());
     values.push(format!("{:.2}", metrics.read_stats.yield_gb));
-    headers.push("mean_coverage");
+    headers.push("mean_coverage".to_string());
     values.push(format!("{:.2}", metrics.read_stats.mean_coverage));
-    headers.push("yield_gb_long");
+    headers.push("yield_gb_long".to_string());
     values.push(format!("{:.2}", metrics.read_stats.yield_gb_long));
-    headers.push("n50");
+    for long in &metrics.read_stats.long_read_yield {
+        headers.push(format!("yield_gb_gt{}", long.threshold));
+        values.push(format!("{:.2}", long.yield_gb));
+        headers.push(format!("num_reads_gt{}", long.threshold));
+        values.push(long.num_reads.to_string());
+        headers.push(format!("fraction_yield_gt{}", long.threshold));
+        values.push(format!("{:.2}", long.fraction_of_yield));
+    }
+    headers.push("n50".to_string());
     values.push(metrics.read_stats.n50.to_string());
-    headers.push("n75");
+    headers.push("n75".to_string());
     values.push(metrics.read_stats.n75.to_string());
-    headers.push("median_length");
+    headers.push("median_length".to_string());
     values.push(format!("{:.2}", metrics.read_stats.median_length));
-    headers.push("mean_length");
+    headers.push("mean_length".to_string());
     values.push(format!("{:.2}", metrics.read_stats.mean_length));
+    for nx in &metrics.read_stats.nx {
+        // n50 and n75 are always reported above
+        if nx.x != 50 && nx.x != 75 {
+            headers.push(format!("n{}", nx.x));
+            values.push(nx.length.to_string());
+        }
+    }
+    for lx in &metrics.read_stats.lx {
+        headers.push(format!("l{}", lx.x));
+        values.push(lx.num_reads.to_string());
+    }
+    headers.push("aun".to_string());
+    values.push(format!("{:.2}", metrics.read_stats.aun));
+    let p = &metrics.read_stats.length_percentiles;
+    for (name, value) in [
+        ("length_p5", p.p5),
+        ("length_p10", p.p10),
+        ("length_p25", p.p25),
+        ("length_p50", p.p50),
+        ("length_p75", p.p75),
+        ("length_p90", p.p90),
+        ("length_p95", p.p95),
+    ] {
+        headers.push(name.to_string());
+        values.push(value.to_string());
+    }
+
+    // Alignment lengths (if read stats are per read)
+    if let Some(read_level_stats) = &metrics.read_level_stats {
+        let summary = &read_level_stats.alignment_length;
+        headers.push("num_split_reads".to_string());
+        values.push(read_level_stats.num_split_reads.to_string());
+        headers.push("alignment_length_n50".to_string());
+        values.push(summary.n50.to_string());
+        headers.push("alignment_length_median".to_string());
+        values.push(format!("{:.2}", summary.median));
+        headers.push("alignment_length_mean".to_string());
+        values.push(format!("{:.2}", summary.mean));
+    }
+
+    // Query length, aligned length and reference span (if available)
+    if let Some(length_stats) = &metrics.length_stats {
+        let summaries = [
+            ("query_length", &length_stats.query_length),
+            ("aligned_length", &length_stats.aligned_length),
+            ("reference_span", &length_stats.reference_span),
+        ];
+        for (name, summary) in summaries {
+            headers.push(format!("{}_n50", name));
+            values.push(summary.n50.to_string());
+            headers.push(format!("{}_median", name));
+            values.push(format!("{:.2}", summary.median));
+            headers.push(format!("{}_mean", name));
+            values.push(format!("{:.2}", summary.mean));
+        }
+        headers.push("fraction_aligned".to_string());
+        values.push(format!("{:.3}", length_stats.fraction_aligned));
+    }
 
     // Identity stats (if available)
     if let Some(identity_stats) = &metrics.identity_stats {
         if identity_stats.is_estimated {
-            headers.push("median_est_identity");
+            headers.push("median_est_identity".to_string());
             values.push(format!("{:.2}", identity_stats.median_identity));
-            headers.push("mean_est_identity");
+            headers.push("mean_est_identity".to_string());
             values.push(format!("{:.2}", identity_stats.mean_identity));
-            headers.push("modal_est_identity");
+            headers.push("modal_est_identity".to_string());
             values.push(format!("{:.1}", identity_stats.modal_identity));
         } else {
-            headers.push("median_identity");
+            headers.push("median_identity".to_string());
             values.push(format!("{:.2}", identity_stats.median_identity));
-            headers.push("mean_identity");
+            headers.push("mean_identity".to_string());
             values.push(format!("{:.2}", identity_stats.mean_identity));
-            headers.push("modal_identity");
+            headers.push("modal_identity".to_string());
             values.push(format!("{:.1}", identity_stats.modal_identity));
         }
     }
 
     // Phase stats (if available)
     if let Some(phase_stats) = &metrics.phase_stats {
-        headers.push("fraction_phased");
+        headers.push("fraction_phased".to_string());
         values.push(format!("{:.2}", phase_stats.fraction_phased));
-        headers.push("num_phaseblocks");
+        headers.push("num_phaseblocks".to_string());
         values.push(phase_stats.num_phaseblocks.to_string());
-        headers.push("total_bases_phased_gb");
+        headers.push("total_bases_phased_gb".to_string());
         values.push(format!("{:.2}", phase_stats.total_bases_phased_gb));
-        headers.push("median_phaseblock_length");
+        headers.push("median_phaseblock_length".to_string());
         values.push(format!("{:.2}", phase_stats.median_phaseblock_length));
-        headers.push("n50_phaseblock_length");
+        headers.push("n50_phaseblock_length".to_string());
         values.push(phase_stats.n50_phaseblock_length.to_string());
+        headers.push("fraction_genome_phased".to_string());
+        values.push(format!("{:.3}", phase_stats.fraction_genome_phased));
+        if let Some(region_stats) = &phase_stats.region_stats {
+            headers.push("num_regions".to_string());
+            values.push(region_stats.num_regions.to_string());
+            headers.push("fraction_region_bases_phased".to_string());
+            values.push(format!("{:.3}", region_stats.fraction_bases_phased));
+            headers.push("num_regions_fully_phased".to_string());
+            values.push(region_stats.num_fully_phased.to_string());
+            headers.push("fraction_regions_fully_phased".to_string());
+            values.push(format!("{:.3}", region_stats.fraction_fully_phased));
+        }
+        headers.push("hp1_reads".to_string());
+        values.push(phase_stats.haplotypes.hp1_reads.to_string());
+        headers.push("hp2_reads".to_string());
+        values.push(phase_stats.haplotypes.hp2_reads.to_string());
+        headers.push("unassigned_reads".to_string());
+        values.push(phase_stats.haplotypes.unassigned_reads.to_string());
+        headers.push("hp1_bases".to_string());
+        values.push(phase_stats.haplotypes.hp1_bases.to_string());
+        headers.push("hp2_bases".to_string());
+        values.push(phase_stats.haplotypes.hp2_bases.to_string());
+        headers.push("unassigned_bases".to_string());
+        values.push(phase_stats.haplotypes.unassigned_bases.to_string());
+        headers.push("hp1_fraction".to_string());
+        values.push(format!("{:.3}", phase_stats.haplotypes.hp1_fraction));
     }
 
     // Splice stats (if available)
     if let Some(splice_stats) = &metrics.splice_stats {
-        headers.push("median_exons");
+        headers.push("median_exons".to_string());
         values.push(splice_stats.median_exons.to_string());
-        headers.push("mean_exons");
+        headers.push("mean_exons".to_string());
         values.push(format!("{:.2}", splice_stats.mean_exons));
-        headers.push("fraction_unspliced");
+        headers.push("fraction_unspliced".to_string());
         values.push(format!("{:.2}", splice_stats.fraction_unspliced));
+        // intron and exon columns are always present in spliced mode, NA if no introns were found
+        push_length_distribution(
+            &mut headers,
+            &mut values,
+            "intron",
+            splice_stats.intron_lengths.as_ref(),
+        );
+        push_length_distribution(
+            &mut headers,
+            &mut values,
+            "exon_block",
+            splice_stats.exon_lengths.as_ref(),
+        );
+    }
+
+    if let Some(incomplete) = &metrics.incomplete {
+        headers.push("incomplete".to_string());
+        values.push(incomplete.reason.clone());
+        headers.push("records_processed".to_string());
+        values.push(incomplete.records_processed.to_string());
+        headers.push("last_position".to_string());
+        values.push(incomplete.last_position.clone().unwrap_or("NA".to_string()));
     }
 
-    // Print headers and values as TSV
-    println!("{}", headers.join("\t"));
-    println!("{}", values.join("\t"));
+    if let Some(warnings) = &metrics.warnings {
+        headers.push("skipped_records".to_string());
+        values.push(warnings.skipped_records.to_string());
+    }
+
+    // QC status, overall and per rule (without spaces, e.g. qc:read_stats.n50>=15000)
+    if let Some(qc) = &metrics.qc {
+        headers.push("qc_status".to_string());
+        values.push(qc.status.to_string());
+        for rule in &qc.rules {
+            headers.push(format!("qc:{}", rule.rule.replace(' ', "")));
+            values.push(rule.status.to_string());
+        }
+    }
+
+    format!("{}\n{}\n", headers.join("\t"), values.join("\t"))
+}
+
+fn push_length_distribution(
+    headers: &mut Vec<String>,
+    values: &mut Vec<String>,
+    name: &str,
+    dist: Option<&metrics::LengthDistribution>,
+) {
+    let stats = ["median", "mean", "p5", "p25", "p75", "p95", "max"];
+    headers.push(format!("num_{}s", name));
+    headers.extend(stats.iter().map(|stat| format!("{}_{}_length", stat, name)));
+    match dist {
+        Some(d) => values.extend([
+            d.count.to_string(),
+            format!("{:.2}", d.median),
+            format!("{:.2}", d.mean),
+            format!("{:.0}", d.p5),
+            format!("{:.0}", d.p25),
+            format!("{:.0}", d.p75),
+            format!("{:.0}", d.p95),
+            d.max.to_string(),
+        ]),
+        None => values.extend(vec!["NA".to_string(); stats.len() + 1]),
+    }
 }
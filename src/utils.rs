@@ -1,13 +1,16 @@
+use crate::contigs::ContigFilter;
 use std::path::PathBuf;
 
+/// Sums the lengths of the contigs in the header, optionally only those selected with --contigs
 pub fn get_genome_size(
     header: &rust_htslib::bam::Header,
+    contigs: Option<&ContigFilter>,
 ) -> Result<u64, rust_htslib::errors::Error> {
     let mut genome_size = 0;
     // print header records to the terminal, akin to samtool
     for (key, records) in header.to_hashmap() {
         for record in records {
-            if key == "SQ" {
+            if key == "SQ" && contigs.is_none_or(|contigs| contigs.is_match(&record["SN"])) {
                 genome_size += record["LN"]
                     .parse::<u64>()
                     .expect("Failed parsing length of chromosomes");
@@ -17,6 +20,16 @@ pub fn get_genome_size(
     Ok(genome_size)
 }
 
+/// Checks the sort order in the @HD line of the header
+pub fn is_coordinate_sorted(header: &rust_htslib::bam::Header) -> bool {
+    header
+        .to_hashmap()
+        .get("HD")
+        .and_then(|records| records.first())
+        .and_then(|record| record.get("SO"))
+        .is_some_and(|sort_order| sort_order == "coordinate")
+}
+
 pub fn accuracy_to_phred(identity: f64) -> usize {
     // convert identity to phred scale
     // but return as usize (as that will be used for the histogram)
@@ -24,12 +37,38 @@ pub fn accuracy_to_phred(identity: f64) -> usize {
     (-10.0 * (1.0 - identity / 100.0).log10()) as usize
 }
 
+/// The thresholds for the long read yield
+/// yield_gb_long is always calculated for reads >25kb, for backwards compatibility
+pub fn yield_thresholds(long_thresholds: &[u64]) -> Vec<u64> {
+    let mut thresholds = vec![25_000];
+    thresholds.extend(long_thresholds);
+    thresholds
+}
+
 // Helper function to calculate data yield
-pub fn calculate_data_yield(lengths: &[u128]) -> (u128, u128) {
-    lengths.iter().fold((0u128, 0u128), |(total, long), &len| {
-        let long_increment = if len > 25000 { len } else { 0 };
-        (total + len, long + long_increment)
-    })
+// returns the total yield and, for every threshold, the yield and number of reads longer than that threshold
+pub fn calculate_data_yield(lengths: &[u128], thresholds: &[u64]) -> (u128, Vec<(u128, usize)>) {
+    let mut long = vec![(0u128, 0usize); thresholds.len()];
+    let mut total = 0u128;
+    for &len in lengths {
+        total += len;
+        for (threshold, (long_yield, long_count)) in thresholds.iter().zip(long.iter_mut()) {
+            if len > *threshold as u128 {
+                *long_yield += len;
+                *long_count += 1;
+            }
+        }
+    }
+    (total, long)
+}
+
+/// Formats a length threshold for display, e.g. 25000 as 25kb
+pub fn format_threshold(threshold: u64) -> String {
+    if threshold >= 1000 && threshold.is_multiple_of(1000) {
+        format!("{}kb", threshold / 1000)
+    } else {
+        format!("{}bp", threshold)
+    }
 }
 
 pub fn is_file(pathname: &str) -> Result<(), String> {
@@ -47,3 +86,29 @@ pub fn is_file(pathname: &str) -> Result<(), String> {
         Err(format!("Input file {} is invalid", path.display()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_data_yield_thresholds() {
+        let lengths = vec![120_000u128, 60_000, 30_000, 2_000, 500];
+        let (total, long) = calculate_data_yield(&lengths, &[1000, 25_000, 50_000, 100_000]);
+        assert_eq!(total, 212_500);
+        assert_eq!(long[0], (212_000, 4));
+        assert_eq!(long[1], (210_000, 3));
+        assert_eq!(long[2], (180_000, 2));
+        assert_eq!(long[3], (120_000, 1));
+        let (total, long) = calculate_data_yield(&[], &[25_000]);
+        assert_eq!(total, 0);
+        assert_eq!(long, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_format_threshold() {
+        assert_eq!(format_threshold(25_000), "25kb");
+        assert_eq!(format_threshold(1000), "1kb");
+        assert_eq!(format_threshold(1500), "1500bp");
+    }
+}
@@ -0,0 +1,236 @@
+use crate::extract_from_bam;
+use crate::options::MetricArgs;
+use crate::state::State;
+use crate::{QcOptions, html_report, metrics, metrics_processor, plots, tsv_output};
+use chrono::offset::Local;
+use clap::{ArgGroup, Args};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+#[clap(group(ArgGroup::new("report").required(true).multiple(true).args(["json", "tsv", "html"])))]
+pub struct WatchArgs {
+    /// Directory with bam or cram files (e.g. written by dorado), including its subdirectories
+    #[clap(value_parser)]
+    dir: String,
+
+    /// Seconds between scans of the directory for new files
+    #[clap(long, value_parser, default_value_t = 60)]
+    interval: u64,
+
+    /// Stop after this many seconds without new files [default: run until interrupted]
+    #[clap(long, value_parser, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Rewrite the metrics of all files as json to this file after every scan with new files
+    #[clap(long, value_parser, value_name = "FILE")]
+    json: Option<String>,
+
+    /// Rewrite the metrics of all files as tsv to this file after every scan with new files
+    #[clap(long, value_parser, value_name = "FILE")]
+    tsv: Option<String>,
+
+    /// Rewrite an HTML report of all files to this file after every scan with new files
+    #[clap(long, value_parser, value_name = "FILE")]
+    html: Option<String>,
+
+    /// Write the state of all files to this file, to continue from it when watching again
+    #[clap(long, value_parser, value_name = "FILE")]
+    state: Option<String>,
+
+    #[clap(flatten)]
+    metrics: MetricArgs,
+}
+
+impl WatchArgs {
+    /// The options of the inputs, with sketches to combine them in a state and with the
+    /// histograms for the HTML report
+    fn options(&self) -> QcOptions {
+        QcOptions::from(&self.metrics).sketch(true).histograms(true)
+    }
+}
+
+/// Processes the bam and cram files in a directory as they appear, and rewrites the reports
+/// of all files processed so far, e.g. to monitor a sequencing run
+pub fn watch(watch_args: &WatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Path::new(&watch_args.dir);
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", watch_args.dir).into());
+    }
+    // when watching again, the files in the state are not processed again
+    let mut state = match &watch_args.state {
+        Some(path) if Path::new(path).exists() => Some(State::read(path)?),
+        _ => None,
+    };
+    // files are identified by their path relative to the directory, as in the inputs of the state
+    let mut processed: HashSet<String> = state
+        .iter()
+        .flat_map(|state| state.inputs.iter().cloned())
+        .collect();
+    // files that failed are only tried again if their size changes
+    let mut failed: HashMap<PathBuf, u64> = HashMap::new();
+    let mut last_new_file = Instant::now();
+    // the reports of a state to continue from are written even without new files
+    let mut first_scan = true;
+    loop {
+        let mut new_files = 0;
+        for path in find_inputs(dir)? {
+            let name = relative_path(dir, &path);
+            let size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            if processed.contains(&name) || failed.get(&path) == Some(&size) {
+                continue;
+            }
+            let file_state = match process(&path, name.clone(), watch_args) {
+                Ok(Some(file_state)) => file_state,
+                // files that are still being written are tried again at the next scan
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Skipping {}: {err}", path.display());
+                    failed.insert(path, size);
+                    continue;
+                }
+            };
+            match &mut state {
+                Some(state) => {
+                    if let Err(err) = state.merge(file_state) {
+                        warn!("Skipping {}: {err}", path.display());
+                        failed.insert(path, size);
+                        continue;
+                    }
+                }
+                None => state = Some(file_state),
+            }
+            info!("Added {}", path.display());
+            processed.insert(name);
+            new_files += 1;
+        }
+        if new_files > 0 {
+            last_new_file = Instant::now();
+        }
+        if (new_files > 0 || first_scan)
+            && let Some(state) = &state
+        {
+            write_reports(state, watch_args)?;
+            eprintln!(
+                "Added {new_files} files, the reports include {} files",
+                state.inputs.len()
+            );
+        }
+        first_scan = false;
+        if let Some(timeout) = watch_args.idle_timeout
+            && last_new_file.elapsed() >= Duration::from_secs(timeout)
+        {
+            if state.is_none() {
+                warn!("No complete bam or cram files found in {}", watch_args.dir);
+            }
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_secs(watch_args.interval));
+    }
+}
+
+/// The bam and cram files in the directory and its subdirectories, sorted by path
+fn find_inputs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            inputs.extend(find_inputs(&path)?);
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension == "bam" || extension == "cram")
+        {
+            inputs.push(path);
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+/// The path of a file in the directory relative to it, so that files with the same name in
+/// different subdirectories (e.g. pass and fail) are told apart
+fn relative_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The state of a file, or None if the file is still being written (its EOF marker is absent)
+fn process(
+    path: &Path,
+    name: String,
+    watch_args: &WatchArgs,
+) -> Result<Option<State>, Box<dyn std::error::Error>> {
+    let input = path.to_string_lossy();
+    let options = watch_args.options();
+    let mut bam = extract_from_bam::open(&input, &options)?;
+    if extract_from_bam::has_eof_marker(&bam) == Some(false) {
+        return Ok(None);
+    }
+    let (data, header) = extract_from_bam::extract_reader(&mut bam, &input, &options)?;
+    if let Some(incomplete) = &data.incomplete {
+        return Err(incomplete.reason.clone().into());
+    }
+    Ok(Some(State::from_data(&data, name, &options, &header)?))
+}
+
+/// Rewrites the reports with the metrics of the state, each by renaming a new file so that
+/// the reports are never read half-written
+fn write_reports(state: &State, watch_args: &WatchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let file_info = metrics::FileInfo {
+        name: file_name(&std::fs::canonicalize(&watch_args.dir)?),
+        path: watch_args.dir.clone(),
+        creation_time: "NA".to_string(),
+    };
+    let options = watch_args.options();
+    let (data, header) = state.clone().into_data(&options)?;
+    let (mut metrics, _) =
+        metrics_processor::calculate_metrics(&data, &options, &header, file_info)?;
+    metrics.sections.insert(
+        "watch".to_string(),
+        serde_json::json!({
+            "num_files": state.inputs.len(),
+            "last_update": Local::now().format("%d/%m/%Y %T").to_string(),
+        }),
+    );
+    if let Some(path) = &watch_args.json {
+        replace(path, |tmp| {
+            std::fs::write(tmp, serde_json::to_string_pretty(&metrics)?)?;
+            Ok(())
+        })?;
+    }
+    if let Some(path) = &watch_args.tsv {
+        replace(path, |tmp| {
+            std::fs::write(tmp, tsv_output::tsv_output(&metrics))?;
+            Ok(())
+        })?;
+    }
+    if let Some(path) = &watch_args.html {
+        let charts = plots::charts(&data, &metrics, None, false, false);
+        replace(path, |tmp| html_report::write_html(tmp, &metrics, &charts))?;
+    }
+    if let Some(path) = &watch_args.state {
+        replace(path, |tmp| state.write(tmp))?;
+    }
+    Ok(())
+}
+
+/// Writes a file next to the path, and renames it to the path
+fn replace(
+    path: &str,
+    write: impl FnOnce(&str) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = format!("{path}.tmp");
+    write(&tmp)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -93,3 +93,243 @@ fn json_mapped_identity_not_estimated() {
         .unwrap_or(false);
     assert!(!is_estimated);
 }
+
+#[test]
+fn json_reports_requested_nx() {
+    let bam_path = test_bam_path();
+    let args = vec![
+        "--format".to_string(),
+        "json".to_string(),
+        "--nx".to_string(),
+        "25,50".to_string(),
+        bam_path,
+    ];
+    let json_value = run_cramino_json(args);
+    let nx = json_value["read_stats"]["nx"]
+        .as_array()
+        .expect("nx is an array");
+    assert_eq!(nx.len(), 2);
+    assert_eq!(nx[0]["x"], 25);
+    assert_eq!(nx[1]["length"], json_value["read_stats"]["n50"]);
+    let lx = json_value["read_stats"]["lx"]
+        .as_array()
+        .expect("lx is an array");
+    assert_eq!(lx[0]["x"], 25);
+    assert!(lx[0]["num_reads"].as_u64().unwrap() <= lx[1]["num_reads"].as_u64().unwrap());
+    assert!(json_value["read_stats"]["aun"].is_number());
+    assert!(json_value["read_stats"]["length_percentiles"]["p95"].is_number());
+}
+
+/// Writes the reads of the test bam to two shards of consecutive regions, split between two positions
+fn write_shards(dir: &std::path::Path) -> [String; 2] {
+    use rust_htslib::bam::{self, Read};
+    let mut reader = bam::Reader::from_path(test_bam_path()).unwrap();
+    let header = bam::Header::from_template(reader.header());
+    let records = reader
+        .records()
+        .map(|record| record.unwrap())
+        .collect::<Vec<bam::Record>>();
+    let split = (records.len() / 2..records.len())
+        .find(|&index| records[index].pos() != records[index - 1].pos())
+        .unwrap();
+    let (first, second) = records.split_at(split);
+    [("shard1.bam", first), ("shard2.bam", second)].map(|(name, records)| {
+        let path = dir.join(name);
+        let mut writer = bam::Writer::from_path(&path, &header, bam::Format::Bam).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    })
+}
+
+#[test]
+fn json_merge_of_state_files() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("target");
+    dir.push("merge-test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = ["--format", "json", "--sketch", "--karyotype", "--phased"].map(String::from);
+    let mut states = Vec::new();
+    for shard in write_shards(&dir) {
+        let state = format!("{shard}.state");
+        let mut args = options.to_vec();
+        args.extend([format!("--state={state}"), shard]);
+        run_cramino_json(args);
+        states.push(state);
+    }
+    let mut args = vec![
+        "merge".to_string(),
+        "--format".to_string(),
+        "json".to_string(),
+    ];
+    args.extend(states);
+    let mut merged = run_cramino_json(args);
+    let mut args = options.to_vec();
+    args.push(test_bam_path());
+    let mut single = run_cramino_json(args);
+
+    assert_eq!(merged["alignment_stats"], single["alignment_stats"]);
+    assert_eq!(merged["read_stats"], single["read_stats"]);
+    assert_eq!(merged["identity_stats"], single["identity_stats"]);
+    assert!(single["phase_stats"]["num_phaseblocks"].as_u64().unwrap() > 0);
+    assert_eq!(merged["phase_stats"], single["phase_stats"]);
+    // the contigs of the karyotype are not in a fixed order
+    for json in [&mut merged, &mut single] {
+        json["karyotype_stats"]
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|contig| contig["chromosome"].to_string());
+    }
+    assert!(!single["karyotype_stats"].as_array().unwrap().is_empty());
+    assert_eq!(merged["karyotype_stats"], single["karyotype_stats"]);
+}
+
+#[test]
+fn json_compare_reports() {
+    let bam_path = test_bam_path();
+    let mut report_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    report_path.push("target");
+    report_path.push("compare-report.json");
+    let report_path = report_path.to_string_lossy().into_owned();
+    let output = Command::new(env!("CARGO_BIN_EXE_cramino"))
+        .args(["--format", "json", "--min-read-len", "2000", &bam_path])
+        .output()
+        .expect("Failed to run cramino");
+    std::fs::write(&report_path, output.stdout).unwrap();
+    // the bam file is processed without a minimal read length
+    let args = vec![
+        "compare".to_string(),
+        "--format".to_string(),
+        "json".to_string(),
+        bam_path,
+        report_path,
+    ];
+    let comparison = run_cramino_json(args);
+    let metrics = comparison["metrics"].as_array().unwrap();
+    let num_reads = metrics
+        .iter()
+        .find(|m| m["metric"] == "alignment_stats.num_reads")
+        .unwrap();
+    assert!(num_reads["differences"][0]["absolute"].as_f64().unwrap() < 0.0);
+    assert_eq!(num_reads["exceeds_tolerance"], true);
+    assert!(comparison["num_changed"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn json_qc_thresholds_exit_code() {
+    let bam_path = test_bam_path();
+    let mut thresholds_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    thresholds_path.push("target");
+    thresholds_path.push("qc-thresholds.toml");
+    std::fs::write(
+        &thresholds_path,
+        "fail = [\"alignment_stats.num_reads > 0\"]\nwarn = [\"n50 > 1e12\"]\n",
+    )
+    .unwrap();
+    let thresholds_arg = format!("--qc-thresholds={}", thresholds_path.display());
+    let args = vec![
+        "--format".to_string(),
+        "json".to_string(),
+        thresholds_arg.clone(),
+        bam_path.clone(),
+    ];
+    // a rule at the warn level does not change the exit code
+    let json_value = run_cramino_json(args);
+    assert_eq!(json_value["qc"]["status"], "WARN");
+    assert_eq!(json_value["qc"]["rules"][0]["status"], "PASS");
+    assert_eq!(json_value["qc"]["rules"][1]["metric"], "read_stats.n50");
+
+    std::fs::write(&thresholds_path, "fail = [\"read_stats.n50 > 1e12\"]\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cramino"))
+        .args(["--format", "json", &thresholds_arg, &bam_path])
+        .output()
+        .expect("Failed to run cramino");
+    assert_eq!(output.status.code(), Some(3));
+    let json_value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json_value["qc"]["status"], "FAIL");
+}
+
+#[test]
+fn json_truncated_file_partial_report() {
+    let bam = std::fs::read(test_bam_path()).unwrap();
+    let mut truncated_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    truncated_path.push("target");
+    truncated_path.push("truncated.bam");
+    std::fs::write(&truncated_path, &bam[..bam.len() / 2]).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cramino"))
+        .args(["--format", "json", truncated_path.to_str().unwrap()])
+        .output()
+        .expect("Failed to run cramino");
+    // the report of the records before the truncation is written, flagged as incomplete
+    assert_eq!(output.status.code(), Some(6));
+    let json_value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let records_processed = json_value["incomplete"]["records_processed"]
+        .as_u64()
+        .unwrap();
+    assert!(records_processed > 0);
+    assert!(json_value["incomplete"]["last_position"].is_string());
+    assert!(
+        json_value["alignment_stats"]["num_alignments"]
+            .as_u64()
+            .unwrap()
+            <= records_processed
+    );
+
+    let json_value = run_cramino_json(vec![
+        "--format".to_string(),
+        "json".to_string(),
+        test_bam_path(),
+    ]);
+    assert!(json_value.get("incomplete").is_none());
+}
+
+#[test]
+fn json_watch_directory() {
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("target");
+    dir.push("watch-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("pass")).unwrap();
+    let bam = std::fs::read(test_bam_path()).unwrap();
+    std::fs::write(dir.join("chunk_0.bam"), &bam).unwrap();
+    // files with the same name in different subdirectories are different files
+    std::fs::write(dir.join("pass").join("chunk_0.bam"), &bam).unwrap();
+    // a file that is still being written has no EOF marker, and is not included yet
+    std::fs::write(dir.join("chunk_2.bam"), &bam[..bam.len() / 2]).unwrap();
+    let json_path = dir.join("report.json");
+    let state_path = dir.join("run.state");
+    let watch = || {
+        let output = Command::new(env!("CARGO_BIN_EXE_cramino"))
+            .args([
+                "watch",
+                dir.to_str().unwrap(),
+                "--idle-timeout=0",
+                &format!("--json={}", json_path.display()),
+                &format!("--state={}", state_path.display()),
+            ])
+            .output()
+            .expect("Failed to run cramino");
+        assert!(
+            output.status.success(),
+            "cramino failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        serde_json::from_slice::<serde_json::Value>(&std::fs::read(&json_path).unwrap()).unwrap()
+    };
+    let json_value = watch();
+    assert_eq!(json_value["watch"]["num_files"], 2);
+    // when watching again, the files in the state are not processed again
+    let json_value = watch();
+    assert_eq!(json_value["watch"]["num_files"], 2);
+
+    let single = run_cramino_json(vec![
+        "--format".to_string(),
+        "json".to_string(),
+        test_bam_path(),
+    ]);
+    assert_eq!(
+        json_value["alignment_stats"]["num_reads"].as_u64().unwrap(),
+        2 * single["alignment_stats"]["num_reads"].as_u64().unwrap()
+    );
+}
@@ -0,0 +1,203 @@
+use cramino::QcOptions;
+use rust_htslib::bam::{self, Read};
+use std::path::PathBuf;
+
+fn test_bam_path() -> String {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("test-data");
+    path.push("small-test-phased.bam");
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn library_metrics_from_path_reader_and_records() {
+    let options = QcOptions::new().threads(2).karyotype(true);
+    let from_path = cramino::metrics_from_path(&test_bam_path(), &options).unwrap();
+    assert_eq!(from_path.file_info.name, "small-test-phased.bam");
+    assert!(from_path.alignment_stats.num_reads > 0);
+    assert!(from_path.karyotype_stats.is_some());
+
+    let mut reader = bam::Reader::from_path(test_bam_path()).unwrap();
+    let from_reader = cramino::metrics_from_reader(&mut reader, &options).unwrap();
+    assert_eq!(from_reader.file_info.name, "NA");
+    assert_eq!(from_reader.read_stats.n50, from_path.read_stats.n50);
+
+    let mut reader = bam::Reader::from_path(test_bam_path()).unwrap();
+    let header = reader.header().clone();
+    let records = reader.records().map(|record| record.unwrap());
+    let from_records = cramino::metrics_from_records(records, &header, &options).unwrap();
+    assert_eq!(
+        from_records.alignment_stats.num_reads,
+        from_path.alignment_stats.num_reads
+    );
+    assert_eq!(
+        from_records.identity_stats.unwrap().median_identity,
+        from_path.identity_stats.unwrap().median_identity
+    );
+}
+
+#[test]
+fn library_min_read_len() {
+    let all = cramino::metrics_from_path(&test_bam_path(), &QcOptions::new()).unwrap();
+    let options = QcOptions::new().min_read_len(usize::MAX);
+    let none = cramino::metrics_from_path(&test_bam_path(), &options).unwrap();
+    assert!(all.alignment_stats.num_reads > 0);
+    assert_eq!(none.alignment_stats.num_reads, 0);
+}
+
+/// Counts the reverse strand reads, as a section of the report
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct StrandCollector {
+    reads: u64,
+    reverse: u64,
+}
+
+impl cramino::MetricCollector for StrandCollector {
+    fn observe(&mut self, record: &bam::Record, _read: &cramino::ReadInfo) {
+        if record.is_supplementary() {
+            return;
+        }
+        self.reads += 1;
+        self.reverse += record.is_reverse() as u64;
+    }
+
+    fn merge(&mut self, other: Box<dyn cramino::MetricCollector>) -> Result<(), String> {
+        let other = cramino::collectors::downcast::<Self>(other)?;
+        self.reads += other.reads;
+        self.reverse += other.reverse;
+        Ok(())
+    }
+
+    fn finalize(&self) -> Option<(String, serde_json::Value)> {
+        let section = serde_json::json!({"reads": self.reads, "reverse_reads": self.reverse});
+        Some(("strand_stats".to_string(), section))
+    }
+}
+
+#[test]
+fn library_custom_collector() {
+    let options = QcOptions::new().collector(StrandCollector::default);
+    let metrics = cramino::metrics_from_path(&test_bam_path(), &options).unwrap();
+    let json = serde_json::to_value(&metrics).unwrap();
+    assert_eq!(
+        json["strand_stats"]["reads"],
+        metrics.alignment_stats.num_reads as u64
+    );
+    assert!(json["strand_stats"]["reverse_reads"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn library_custom_collector_merged_states() {
+    let options = QcOptions::new()
+        .sketch(true)
+        .collector(StrandCollector::default);
+    let whole = cramino::metrics_from_path(&test_bam_path(), &options).unwrap();
+
+    let mut reader = bam::Reader::from_path(test_bam_path()).unwrap();
+    let header = reader.header().clone();
+    let records: Vec<bam::Record> = reader.records().map(|record| record.unwrap()).collect();
+    let (first, second) = records.split_at(records.len() / 2);
+    let mut states = [first, second].into_iter().map(|shard| {
+        let data =
+            cramino::extract_from_bam::extract_records(shard.iter(), &header, "NA", &options)
+                .unwrap();
+        let header = bam::Header::from_template(&header);
+        cramino::state::State::from_data(&data, "NA".to_string(), &options, &header).unwrap()
+    });
+    let mut merged = states.next().unwrap();
+    merged.merge(states.next().unwrap()).unwrap();
+    assert_eq!(merged.collectors[0].len(), 2);
+
+    let (data, header) = merged.into_data(&options).unwrap();
+    let file_info = cramino::metrics_processor::file_info(&test_bam_path());
+    let (metrics, _) =
+        cramino::metrics_processor::calculate_metrics(&data, &options, &header, file_info).unwrap();
+    let json = serde_json::to_value(&metrics).unwrap();
+    let whole = serde_json::to_value(&whole).unwrap();
+    assert_eq!(json["strand_stats"], whole["strand_stats"]);
+    assert!(json["strand_stats"]["reverse_reads"].as_u64().unwrap() > 0);
+
+    let err = cramino::state::State::from_data(
+        &data,
+        "NA".to_string(),
+        &QcOptions::new().sketch(true),
+        &header,
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("collectors"));
+}
+
+/// Writes a bam file with two valid reads and one with a negative NM tag
+fn write_bam_with_invalid_record(path: &std::path::Path) {
+    let mut header = bam::Header::new();
+    let mut contig = bam::header::HeaderRecord::new(b"SQ");
+    contig.push_tag(b"SN", "chr1").push_tag(b"LN", 100_000);
+    header.push_record(&contig);
+    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam).unwrap();
+    for (name, nm) in [("valid1", 1), ("invalid", -1), ("valid2", 2)] {
+        let mut record = bam::Record::new();
+        let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(1000)]);
+        record.set(name.as_bytes(), Some(&cigar), &[b'A'; 1000], &[30; 1000]);
+        record.set_tid(0);
+        record.set_pos(100);
+        record.unset_unmapped();
+        record.push_aux(b"NM", bam::record::Aux::I32(nm)).unwrap();
+        writer.write(&record).unwrap();
+    }
+}
+
+#[test]
+fn library_strict_and_lenient() {
+    let path = std::env::temp_dir().join("cramino-invalid-record.bam");
+    write_bam_with_invalid_record(&path);
+    let input = path.to_str().unwrap();
+
+    let err = cramino::metrics_from_path(input, &QcOptions::new()).unwrap_err();
+    match err.downcast_ref::<cramino::CraminoError>() {
+        Some(cramino::CraminoError::InvalidRecord {
+            read_name, offset, ..
+        }) => {
+            assert_eq!(read_name, "invalid");
+            assert!(offset.is_some());
+        }
+        _ => panic!("Expected an invalid record error, got {err}"),
+    }
+    assert_eq!(cramino::error::exit_code(err.as_ref()), 5);
+
+    let metrics = cramino::metrics_from_path(input, &QcOptions::new().lenient(true)).unwrap();
+    assert_eq!(metrics.alignment_stats.num_reads, 2);
+    let warnings = metrics.warnings.unwrap();
+    assert_eq!(warnings.skipped_records, 1);
+    assert!(warnings.errors[0].contains("negative NM tag"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn library_haplotypes_without_phasesets() {
+    let mut header = bam::Header::new();
+    let mut contig = bam::header::HeaderRecord::new(b"SQ");
+    contig.push_tag(b"SN", "chr1").push_tag(b"LN", 100_000);
+    header.push_record(&contig);
+    let header = bam::HeaderView::from_header(&header);
+    // haplotagged reads (HP tag) without phaseset (PS tag)
+    let records = [1u8, 1, 2].map(|haplotype| {
+        let mut record = bam::Record::new();
+        let cigar = bam::record::CigarString(vec![bam::record::Cigar::Match(1000)]);
+        record.set(b"read", Some(&cigar), &[b'A'; 1000], &[30; 1000]);
+        record.set_tid(0);
+        record.set_pos(100);
+        record.unset_unmapped();
+        record.push_aux(b"NM", bam::record::Aux::I32(10)).unwrap();
+        record
+            .push_aux(b"HP", bam::record::Aux::U8(haplotype))
+            .unwrap();
+        record
+    });
+    let metrics =
+        cramino::metrics_from_records(&records, &header, &QcOptions::new().phased(true)).unwrap();
+    let phase_stats = metrics.phase_stats.unwrap();
+    assert_eq!(phase_stats.num_phaseblocks, 0);
+    assert_eq!(phase_stats.haplotypes.hp1_reads, 2);
+    assert_eq!(phase_stats.haplotypes.hp2_reads, 1);
+    assert_eq!(phase_stats.chromosomes[0].haplotypes.hp2_reads, 1);
+}